@@ -0,0 +1,6 @@
+/* the bootloader takes sectors 0 to 2, the rollback counter takes sector 3 */
+MEMORY
+{
+  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 48K
+  RAM : ORIGIN = 0x20000000, LENGTH = 112K
+}
@@ -0,0 +1,34 @@
+use stm32f4xx_hal::flash::{LockedFlash, FlashExt};
+
+/// sector 3 is never erased, every cleared bit counts one
+const COUNTER_SECTOR_BASE: usize = 0x0800_c000;
+const COUNTER_LEN: usize = 16 * 1024;
+const FLASH_BASE: usize = 0x0800_0000;
+
+fn bytes() -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(COUNTER_SECTOR_BASE as *const u8, COUNTER_LEN) }
+}
+
+/// the lowest version allowed to boot
+pub fn read() -> u32 {
+    bytes().iter().map(|byte| byte.count_zeros()).sum()
+}
+
+/// clear bits until the counter reaches `version`.
+/// bits are only ever cleared, so losing power leaves a counter
+/// between the old and the new value
+pub fn raise(flash: &mut LockedFlash, version: u32) {
+    let target = (version as usize).min(COUNTER_LEN * 8);
+    let mut unlocked = flash.unlocked();
+
+    for (idx, byte) in bytes().iter().enumerate().take(target.div_ceil(8)) {
+        let cleared = (target - idx * 8).min(8);
+        let value = 0xffu8.checked_shl(cleared as u32).unwrap_or(0);
+        if value & byte != *byte {
+            let _ = unlocked.program(
+                COUNTER_SECTOR_BASE + idx - FLASH_BASE, 
+                [value & byte].iter()
+            );
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use core::slice;
+
+use stm32f4xx_hal::flash::{LockedFlash, FlashExt};
+
+use k256::{
+    ecdsa::{Signature, VerifyingKey, signature::DigestVerifier},
+    sha2::{Sha256, Digest}
+};
+
+pub const IMAGE_MAGIC: [u8; 4] = *b"EWFW";
+/// the header is padded to keep the vector table of the application aligned
+pub const HEADER_LEN: usize = 0x200;
+/// magic, version, slot, padding, size, then the signature
+pub const HEADER_FIELDS_LEN: usize = 4 + 4 + 1 + 3 + 4 + 64;
+/// the signature covers every field before it
+const SIGNED_LEN: usize = HEADER_FIELDS_LEN - 64;
+
+/// flash address of the header in each slot
+pub const SLOT_BASES: [usize; 2] = [0x0801_0000, 0x0804_0000];
+/// slot A takes sectors 4 and 5, slot B the same length from sector 6,
+/// the rest of sector 7 is left unused
+pub const SLOT_LEN: usize = 192 * 1024;
+pub const MAX_IMAGE_SIZE: usize = SLOT_LEN - HEADER_LEN;
+const FLASH_BASE: usize = 0x0800_0000;
+
+/// programmed in the padding of the header before the first boot
+/// of an image newer than the rollback counter
+const TRIED_OFFSET: usize = HEADER_FIELDS_LEN;
+const TRIED_MAGIC: u32 = 0x7e57_b007;
+/// programmed by the firmware once it has booted, see `firmware::confirm_boot`
+const CONFIRMED_OFFSET: usize = HEADER_FIELDS_LEN + 4;
+const CONFIRMED_MAGIC: u32 = 0xb007_c0de;
+
+const VENDOR_PUBKEY: &str = env!("VENDOR_PUBKEY");
+
+pub struct Header {
+    /// also the value of the rollback counter once the image is booted
+    pub version: u32,
+    /// the slot the image is linked for
+    pub slot: u8,
+    pub size: u32,
+    pub signature: [u8; 64]
+}
+
+impl Header {
+    pub fn parse(bytes: &[u8; HEADER_FIELDS_LEN]) -> Option<Self> {
+        if bytes[..4] != IMAGE_MAGIC {
+            return None
+        }
+
+        Some(Self {
+            version: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            slot: bytes[8],
+            size: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+            signature: bytes[16..].try_into().unwrap()
+        })
+    }
+}
+
+fn vendor_key() -> Option<VerifyingKey> {
+    let mut key = [0; 65];
+    let len = VENDOR_PUBKEY.len() / 2;
+    hex::decode_to_slice(VENDOR_PUBKEY, key.get_mut(..len)?).ok()?;
+    VerifyingKey::from_sec1_bytes(&key[..len]).ok()
+}
+
+/// check the image in a slot, returns its version if it is signed by the vendor
+pub fn verify(slot: usize) -> Option<u32> {
+    let base = SLOT_BASES[slot];
+    let fields = unsafe { &*(base as *const [u8; HEADER_FIELDS_LEN]) };
+    let header = Header::parse(fields)?;
+
+    if header.slot as usize != slot || header.size as usize > MAX_IMAGE_SIZE {
+        return None
+    }
+
+    let body = unsafe {
+        slice::from_raw_parts((base + HEADER_LEN) as *const u8, header.size as usize)
+    };
+    let digest = Sha256::new()
+        .chain_update(&fields[..SIGNED_LEN])
+        .chain_update(body);
+    let signature = Signature::try_from(&header.signature[..]).ok()?;
+
+    vendor_key()?.verify_digest(digest, &signature).ok()?;
+    Some(header.version)
+}
+
+fn word(slot: usize, offset: usize) -> u32 {
+    unsafe { *((SLOT_BASES[slot] + offset) as *const u32) }
+}
+
+pub fn tried(slot: usize) -> bool {
+    word(slot, TRIED_OFFSET) == TRIED_MAGIC
+}
+
+pub fn confirmed(slot: usize) -> bool {
+    word(slot, CONFIRMED_OFFSET) == CONFIRMED_MAGIC
+}
+
+/// the image is booted once on trial, it is not tried again unless it confirms
+pub fn mark_tried(flash: &mut LockedFlash, slot: usize) {
+    if !tried(slot) {
+        let _ = flash.unlocked().program(
+            SLOT_BASES[slot] + TRIED_OFFSET - FLASH_BASE,
+            TRIED_MAGIC.to_le_bytes().iter()
+        );
+    }
+}
@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+#![feature(let_else)]
+
+// Halt on panic
+use panic_halt as _;
+
+use cortex_m_rt::entry;
+use stm32f4xx_hal::{pac, flash::LockedFlash};
+
+mod counter;
+mod image;
+
+use image::{SLOT_BASES, HEADER_LEN};
+
+/// boot the valid image with the highest version, 
+/// images older than the rollback counter are ignored.
+///
+/// an image newer than the counter is booted once on trial, the counter
+/// is only raised to its version after the firmware has confirmed the boot.
+/// a trial which reset before confirming falls back to the other image
+#[entry]
+fn main() -> ! {
+    let Some(dp) = pac::Peripherals::take() else {
+        loop { cortex_m::asm::wfi() }
+    };
+
+    let min_version = counter::read();
+    let failed = |slot: usize, version: u32| {
+        version > min_version && image::tried(slot) && !image::confirmed(slot)
+    };
+    // a failed image is still better than nothing to boot
+    let best = (0..SLOT_BASES.len())
+        .filter_map(|slot| image::verify(slot).map(|version| (slot, version)))
+        .filter(|(_, version)| *version >= min_version)
+        .max_by_key(|(slot, version)| (!failed(*slot, *version), *version));
+
+    let Some((slot, version)) = best else {
+        // nothing to boot, wait for a debug probe
+        loop { cortex_m::asm::wfi() }
+    };
+
+    if version > min_version {
+        let mut flash = LockedFlash::new(dp.FLASH);
+        match image::confirmed(slot) {
+            true => counter::raise(&mut flash, version),
+            false => image::mark_tried(&mut flash, slot)
+        }
+    }
+
+    unsafe {
+        let vector_table = (SLOT_BASES[slot] + HEADER_LEN) as *const u32;
+        (*cortex_m::peripheral::SCB::PTR).vtor.write(vector_table as u32);
+        cortex_m::asm::bootload(vector_table)
+    }
+}
@@ -0,0 +1,38 @@
+use ethdwallet_protocol::{AuditEntry, AuditOp};
+use web3::signing::keccak256;
+
+use crate::error::Error;
+
+/// check that every entry follows the one before it.
+/// the wallet erases its oldest entries when the log is full,
+/// then the chain starts from the `prev` of the first entry kept
+pub fn verify(entries: &[AuditEntry]) -> Result<(), Error> {
+    if let Some(first) = entries.first() {
+        if first.seq == 0 && first.prev != [0; 32] {
+            return Err(Error::AuditChainBroken(0))
+        }
+    }
+
+    entries.windows(2).try_for_each(|pair| {
+        let (prev, entry) = (&pair[0], &pair[1]);
+        match entry.seq == prev.seq + 1 && entry.prev == keccak256(&prev.encode()) {
+            true => Ok(()),
+            false => Err(Error::AuditChainBroken(entry.seq))
+        }
+    })
+}
+
+/// one line for an entry
+pub fn describe(entry: &AuditEntry) -> String {
+    let op = match entry.op {
+        AuditOp::SignTransaction => "transaction",
+        AuditOp::SignHash => "hash"
+    };
+    let result = match entry.result {
+        None => "signed".to_string(),
+        Some(e) => format!("refused: {}", e.reason())
+    };
+    format!("#{} {} account {}: {} 0x{}",
+        entry.seq, op, entry.account, result, hex::encode(entry.digest)
+    )
+}
@@ -1,5 +1,7 @@
+use std::fmt::{self, Display};
+
+use ethdwallet_protocol::{ErrorReport, ErrorCategory, DecodeError, PROTOCOL_VERSION};
 use num::bigint::ParseBigIntError;
-use num_enum::FromPrimitive;
 
 #[derive(Debug)]
 pub enum Error {
@@ -8,10 +10,115 @@ pub enum Error {
     SerialCorrupted,
     SerialTimeout(serialport::Error),
     IoError(std::io::Error),
-    WalletError(WalletError),
+    /// the wallet refused the request
+    WalletError(ErrorReport),
     Web3Error(web3::Error),
     ErrorAddressFormat,
-    RlpError(serlp::error::Error)
+    InvalidLabel,
+    InvalidCiphertext,
+    InvalidImage,
+    InvalidKey,
+    RlpError(serlp::error::Error),
+    /// the protocol version of the wallet, 0 if it is older than GetInfo
+    IncompatibleWallet(u16),
+    /// the firmware of the wallet does not know the instruction
+    UnsupportedInstruction,
+    /// the sequence number of the first audit entry not following the one before
+    AuditChainBroken(u32)
+}
+
+impl Error {
+    /// the request may go through when it is sent again
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::SerialCorrupted => true,
+            Self::IoError(e) => e.kind() == std::io::ErrorKind::TimedOut,
+            _ => false
+        }
+    }
+}
+
+impl Error {
+    /// what the CLI exits with, scripts rely on these:
+    /// 1 anything else, 2 invalid input, 3 the serial link, 4 an incompatible wallet,
+    /// 5 the network, 6 an audit log failing its check,
+    /// 10 and up the category of an error sent by the wallet
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::InvalidHexMsg | Self::InvalidValue | Self::ErrorAddressFormat
+            | Self::InvalidLabel | Self::InvalidCiphertext | Self::InvalidImage
+            | Self::InvalidKey => 2,
+            Self::SerialCorrupted | Self::SerialTimeout(_) | Self::IoError(_) => 3,
+            Self::IncompatibleWallet(_) | Self::UnsupportedInstruction => 4,
+            Self::Web3Error(_) => 5,
+            Self::AuditChainBroken(_) => 6,
+            Self::WalletError(report) => 10 + report.category as i32,
+            Self::RlpError(_) => 1
+        }
+    }
+}
+
+fn category_name(category: ErrorCategory) -> &'static str {
+    match category {
+        ErrorCategory::Transport => "transport",
+        ErrorCategory::Request => "request",
+        ErrorCategory::State => "state",
+        ErrorCategory::Auth => "auth",
+        ErrorCategory::Policy => "policy",
+        ErrorCategory::Update => "update",
+        ErrorCategory::Hardware => "hardware",
+        ErrorCategory::Internal => "internal"
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidHexMsg => write!(f, "invalid hex"),
+            Self::InvalidValue => write!(f, "invalid value"),
+            Self::SerialCorrupted => write!(f, "the wallet answered with damaged data"),
+            Self::SerialTimeout(e) => write!(f, "serial port: {}", e),
+            Self::IoError(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                write!(f, "the wallet did not answer in time")
+            },
+            Self::IoError(e) => write!(f, "{}", e),
+            Self::WalletError(report) => {
+                write!(f, "the wallet refused: {}", report.reason())?;
+                if let Some(arg) = report.arg {
+                    write!(f, " ({})", arg)?;
+                }
+                write!(f, " [{} error {}]", category_name(report.category), report.code)
+            },
+            Self::Web3Error(e) => write!(f, "network: {}", e),
+            Self::ErrorAddressFormat => write!(f, "invalid address"),
+            Self::InvalidLabel => write!(f, "invalid label"),
+            Self::InvalidCiphertext => write!(f, "invalid ciphertext"),
+            Self::InvalidImage => write!(f, "invalid firmware image"),
+            Self::InvalidKey => write!(f, "invalid key"),
+            Self::RlpError(e) => write!(f, "cannot encode the transaction: {:?}", e),
+            Self::IncompatibleWallet(0) => write!(f, "the wallet firmware is older than this CLI"),
+            Self::IncompatibleWallet(version) => write!(f,
+                "the wallet speaks protocol v{}, this CLI v{}", version, PROTOCOL_VERSION
+            ),
+            Self::UnsupportedInstruction => write!(f, "the wallet firmware does not support this"),
+            Self::AuditChainBroken(seq) => write!(f, "the audit log is broken at entry #{}", seq)
+        }
+    }
+}
+
+impl From<ErrorReport> for Error {
+    fn from(report: ErrorReport) -> Self {
+        Self::WalletError(report)
+    }
+}
+
+impl From<DecodeError<Error>> for Error {
+    fn from(e: DecodeError<Error>) -> Self {
+        match e {
+            DecodeError::Io(e) => e,
+            DecodeError::Malformed => Self::SerialCorrupted
+        }
+    }
 }
 
 impl From<serlp::error::Error> for Error {
@@ -38,6 +145,12 @@ impl From<serialport::Error> for Error {
     }
 }
 
+impl From<base64::DecodeError> for Error {
+    fn from(_: base64::DecodeError) -> Self {
+        Self::InvalidCiphertext
+    }
+}
+
 impl From<hex::FromHexError> for Error {
     fn from(_: hex::FromHexError) -> Self {
         Self::InvalidHexMsg
@@ -50,18 +163,3 @@ impl From<std::io::Error> for Error {
         Self::IoError(e)
     }
 }
-
-#[repr(u8)]
-#[derive(Debug, Clone, Copy, FromPrimitive)]
-pub enum WalletError {
-    HalInitError,
-    AccountIdxOOB,
-    CryptoError,
-    InvalidSerialConfig,
-    InvalidInstruction,
-    SerialDataCorrupted,
-    WrongPassword,
-    SerialTxError,
-    #[num_enum(default)]
-    UnknownError
-}
@@ -0,0 +1,56 @@
+use k256::{
+    ecdsa::{SigningKey, Signature, signature::DigestSigner},
+    sha2::{Sha256, Digest}
+};
+
+use crate::error::Error;
+
+// the image layout is defined by ethdwallet-boot
+pub const IMAGE_MAGIC: [u8; 4] = *b"EWFW";
+pub const HEADER_LEN: usize = 0x200;
+pub use ethdwallet_protocol::HEADER_FIELDS_LEN;
+const SIGNED_LEN: usize = HEADER_FIELDS_LEN - 64;
+pub const MAX_IMAGE_SIZE: usize = 192 * 1024 - HEADER_LEN;
+/// body bytes sent in each FirmwareChunk
+pub const CHUNK_LEN: usize = 512;
+
+/// prepend a signed header to a raw binary linked for `slot`
+pub fn sign_image(key: &SigningKey, version: u32, slot: u8, body: &[u8]) -> Result<Vec<u8>, Error> {
+    if body.len() > MAX_IMAGE_SIZE {
+        return Err(Error::InvalidImage)
+    }
+
+    let mut image = vec![0xff; HEADER_LEN];
+    image[..4].copy_from_slice(&IMAGE_MAGIC);
+    image[4..8].copy_from_slice(&version.to_le_bytes());
+    image[8] = slot;
+    image[9..12].fill(0);
+    image[12..16].copy_from_slice(&(body.len() as u32).to_le_bytes());
+
+    let digest = Sha256::new()
+        .chain_update(&image[..SIGNED_LEN])
+        .chain_update(body);
+    let signature: Signature = key.sign_digest(digest);
+    image[SIGNED_LEN..HEADER_FIELDS_LEN].copy_from_slice(signature.as_ref());
+
+    image.extend_from_slice(body);
+    Ok(image)
+}
+
+/// (version, slot, header fields, body) of a signed image
+pub fn split_image(image: &[u8]) -> Result<(u32, u8, [u8; HEADER_FIELDS_LEN], &[u8]), Error> {
+    if image.len() < HEADER_LEN || image[..4] != IMAGE_MAGIC {
+        return Err(Error::InvalidImage)
+    }
+
+    let size = u32::from_le_bytes(image[12..16].try_into().unwrap()) as usize;
+    let body = image.get(HEADER_LEN..HEADER_LEN + size)
+        .ok_or(Error::InvalidImage)?;
+
+    Ok((
+        u32::from_le_bytes(image[4..8].try_into().unwrap()),
+        image[8],
+        image[..HEADER_FIELDS_LEN].try_into().unwrap(),
+        body
+    ))
+}
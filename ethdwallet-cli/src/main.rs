@@ -1,21 +1,49 @@
 #![feature(let_else)]
 
-use std::{time::Duration, fmt::Display, str::FromStr};
+use std::{
+    time::{Duration, SystemTime}, fmt::Display, str::FromStr, io::Write,
+    sync::atomic::{AtomicU8, AtomicU64, Ordering}, thread, ops::{Deref, DerefMut}
+};
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ArgEnum};
 use error::Error;
+use ethdwallet_protocol::{
+    Instruction, Response, Signature, DuressPolicy, Info, Sink, Source, Answer, WalletError, CrashCause,
+    AuditEntry, EthAddr, LABEL_LEN, AUDIT_PAGE_LEN, MAX_MSG_LEN, FRAME_TIMEOUT_MS, DEFAULT_BAUD_RATE, BAUD_RATES,
+    BAUD_CONFIRM_MS, read_answer
+};
+use firmware::CHUNK_LEN;
+use k256::ecdsa::SigningKey;
 use num::BigUint;
-use serialport::{self, SerialPort};
+use serde::Deserialize;
+use serialport::{self, SerialPort, ClearBuffer};
 use tx::UnsignedTx;
 use web3::types::H160;
 
+mod audit;
 mod error;
+mod firmware;
 mod tx;
 
 
 pub const RINKEBY_ENDPOINT: &'static str = "https://rinkeby.infura.io/v3/2620729769024a63bf0c874a04fad486";
 pub const RINKEBY_CHAINID: u32 = 4;
 
+/// the wallet acknowledges a request as soon as it arrives
+const ACK_TIMEOUT: Duration = Duration::from_secs(1);
+/// the user may take a while on the keypad
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1000);
+/// the wallet drops a partial frame after a pause, wait longer before sending again
+const RETRY_DELAY: Duration = Duration::from_millis(2 * FRAME_TIMEOUT_MS as u64);
+const MAX_ATTEMPTS: usize = 3;
+/// transaction bytes sent in each SignChunk, the most a request holds
+const SIGN_CHUNK_LEN: usize = MAX_MSG_LEN - 1 - 4;
+
+/// id of the next request, the wallet replays its last reply for a repeated id
+static NEXT_ID: AtomicU8 = AtomicU8::new(0);
+/// the instructions the wallet supports, filled by the handshake
+static SUPPORTED: AtomicU64 = AtomicU64::new(u64::MAX);
+
 #[derive(Clone, Subcommand)]
 #[clap(rename_all = "snake_case")]
 pub enum Action {
@@ -37,79 +65,144 @@ pub enum Action {
     Get {
         #[clap(short, long)]
         account: u8
+    },
+    BookAdd {
+        #[clap(short, long)]
+        label: String,
+        #[clap(short = 'd', long)]
+        address: String
+    },
+    BookRemove {
+        #[clap(short, long)]
+        index: u8
+    },
+    BookList,
+    Allowlist {
+        #[clap(short, long)]
+        account: u8,
+        #[clap(long)]
+        disable: bool
+    },
+    HiddenSetup,
+    Unlock,
+    Duress {
+        #[clap(long)]
+        wipe: bool
+    },
+    BlindSigning {
+        #[clap(long)]
+        disable: bool
+    },
+    SignHash {
+        #[clap(long)]
+        hash: String,
+        #[clap(short, long)]
+        account: u8
+    },
+    /// ciphertext is hex for secp256k1, 
+    /// or the (hex encoded) json of eth_decrypt for x25519
+    Decrypt {
+        #[clap(short, long)]
+        ciphertext: String,
+        #[clap(long, arg_enum, default_value = "x25519")]
+        scheme: Scheme,
+        #[clap(short, long)]
+        account: u8
+    },
+    EncryptionKey {
+        #[clap(short, long)]
+        account: u8
+    },
+    Diagnostics,
+    /// what the wallet runs and supports
+    Info,
+    /// print the signing operations logged by the wallet and check their hash chain
+    Audit,
+    /// print what made the wallet reset last, once
+    Crash,
+    /// stream the image linked for the slot not running to the device
+    Update {
+        #[clap(long)]
+        slot_a: String,
+        #[clap(long)]
+        slot_b: String
+    },
+    /// prepend a signed header to a raw binary, done by the vendor
+    SignImage {
+        /// hex encoded secp256k1 private key of the vendor
+        #[clap(short, long)]
+        key: String,
+        #[clap(short, long)]
+        version: u32,
+        /// 0 for slot A, 1 for slot B
+        #[clap(long)]
+        slot: u8,
+        #[clap(short, long)]
+        input: String,
+        #[clap(short, long)]
+        output: String
     }
 }
 
-#[derive(Parser)]
-struct Args {
-    #[clap(short, long)]
-    serial: String,
-    #[clap(short, long)]
-    baudrate: u32,
-    #[clap(subcommand)]
-    action: Action
-}
-
-enum Instruction {
-    /// [0, account_id, raw]
-    SignTransaction(u8, Vec<u8>),
-    /// [1, account_id]
-    GetAddress(u8),
-    /// [2]
-    GetAddressList
+#[derive(Clone, Copy, ArgEnum)]
+pub enum Scheme {
+    Secp256k1,
+    X25519
 }
 
-impl Instruction {
-    fn build_msg(self) -> Vec<u8> {
-        let mut msg = Vec::new();
-        msg.push(0xff);
-    
-        let mut content = Vec::new();
-        match self {
-            Instruction::SignTransaction(idx, raw) => {
-                content.push(0x00);
-                content.push(idx);
-                content.extend(raw);
-            },
-            Instruction::GetAddress(idx) => {
-                content.push(0x01);
-                content.push(idx);
-            },
-            Instruction::GetAddressList => {
-                content.push(0x02);
-            },
-        };
-
-        msg.extend_from_slice(&(content.len() as u32).to_le_bytes());
-        msg.extend(content);
-
-        msg
+impl From<Scheme> for ethdwallet_protocol::Scheme {
+    fn from(scheme: Scheme) -> Self {
+        match scheme {
+            Scheme::Secp256k1 => Self::Secp256k1,
+            Scheme::X25519 => Self::X25519XSalsa20Poly1305
+        }
     }
 }
 
-const ACCOUNT_NUM: usize = 32;
-type EthAddr = [u8; 20];
-
-#[derive(Clone, Copy, Debug)]
-pub struct Signature {
-    pub r: [u8; 32],
-    pub s: [u8; 32],
-    pub v: u8
+/// the ciphertext produced by eth-sig-util
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EncryptedData {
+    version: String,
+    nonce: String,
+    ephem_public_key: String,
+    ciphertext: String
 }
 
-type PubKey = [u8; 64];
-
-#[repr(u8)]
-#[derive(Debug)]
-enum Response {
-    Signature(Signature),
-    Address((EthAddr, PubKey)),
-    AddressList([EthAddr; ACCOUNT_NUM])
+#[derive(Parser)]
+struct Args {
+    #[clap(short, long)]
+    serial: String,
+    /// the fastest baud rate to negotiate, the session starts at 9600
+    #[clap(short, long)]
+    baudrate: Option<u32>,
+    #[clap(subcommand)]
+    action: Action
 }
 
-impl Display for Response {
+const RECOVERY_STATUS: [&str; 8] = [
+    "clean", 
+    "voted (some copies are corrupted)", 
+    "repaired (most copies were corrupted)",
+    "fell back to the previous version",
+    "reconstructed from corrupted copies",
+    "unrecoverable",
+    "blank",
+    "written by a newer firmware"
+];
+
+/// RCC_CSR from bit 24 on, as kept in a crash report
+const RESET_FLAGS: [&str; 8] = [
+    "", "brownout", "reset pin", "power on", "software", "independent watchdog",
+    "window watchdog", "low power"
+];
+
+/// prints a response for the user
+struct Pretty<'a>(&'a Response);
+
+impl Display for Pretty<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
+        match self.0 {
             Response::Signature(Signature { r, s, v }) => {
                 write!(f, "r: {}, s: {}, v: {}", 
                     hex::encode(r),
@@ -126,70 +219,404 @@ impl Display for Response {
                     write!(f, "account {}: 0x{}\n", idx, hex::encode(addr))
                 })
             },
+            Response::Done => write!(f, "done"),
+            Response::AddressBook(entries) => {
+                entries.iter().enumerate()
+                    .filter(|(_, entry)| entry.used)
+                    .try_for_each(|(idx, entry)| {
+                        write!(f, "entry {}: {} 0x{}\n", 
+                            idx, 
+                            String::from_utf8_lossy(&entry.label).trim_end_matches('\0'),
+                            hex::encode(entry.addr)
+                        )
+                    })
+            },
+            Response::Plaintext(buf, len) => {
+                write!(f, "{}", String::from_utf8_lossy(&buf[..*len]))
+            },
+            Response::EncryptionPubkey(pubkey) => {
+                write!(f, "{}", base64::encode(pubkey))
+            },
+            Response::Diagnostics(diag) => {
+                write!(f, "status: {}\n", RECOVERY_STATUS.get(diag.status as usize)
+                    .unwrap_or(&"unknown")
+                )?;
+                write!(f, "slot: {}, sequence: {}, format: v{}\n", 
+                    ['A', 'B'].get(diag.slot as usize).unwrap_or(&'?'), 
+                    diag.seq,
+                    diag.version
+                )?;
+                write!(f, "copies: {} valid, {} agreeing, {} total", 
+                    diag.valid, diag.agreeing, diag.copies
+                )
+            },
+            Response::FirmwareInfo(slot, version) => {
+                write!(f, "running slot: {}, version: {}", 
+                    ['A', 'B'].get(*slot as usize).unwrap_or(&'?'), 
+                    version
+                )
+            },
+            Response::Info(info) => {
+                write!(f, "protocol: v{}, firmware: v{} ({})\n", 
+                    info.protocol_version, 
+                    info.firmware_version, 
+                    hex::encode(info.git_hash)
+                )?;
+                write!(f, "accounts: {}, max message: {} bytes, instructions: {:#x}\n", 
+                    info.account_num, info.max_msg_len, info.instructions
+                )?;
+                write!(f, "initialized: {}, unlocked: {}\n", info.initialized, info.unlocked)?;
+                write!(f, "uid: {}", hex::encode(info.uid))
+            },
+            Response::AuditLog(entries, count) => {
+                entries[..*count].iter().try_for_each(|entry| {
+                    write!(f, "{}\n", audit::describe(entry))
+                })
+            },
+            Response::CrashReport(None) => write!(f, "no crash since the last report"),
+            Response::CrashReport(Some(report)) => {
+                let cause = match report.cause {
+                    CrashCause::Panic => "panic",
+                    CrashCause::HardFault => "hard fault",
+                    CrashCause::Iwdg => "independent watchdog, a request was stuck for 30 seconds",
+                    CrashCause::SoftWatchdog => "TIM2 watchdog, the wallet stopped responding"
+                };
+                let flags: Vec<_> = RESET_FLAGS.iter().enumerate()
+                    .filter(|(bit, _)| *bit > 0 && report.reset_flags & (1 << bit) != 0)
+                    .map(|(_, name)| *name)
+                    .collect();
+                write!(f, "cause: {}\n", cause)?;
+                write!(f, "reset by: {}\n", flags.join(", "))?;
+                if !report.message().is_empty() {
+                    write!(f, "message: {}\n", report.message())?;
+                }
+                write!(f, "pc: {:#010x}, lr: {:#010x}, xpsr: {:#010x}\n",
+                    report.pc, report.lr, report.xpsr
+                )?;
+                write!(f, "cfsr: {:#010x}, hfsr: {:#010x}, mmfar: {:#010x}, bfar: {:#010x}",
+                    report.cfsr, report.hfsr, report.mmfar, report.bfar
+                )
+            },
         }
     }
 }
 
+/// the serial port as the two ends of the protocol
+struct Port<'a>(&'a mut dyn SerialPort);
+
+impl Sink for Port<'_> {
+    type Error = Error;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        Ok(self.0.write_all(bytes)?)
+    }
+}
+
+impl Source for Port<'_> {
+    type Error = Error;
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        Ok(self.0.read_exact(buf)?)
+    }
+}
+
+/// send the request again when the frame is damaged on the way or the answer is lost
 fn process_instruction(
     serial: &mut dyn SerialPort, instr: Instruction
 ) -> Result<Response, error::Error> {
+    request(serial, instr, MAX_ATTEMPTS)
+}
+
+/// send the request at most `attempts` times
+fn request(
+    serial: &mut dyn SerialPort, instr: Instruction, attempts: usize
+) -> Result<Response, error::Error> {
+    if SUPPORTED.load(Ordering::Relaxed) & (1 << instr.code()) == 0 {
+        return Err(Error::UnsupportedInstruction)
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    let mut attempt = 1;
+    loop {
+        match exchange(serial, id, &instr) {
+            Err(e) if attempt < attempts && e.is_transient() => {
+                thread::sleep(RETRY_DELAY);
+                serial.clear(ClearBuffer::Input)?;
+                attempt += 1;
+            },
+            result => return result
+        }
+    }
+}
+
+/// send the request once, then wait for its acknowledgement and its reply
+fn exchange(
+    serial: &mut dyn SerialPort, id: u8, instr: &Instruction
+) -> Result<Response, error::Error> {
+    serial.set_timeout(ACK_TIMEOUT)?;
+    let mut port = Port(serial);
+    instr.write_request(id, &mut port)?;
+
+    // answers to an earlier request may arrive late, skip them
+    loop {
+        match read_answer(&mut port)? {
+            (answer_id, Answer::Ack) if answer_id == id => break,
+            // only one request is on the way, the id may be the damaged part
+            (_, Answer::Nak) => return Err(Error::SerialCorrupted),
+            _ => continue
+        }
+    }
 
-    let msg = instr.build_msg();
-    // println!("send: {}", hex::encode(&msg));
-    serial.write_all(&msg)?;
+    port.0.set_timeout(REPLY_TIMEOUT)?;
+    loop {
+        match read_answer(&mut port)? {
+            (answer_id, Answer::Reply(reply)) if answer_id == id => return Ok(reply?),
+            _ => continue
+        }
+    }
+}
 
-    let mut state = [0, 0];
-    serial.read_exact(&mut state)?;
+/// ask what the wallet is, refuse to go on with a wallet speaking another protocol
+fn handshake(serial: &mut dyn SerialPort, attempts: usize) -> Result<Info, Error> {
+    let info = match request(serial, Instruction::GetInfo, attempts) {
+        Ok(Response::Info(info)) => info,
+        // older than GetInfo
+        Err(Error::WalletError(report)) if report.error() == Some(WalletError::InvalidInstruction) => {
+            return Err(Error::IncompatibleWallet(0))
+        },
+        Err(e) => return Err(e),
+        Ok(_) => return Err(Error::SerialCorrupted)
+    };
+    if !info.is_compatible() {
+        return Err(Error::IncompatibleWallet(info.protocol_version))
+    }
+
+    SUPPORTED.store(info.instructions, Ordering::Relaxed);
+    Ok(info)
+}
 
-    match state[0] {
-        0x00 => {},
-        0xff => return Err(Error::WalletError(state[1].into())),
-        _ => return Err(Error::SerialCorrupted)
+/// find the wallet at the default baud rate, or at the rate an interrupted session left it at
+fn connect(serial: &mut dyn SerialPort) -> Result<Info, Error> {
+    let mut result = handshake(serial, MAX_ATTEMPTS);
+    for baud in BAUD_RATES.into_iter().filter(|baud| *baud != DEFAULT_BAUD_RATE) {
+        match result {
+            Err(ref e) if e.is_transient() => {},
+            _ => break
+        }
+        serial.set_baud_rate(baud)?;
+        serial.clear(ClearBuffer::Input)?;
+        result = handshake(serial, 1);
     }
 
-    Ok(match state[1] {
-        0x00 => {
-            let mut r = [0; 32];
-            let mut s = [0; 32];
-            let mut v = [0];
+    if result.is_err() {
+        serial.set_baud_rate(DEFAULT_BAUD_RATE)?;
+    }
+    result
+}
 
-            serial.read_exact(&mut r)?;
-            serial.read_exact(&mut s)?;
-            serial.read_exact(&mut v)?;
+/// switch to the fastest baud rate up to `max` the requests get through at,
+/// the wallet falls back to DEFAULT_BAUD_RATE by itself when they do not
+fn negotiate_baud_rate(serial: &mut dyn SerialPort, max: u32) -> Result<(), Error> {
+    if SUPPORTED.load(Ordering::Relaxed) & (1 << Instruction::SetBaudRate(0).code()) == 0 {
+        return Ok(())
+    }
 
-            Response::Signature(Signature {
-                r, s, v: v[0]
-            })
-        },
-        0x01 => {
-            let mut addr = [0; 20];
-            let mut pubkey = [0; 64];
-            serial.read_exact(&mut addr)?;
-            serial.read_exact(&mut pubkey)?;
+    for baud in BAUD_RATES.into_iter().filter(|baud| *baud <= max) {
+        if baud == serial.baud_rate()? {
+            break
+        }
+        match process_instruction(serial, Instruction::SetBaudRate(baud)) {
+            Ok(_) => {},
+            // the wallet does not take this rate
+            Err(Error::WalletError(_)) => continue,
+            // the wallet may have switched, or not
+            Err(e) if e.is_transient() => {
+                fall_back(serial)?;
+                continue
+            },
+            Err(e) => return Err(e)
+        }
 
-            Response::Address((addr, pubkey))
-        },
-        0x02 => {
-            let mut addrs = [[0; 20]; ACCOUNT_NUM];
-            addrs.iter_mut().try_for_each(|addr| {
-                serial.read_exact(addr)?;
-                Ok::<(), Error>(())
-            })?;
-            
-            Response::AddressList(addrs)
-        },
-        _ => return Err(Error::SerialCorrupted)
-    })
+        serial.set_baud_rate(baud)?;
+        if process_instruction(serial, Instruction::GetInfo).is_ok() {
+            return Ok(())
+        }
+        fall_back(serial)?;
+    }
+    Ok(())
+}
+
+/// wait until the wallet gives up on the new baud rate, then follow it
+fn fall_back(serial: &mut dyn SerialPort) -> Result<(), Error> {
+    thread::sleep(Duration::from_millis(BAUD_CONFIRM_MS as u64) + RETRY_DELAY);
+    serial.set_baud_rate(DEFAULT_BAUD_RATE)?;
+    Ok(serial.clear(ClearBuffer::Input)?)
+}
+
+/// the serial port of a session, the wallet is put back to DEFAULT_BAUD_RATE at the end
+/// so the next session finds it without probing
+struct Session(Box<dyn SerialPort>);
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if self.0.baud_rate().map_or(false, |baud| baud != DEFAULT_BAUD_RATE) {
+            let _ = process_instruction(
+                self.0.as_mut(), Instruction::SetBaudRate(DEFAULT_BAUD_RATE)
+            );
+        }
+    }
+}
+
+impl Deref for Session {
+    type Target = Box<dyn SerialPort>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Session {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+fn parse_addr(addr: String) -> Result<EthAddr, Error> {
+    let addr = hex::decode(addr.trim_start_matches("0x"))?;
+    addr.try_into().map_err(|_| Error::ErrorAddressFormat)
+}
+
+fn parse_label(label: String) -> Result<[u8; LABEL_LEN], Error> {
+    if !label.is_ascii() || label.len() > LABEL_LEN {
+        return Err(Error::InvalidLabel)
+    }
+
+    let mut buf = [0; LABEL_LEN];
+    buf[..label.len()].copy_from_slice(label.as_bytes());
+    Ok(buf)
+}
+
+/// ephemeral public key, nonce, then the boxed message
+fn parse_x25519_ciphertext(ciphertext: String) -> Result<Vec<u8>, Error> {
+    let json = match hex::decode(ciphertext.trim_start_matches("0x")) {
+        Ok(json) => json,
+        Err(_) => ciphertext.into_bytes()
+    };
+    let data: EncryptedData = serde_json::from_slice(&json)
+        .map_err(|_| Error::InvalidCiphertext)?;
+
+    if data.version != "x25519-xsalsa20-poly1305" {
+        return Err(Error::InvalidCiphertext)
+    }
+
+    let mut payload = base64::decode(data.ephem_public_key)?;
+    payload.extend(base64::decode(data.nonce)?);
+    payload.extend(base64::decode(data.ciphertext)?);
+    Ok(payload)
+}
+
+/// write the image for the slot not running, then let the device reboot into it
+fn update_firmware(
+    serial: &mut dyn SerialPort, slot_a: String, slot_b: String
+) -> Result<(), Error> {
+    let Response::FirmwareInfo(running, version) = process_instruction(
+        serial, Instruction::GetFirmwareInfo
+    )? else {
+        panic!("type confusion.")
+    };
+    println!("running slot {}, version {}", running, version);
+
+    let path = if running == 0 { slot_b } else { slot_a };
+    let image = std::fs::read(path)?;
+    let (new_version, slot, header, body) = firmware::split_image(&image)?;
+    if slot == running {
+        return Err(Error::InvalidImage)
+    }
+
+    println!("confirm the update to version {} on the device", new_version);
+    process_instruction(serial, Instruction::FirmwareBegin(&header))?;
+
+    for (idx, chunk) in body.chunks(CHUNK_LEN).enumerate() {
+        let offset = (idx * CHUNK_LEN) as u32;
+        process_instruction(serial, Instruction::FirmwareChunk(offset, chunk))?;
+        print!("\r{}/{} bytes", offset as usize + chunk.len(), body.len());
+        std::io::stdout().flush()?;
+    }
+    println!();
+
+    process_instruction(serial, Instruction::FirmwareFinish)?;
+    // the new firmware starts at the default rate
+    serial.set_baud_rate(DEFAULT_BAUD_RATE)?;
+    println!("done, the device restarts into the new firmware");
+    Ok(())
+}
+
+/// every entry the wallet keeps, a page at a time
+fn read_audit_log(serial: &mut dyn SerialPort) -> Result<Vec<AuditEntry>, Error> {
+    let mut entries: Vec<AuditEntry> = Vec::new();
+    loop {
+        let start = entries.last().map_or(0, |entry| entry.seq + 1);
+        let Response::AuditLog(page, count) = process_instruction(
+            serial, Instruction::ReadAuditLog(start)
+        )? else {
+            panic!("type confusion.")
+        };
+
+        entries.extend(&page[..count]);
+        if count < AUDIT_PAGE_LEN {
+            return Ok(entries)
+        }
+    }
+}
+
+/// sign a raw transaction, streamed in chunks if it does not fit in one request
+fn sign_transaction(
+    serial: &mut dyn SerialPort, account: u8, raw: &[u8]
+) -> Result<Signature, Error> {
+    let instr = Instruction::SignTransaction(account, raw);
+    let resp = match instr.content_len() <= MAX_MSG_LEN {
+        true => process_instruction(serial, instr)?,
+        false => {
+            process_instruction(serial, Instruction::SignBegin(account, raw.len() as u32))?;
+            for (idx, chunk) in raw.chunks(SIGN_CHUNK_LEN).enumerate() {
+                let offset = (idx * SIGN_CHUNK_LEN) as u32;
+                process_instruction(serial, Instruction::SignChunk(offset, chunk))?;
+            }
+            process_instruction(serial, Instruction::SignFinish)?
+        }
+    };
+
+    let Response::Signature(sig) = resp else {
+        panic!("type confusion");
+    };
+    Ok(sig)
 }
 
 async fn process_action(
-    serial: String, baudrate: u32, action: Action
+    serial: String, baudrate: Option<u32>, action: Action
 ) -> Result<(), error::Error> {
-    let mut serial = serialport::new(serial, baudrate)
-        .timeout(Duration::from_secs(1000))
+    // signing does not need a device
+    if let Action::SignImage { key, version, slot, input, output } = action {
+        let key = SigningKey::from_bytes(&hex::decode(key.trim_start_matches("0x"))?)
+            .map_err(|_| Error::InvalidKey)?;
+        let image = firmware::sign_image(&key, version, slot, &std::fs::read(input)?)?;
+        std::fs::write(output, image)?;
+        println!("public key: {}", hex::encode(key.verifying_key().to_bytes()));
+        return Ok(())
+    }
+
+    // a new session does not reuse the ids of the last one
+    let seed = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |time| time.subsec_nanos() as u8);
+    NEXT_ID.store(seed, Ordering::Relaxed);
+
+    let mut serial = Session(serialport::new(serial, DEFAULT_BAUD_RATE)
+        .timeout(REPLY_TIMEOUT)
         .data_bits(serialport::DataBits::Eight)
         .stop_bits(serialport::StopBits::One)
-        .open()?;
+        .open()?);
+    let info = connect(serial.as_mut())?;
+    negotiate_baud_rate(serial.as_mut(), baudrate.unwrap_or(BAUD_RATES[0]))?;
 
     let transport = web3::transports::Http::new(RINKEBY_ENDPOINT)?;
     let provider = web3::api::Web3::new(transport);
@@ -198,24 +625,148 @@ async fn process_action(
     Ok(match action {
         Action::Sign { msg, account } => {
             let msg = hex::decode(msg)?;
-            let instr = Instruction::SignTransaction(account as u8, msg);
-            let resp = process_instruction(serial.as_mut(), instr)?;
+            let sig = sign_transaction(serial.as_mut(), account, &msg)?;
 
-            println!("{}", resp)
+            println!("{}", Pretty(&Response::Signature(sig)))
         },
         Action::List => {
             let resp = process_instruction(
                 serial.as_mut(), Instruction::GetAddressList
             )?;
             
-            println!("{}", resp)
+            println!("{}", Pretty(&resp))
         },
         Action::Get { account } => {
             let resp = process_instruction(
                 serial.as_mut(), Instruction::GetAddress(account)
             )?;
 
-            println!("{}", resp)
+            println!("{}", Pretty(&resp))
+        },
+        Action::BookAdd { label, address } => {
+            let instr = Instruction::AddBookEntry(
+                parse_label(label)?, parse_addr(address)?
+            );
+            let resp = process_instruction(serial.as_mut(), instr)?;
+
+            println!("{}", Pretty(&resp))
+        },
+        Action::BookRemove { index } => {
+            let resp = process_instruction(
+                serial.as_mut(), Instruction::RemoveBookEntry(index)
+            )?;
+
+            println!("{}", Pretty(&resp))
+        },
+        Action::BookList => {
+            let resp = process_instruction(
+                serial.as_mut(), Instruction::GetAddressBook
+            )?;
+
+            print!("{}", Pretty(&resp))
+        },
+        Action::Allowlist { account, disable } => {
+            let resp = process_instruction(
+                serial.as_mut(), Instruction::SetAllowlistOnly(account, !disable)
+            )?;
+
+            println!("{}", Pretty(&resp))
+        },
+        Action::HiddenSetup => {
+            println!("enter the passcode, then the new passcode of the hidden wallet");
+            let resp = process_instruction(
+                serial.as_mut(), Instruction::SetupHiddenZone
+            )?;
+
+            println!("{}", Pretty(&resp))
+        },
+        Action::Unlock => {
+            let resp = process_instruction(
+                serial.as_mut(), Instruction::Unlock
+            )?;
+
+            println!("{}", Pretty(&resp))
+        },
+        Action::Duress { wipe } => {
+            println!("enter the passcode, then the duress passcode");
+            let resp = process_instruction(
+                serial.as_mut(), Instruction::SetupDuressZone(match wipe {
+                    true => DuressPolicy::Wipe,
+                    false => DuressPolicy::Decoy
+                })
+            )?;
+
+            println!("{}", Pretty(&resp))
+        },
+        Action::BlindSigning { disable } => {
+            let resp = process_instruction(
+                serial.as_mut(), Instruction::SetBlindSigning(!disable)
+            )?;
+
+            println!("{}", Pretty(&resp))
+        },
+        Action::SignHash { hash, account } => {
+            let hash = hex::decode(hash.trim_start_matches("0x"))?
+                .try_into()
+                .map_err(|_| Error::InvalidHexMsg)?;
+            println!("confirm the blind signing on the device");
+            let resp = process_instruction(
+                serial.as_mut(), Instruction::SignHash(account, hash)
+            )?;
+
+            println!("{}", Pretty(&resp))
+        },
+        Action::Decrypt { ciphertext, scheme, account } => {
+            let payload = match scheme {
+                Scheme::Secp256k1 => hex::decode(ciphertext.trim_start_matches("0x"))?,
+                Scheme::X25519 => parse_x25519_ciphertext(ciphertext)?
+            };
+            println!("confirm the decryption on the device");
+            let resp = process_instruction(
+                serial.as_mut(), Instruction::Decrypt(account, scheme.into(), &payload)
+            )?;
+
+            println!("{}", Pretty(&resp))
+        },
+        Action::EncryptionKey { account } => {
+            let resp = process_instruction(
+                serial.as_mut(), Instruction::GetEncryptionPubkey(account)
+            )?;
+
+            println!("{}", Pretty(&resp))
+        },
+        Action::Update { slot_a, slot_b } => {
+            update_firmware(serial.as_mut(), slot_a, slot_b)?
+        },
+        Action::SignImage { .. } => unreachable!(),
+        Action::Diagnostics => {
+            let resp = process_instruction(
+                serial.as_mut(), Instruction::GetDiagnostics
+            )?;
+
+            println!("{}", Pretty(&resp))
+        },
+        Action::Info => {
+            println!("{}", Pretty(&Response::Info(info)))
+        },
+        Action::Audit => {
+            let entries = read_audit_log(serial.as_mut())?;
+            entries.iter().for_each(|entry| println!("{}", audit::describe(entry)));
+
+            match entries.first() {
+                None => println!("nothing is logged"),
+                Some(first) if first.seq > 0 => {
+                    println!("entries before #{} were erased to make room", first.seq)
+                },
+                Some(_) => {}
+            }
+            audit::verify(&entries)?;
+            println!("the hash chain of {} entries is intact", entries.len())
+        },
+        Action::Crash => {
+            let resp = process_instruction(serial.as_mut(), Instruction::GetCrashReport)?;
+
+            println!("{}", Pretty(&resp))
         },
         Action::Transfer { to, value, account } => {
             let Response::Address((addr, _)) = process_instruction(
@@ -257,13 +808,7 @@ async fn process_action(
             let raw_unsigned = serlp::rlp::to_bytes(&unsigned_tx)?;
             
 
-            let instr = Instruction::SignTransaction(
-                account, raw_unsigned.to_vec()
-            );
-
-            let Response::Signature(sig) = process_instruction(serial.as_mut(), instr)? else {
-                panic!("type confusion");
-            };
+            let sig = sign_transaction(serial.as_mut(), account, &raw_unsigned)?;
 
             let signed_tx = unsigned_tx.into_signed(sig);
 
@@ -290,8 +835,8 @@ async fn process_action(
 async fn main() {
     let args = Args::parse();
 
-    match process_action(args.serial, args.baudrate, args.action).await {
-        Err(e) => println!("Error: {:?}", e),
-        Ok(()) => {}
+    if let Err(e) = process_action(args.serial, args.baudrate, args.action).await {
+        eprintln!("error: {}", e);
+        std::process::exit(e.exit_code())
     }
 }
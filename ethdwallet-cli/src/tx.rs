@@ -2,7 +2,7 @@ use serde::{Serialize, Deserialize};
 use serlp::types::{biguint, byte_array};
 use num::BigUint;
 
-use crate::Signature;
+use ethdwallet_protocol::Signature;
 
 // (nonce, gasprice, startgas, to, value, data, chainid, 0, 0)
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
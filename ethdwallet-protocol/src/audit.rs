@@ -0,0 +1,74 @@
+use crate::WalletError;
+
+/// bytes of an encoded entry, as kept in flash and sent to the host
+pub const AUDIT_ENTRY_LEN: usize = 4 + 4 + 32 + 32;
+/// entries in a ReadAuditLog reply
+pub const AUDIT_PAGE_LEN: usize = 12;
+
+/// what was asked of the wallet
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditOp {
+    /// SignTransaction, or SignBegin to SignFinish
+    SignTransaction,
+    SignHash
+}
+
+/// a signing operation in the audit log of the wallet.
+///
+/// seq(4) + op(1) + account(1) + result(1) + reserved(1) + digest(32) + prev(32),
+/// `prev` is the keccak256 of the encoded entry before, zero for the first entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// counts every entry ever written, there is no clock to tell the time
+    pub seq: u32,
+    pub op: AuditOp,
+    pub account: u8,
+    /// None if the signature was sent
+    pub result: Option<WalletError>,
+    /// keccak256 of the transaction, or the hash signed as it is
+    pub digest: [u8; 32],
+    pub prev: [u8; 32]
+}
+
+impl AuditEntry {
+    /// fills the unused part of a page
+    pub const EMPTY: Self = Self {
+        seq: 0,
+        op: AuditOp::SignTransaction,
+        account: 0,
+        result: None,
+        digest: [0; 32],
+        prev: [0; 32]
+    };
+
+    pub fn encode(&self) -> [u8; AUDIT_ENTRY_LEN] {
+        let mut bytes = [0; AUDIT_ENTRY_LEN];
+        bytes[..4].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[4] = self.op as u8;
+        bytes[5] = self.account;
+        bytes[6] = self.result.map_or(0xff, |e| e as u8);
+        bytes[8..40].copy_from_slice(&self.digest);
+        bytes[40..].copy_from_slice(&self.prev);
+        bytes
+    }
+
+    /// None if the bytes are not an entry
+    pub fn decode(bytes: &[u8; AUDIT_ENTRY_LEN]) -> Option<Self> {
+        Some(Self {
+            seq: u32::from_le_bytes(bytes[..4].try_into().unwrap()),
+            op: match bytes[4] {
+                0 => AuditOp::SignTransaction,
+                1 => AuditOp::SignHash,
+                _ => return None
+            },
+            account: bytes[5],
+            result: match bytes[6] {
+                0xff => None,
+                code => Some(WalletError::from_code(code)?)
+            },
+            digest: bytes[8..40].try_into().unwrap(),
+            prev: bytes[40..].try_into().unwrap()
+        })
+    }
+}
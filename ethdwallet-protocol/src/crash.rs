@@ -0,0 +1,121 @@
+/// the longest panic message kept
+pub const CRASH_MSG_LEN: usize = 96;
+/// bytes of an encoded report, as kept in backup SRAM and sent to the host
+pub const CRASH_REPORT_LEN: usize = 1 + 1 + 1 + 1 + 4 * 7 + CRASH_MSG_LEN;
+
+/// what ended the last run, the causes are never reordered
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrashCause {
+    /// see the message
+    Panic,
+    /// see the registers
+    HardFault,
+    /// a request ran for too long without waiting for the host or a key
+    Iwdg,
+    /// the TIM2 watchdog found the main loop stuck
+    SoftWatchdog
+}
+
+impl CrashCause {
+    const ALL: [Self; 4] = [Self::Panic, Self::HardFault, Self::Iwdg, Self::SoftWatchdog];
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        Self::ALL.get(code as usize).copied()
+    }
+}
+
+/// a crash of the wallet, kept until read with GetCrashReport.
+///
+/// cause(1) + reset flags(1) + message length(1) + reserved(1)
+/// + pc, lr, xpsr, cfsr, hfsr, mmfar, bfar(4 each) + message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CrashReport {
+    pub cause: CrashCause,
+    /// RCC_CSR >> 24 at the start after the crash, zero until then
+    pub reset_flags: u8,
+    /// stacked by the HardFault, zero otherwise
+    pub pc: u32,
+    pub lr: u32,
+    pub xpsr: u32,
+    /// fault status and address registers of the SCB
+    pub cfsr: u32,
+    pub hfsr: u32,
+    pub mmfar: u32,
+    pub bfar: u32,
+    message: [u8; CRASH_MSG_LEN],
+    message_len: u8
+}
+
+impl CrashReport {
+    pub fn new(cause: CrashCause) -> Self {
+        Self {
+            cause,
+            reset_flags: 0,
+            pc: 0,
+            lr: 0,
+            xpsr: 0,
+            cfsr: 0,
+            hfsr: 0,
+            mmfar: 0,
+            bfar: 0,
+            message: [0; CRASH_MSG_LEN],
+            message_len: 0
+        }
+    }
+
+    /// the panic location and message
+    pub fn message(&self) -> &str {
+        // only printable ASCII is kept
+        core::str::from_utf8(&self.message[..self.message_len as usize]).unwrap_or("")
+    }
+
+    /// append to the message, cut at `CRASH_MSG_LEN`,
+    /// anything not printable ASCII becomes '?'
+    pub fn push_message(&mut self, text: &[u8]) {
+        let len = self.message_len as usize;
+        let free = &mut self.message[len..];
+        let count = free.iter_mut().zip(text).map(|(dst, ch)| {
+            *dst = match ch {
+                b' '..=b'~' => *ch,
+                _ => b'?'
+            }
+        }).count();
+        self.message_len = (len + count) as u8;
+    }
+
+    pub fn encode(&self) -> [u8; CRASH_REPORT_LEN] {
+        let mut bytes = [0; CRASH_REPORT_LEN];
+        bytes[..4].copy_from_slice(&[self.cause as u8, self.reset_flags, self.message_len, 0]);
+        let regs = [self.pc, self.lr, self.xpsr, self.cfsr, self.hfsr, self.mmfar, self.bfar];
+        bytes[4..32].chunks_exact_mut(4).zip(regs).for_each(|(dst, reg)| {
+            dst.copy_from_slice(&reg.to_le_bytes())
+        });
+        bytes[32..].copy_from_slice(&self.message);
+        bytes
+    }
+
+    /// None if the bytes are not a report
+    pub fn decode(bytes: &[u8; CRASH_REPORT_LEN]) -> Option<Self> {
+        if bytes[2] as usize > CRASH_MSG_LEN {
+            return None
+        }
+        let reg = |idx: usize| {
+            u32::from_le_bytes(bytes[4 + 4 * idx..8 + 4 * idx].try_into().unwrap())
+        };
+
+        let mut report = Self {
+            reset_flags: bytes[1],
+            pc: reg(0),
+            lr: reg(1),
+            xpsr: reg(2),
+            cfsr: reg(3),
+            hfsr: reg(4),
+            mmfar: reg(5),
+            bfar: reg(6),
+            ..Self::new(CrashCause::from_code(bytes[0])?)
+        };
+        report.push_message(&bytes[32..32 + bytes[2] as usize]);
+        Some(report)
+    }
+}
@@ -0,0 +1,240 @@
+use crate::{Sink, Source, DecodeError};
+
+/// the error codes sent by the wallet, never reorder them
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalletError {
+    HalInitError,
+    AccountIdxOOB,
+    CryptoError,
+    InvalidSerialConfig,
+    InvalidInstruction,
+    SerialDataCorrupted,
+    WrongPassword,
+    SerialTxError,
+    I2cError,
+    WalletNotInitialized,
+    BookFull,
+    BookIdxOOB,
+    BookEntryExists,
+    InvalidTransaction,
+    RecipientNotAllowed,
+    PasscodeInUse,
+    BlindSigningDisabled,
+    UserRejected,
+    InvalidCiphertext,
+    EntropyFailure,
+    WalletCorrupted,
+    UnsupportedFormat,
+    InvalidFirmware,
+    FirmwareRollback,
+    FirmwareIncomplete,
+    FlashError,
+    SigningIncomplete
+}
+
+impl WalletError {
+    const ALL: [Self; 27] = [
+        Self::HalInitError,
+        Self::AccountIdxOOB,
+        Self::CryptoError,
+        Self::InvalidSerialConfig,
+        Self::InvalidInstruction,
+        Self::SerialDataCorrupted,
+        Self::WrongPassword,
+        Self::SerialTxError,
+        Self::I2cError,
+        Self::WalletNotInitialized,
+        Self::BookFull,
+        Self::BookIdxOOB,
+        Self::BookEntryExists,
+        Self::InvalidTransaction,
+        Self::RecipientNotAllowed,
+        Self::PasscodeInUse,
+        Self::BlindSigningDisabled,
+        Self::UserRejected,
+        Self::InvalidCiphertext,
+        Self::EntropyFailure,
+        Self::WalletCorrupted,
+        Self::UnsupportedFormat,
+        Self::InvalidFirmware,
+        Self::FirmwareRollback,
+        Self::FirmwareIncomplete,
+        Self::FlashError,
+        Self::SigningIncomplete
+    ];
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        Self::ALL.get(code as usize).copied()
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        use ErrorCategory::*;
+
+        match self {
+            Self::SerialDataCorrupted | Self::SerialTxError | Self::InvalidSerialConfig => Transport,
+            Self::InvalidInstruction | Self::AccountIdxOOB | Self::BookIdxOOB
+            | Self::InvalidTransaction | Self::InvalidCiphertext
+            | Self::SigningIncomplete => Request,
+            Self::WalletNotInitialized | Self::WalletCorrupted | Self::UnsupportedFormat
+            | Self::PasscodeInUse | Self::BookFull | Self::BookEntryExists => State,
+            Self::WrongPassword | Self::UserRejected => Auth,
+            Self::RecipientNotAllowed | Self::BlindSigningDisabled => Policy,
+            Self::InvalidFirmware | Self::FirmwareRollback | Self::FirmwareIncomplete => Update,
+            Self::HalInitError | Self::I2cError | Self::EntropyFailure | Self::FlashError => Hardware,
+            Self::CryptoError => Internal
+        }
+    }
+
+    /// sent along with the code, so a host older than the code can still show it
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::HalInitError => "peripherals failed to start",
+            Self::AccountIdxOOB => "no such account",
+            Self::CryptoError => "signing failed",
+            Self::InvalidSerialConfig => "invalid serial config",
+            Self::InvalidInstruction => "unknown or malformed request",
+            Self::SerialDataCorrupted => "request damaged on the way",
+            Self::WrongPassword => "wrong passcode",
+            Self::SerialTxError => "serial port failed",
+            Self::I2cError => "keypad or display failed",
+            Self::WalletNotInitialized => "wallet not initialized",
+            Self::BookFull => "address book full",
+            Self::BookIdxOOB => "no such address book entry",
+            Self::BookEntryExists => "address already in the book",
+            Self::InvalidTransaction => "not a transaction",
+            Self::RecipientNotAllowed => "recipient not in the allowlist",
+            Self::PasscodeInUse => "passcode already in use",
+            Self::BlindSigningDisabled => "blind signing disabled",
+            Self::UserRejected => "rejected on the device",
+            Self::InvalidCiphertext => "ciphertext does not decrypt",
+            Self::EntropyFailure => "random source failed",
+            Self::WalletCorrupted => "wallet storage unrecoverable",
+            Self::UnsupportedFormat => "wallet from a newer firmware",
+            Self::InvalidFirmware => "invalid firmware image",
+            Self::FirmwareRollback => "firmware older than running",
+            Self::FirmwareIncomplete => "firmware chunk out of order",
+            Self::FlashError => "flash write failed",
+            Self::SigningIncomplete => "signing chunk out of order"
+        }
+    }
+}
+
+/// what kind of error, the host decides what to do from it
+/// (e.g. its exit code), the categories are never reordered
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// the serial link, sending the request again may help
+    Transport,
+    /// the request itself is wrong
+    Request,
+    /// the wallet is not in a state to do it
+    State,
+    /// the user did not approve it
+    Auth,
+    /// the settings of the wallet forbid it
+    Policy,
+    Update,
+    Hardware,
+    Internal
+}
+
+impl ErrorCategory {
+    const ALL: [Self; 8] = [
+        Self::Transport,
+        Self::Request,
+        Self::State,
+        Self::Auth,
+        Self::Policy,
+        Self::Update,
+        Self::Hardware,
+        Self::Internal
+    ];
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        Self::ALL.get(code as usize).copied()
+    }
+}
+
+/// the longest reason sent
+pub const MAX_REASON_LEN: usize = 32;
+
+/// an error as sent by the wallet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErrorReport {
+    pub category: ErrorCategory,
+    /// see `WalletError`, a newer wallet may send codes this version does not know
+    pub code: u8,
+    /// e.g. the account index not found, or the offset expected next
+    pub arg: Option<u32>,
+    reason: [u8; MAX_REASON_LEN],
+    reason_len: u8
+}
+
+impl ErrorReport {
+    /// the reason is cut to `MAX_REASON_LEN`, anything not printable ASCII becomes '?'
+    pub fn new(category: ErrorCategory, code: u8, arg: Option<u32>, reason: &[u8]) -> Self {
+        let len = reason.len().min(MAX_REASON_LEN);
+        let mut buf = [0; MAX_REASON_LEN];
+        buf.iter_mut().zip(&reason[..len]).for_each(|(dst, ch)| {
+            *dst = match ch {
+                b' '..=b'~' => *ch,
+                _ => b'?'
+            }
+        });
+
+        Self { category, code, arg, reason: buf, reason_len: len as u8 }
+    }
+
+    pub fn error(&self) -> Option<WalletError> {
+        WalletError::from_code(self.code)
+    }
+
+    pub fn reason(&self) -> &str {
+        // only printable ASCII is kept
+        core::str::from_utf8(&self.reason[..self.reason_len as usize]).unwrap_or("")
+    }
+
+    /// with the reason of the error
+    pub fn with_arg(error: WalletError, arg: Option<u32>) -> Self {
+        Self::new(error.category(), error as u8, arg, error.reason().as_bytes())
+    }
+
+    /// length of the report written by `write`
+    pub fn encoded_len(&self) -> usize {
+        1 + 1 + 1 + 4 + 1 + self.reason_len as usize
+    }
+
+    /// category, code, whether there is an argument, the argument (or 0),
+    /// then the length of the reason and the reason
+    pub fn write<S: Sink>(&self, sink: &mut S) -> Result<(), S::Error> {
+        sink.write(&[self.category as u8, self.code, self.arg.is_some() as u8])?;
+        sink.write(&self.arg.unwrap_or(0).to_le_bytes())?;
+        sink.write(&[self.reason_len])?;
+        sink.write(self.reason().as_bytes())
+    }
+
+    pub fn read<S: Source>(source: &mut S) -> Result<Self, DecodeError<S::Error>> {
+        let mut head = [0; 8];
+        source.read(&mut head)?;
+        let category = ErrorCategory::from_code(head[0]).ok_or(DecodeError::Malformed)?;
+        let arg = u32::from_le_bytes(head[3..7].try_into().unwrap());
+        let reason_len = head[7] as usize;
+        if head[2] > 1 || reason_len > MAX_REASON_LEN {
+            return Err(DecodeError::Malformed)
+        }
+
+        let mut reason = [0; MAX_REASON_LEN];
+        source.read(&mut reason[..reason_len])?;
+        Ok(Self::new(
+            category, head[1], (head[2] == 1).then_some(arg), &reason[..reason_len]
+        ))
+    }
+}
+
+impl From<WalletError> for ErrorReport {
+    fn from(e: WalletError) -> Self {
+        Self::with_arg(e, None)
+    }
+}
@@ -0,0 +1,170 @@
+use crc32fast::Hasher;
+
+use crate::{
+    Sink, Source, DecodeError, Reply, read_reply, write_reply,
+    MSG_MAGIC, MAX_MSG_LEN
+};
+
+/// the request frame arrived, the reply follows once it is processed
+const ACK: u8 = 0x06;
+/// the request frame was damaged, send it again
+const NAK: u8 = 0x15;
+
+/// magic, id, length, then the CRC
+pub const FRAME_OVERHEAD: usize = 1 + 1 + 4 + 4;
+/// a longer pause inside a frame drops it, the host waits longer than this
+/// before sending a frame again
+pub const FRAME_TIMEOUT_MS: u32 = 100;
+/// the rate both ends start at, and the wallet falls back to
+pub const DEFAULT_BAUD_RATE: u32 = 9600;
+/// the rates SetBaudRate switches to, fastest first
+pub const BAUD_RATES: [u32; 5] = [921_600, 460_800, 230_400, 115_200, DEFAULT_BAUD_RATE];
+/// after SetBaudRate the wallet falls back to DEFAULT_BAUD_RATE,
+/// unless a valid frame arrives at the new rate within this
+pub const BAUD_CONFIRM_MS: u32 = 2000;
+/// the longest reply is a plaintext: status, tag, length, then the plaintext
+pub const MAX_ANSWER_LEN: usize = 1 + 1 + 4 + MAX_MSG_LEN;
+
+/// what the wallet sends back for a request frame
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Answer {
+    Ack,
+    Nak,
+    Reply(Reply)
+}
+
+impl Answer {
+    fn encoded_len(&self) -> usize {
+        match self {
+            Self::Ack | Self::Nak => 1,
+            Self::Reply(Ok(resp)) => 1 + resp.encoded_len(),
+            Self::Reply(Err(report)) => 1 + report.encoded_len()
+        }
+    }
+}
+
+/// CRC-32 of a frame, over the id, the length and the payload
+pub fn frame_crc(id: u8, payload: &[u8]) -> u32 {
+    let mut crc = Hasher::new();
+    crc.update(&[id]);
+    crc.update(&(payload.len() as u32).to_le_bytes());
+    crc.update(payload);
+    crc.finalize()
+}
+
+/// frames a payload of a known length written through it
+pub struct FrameWriter<'s, S> {
+    sink: &'s mut S,
+    crc: Hasher
+}
+
+impl<'s, S: Sink> FrameWriter<'s, S> {
+    pub fn begin(sink: &'s mut S, id: u8, len: usize) -> Result<Self, S::Error> {
+        let mut head = [id, 0, 0, 0, 0];
+        head[1..].copy_from_slice(&(len as u32).to_le_bytes());
+        sink.write(&[MSG_MAGIC])?;
+        sink.write(&head)?;
+
+        let mut crc = Hasher::new();
+        crc.update(&head);
+        Ok(Self { sink, crc })
+    }
+
+    pub fn finish(self) -> Result<(), S::Error> {
+        self.sink.write(&self.crc.finalize().to_le_bytes())
+    }
+}
+
+impl<S: Sink> Sink for FrameWriter<'_, S> {
+    type Error = S::Error;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), S::Error> {
+        self.crc.update(bytes);
+        self.sink.write(bytes)
+    }
+}
+
+/// reads from a byte slice
+pub struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Source for Cursor<'_> {
+    /// there are not enough bytes left
+    type Error = ();
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), ()> {
+        if self.0.len() < buf.len() {
+            return Err(())
+        }
+        let (head, tail) = self.0.split_at(buf.len());
+        buf.copy_from_slice(head);
+        self.0 = tail;
+        Ok(())
+    }
+}
+
+/// skip to the next frame and read its payload into `buf`,
+/// returns the id and the length of the payload
+pub fn read_frame<S: Source>(
+    source: &mut S, buf: &mut [u8]
+) -> Result<(u8, usize), DecodeError<S::Error>> {
+    let mut byte = [0];
+    while byte[0] != MSG_MAGIC {
+        source.read(&mut byte)?;
+    }
+
+    let mut head = [0; 5];
+    source.read(&mut head)?;
+    let id = head[0];
+    let len = u32::from_le_bytes(head[1..].try_into().unwrap()) as usize;
+
+    let payload = buf.get_mut(..len).ok_or(DecodeError::Malformed)?;
+    source.read(payload)?;
+    let mut crc = [0; 4];
+    source.read(&mut crc)?;
+
+    match u32::from_le_bytes(crc) == frame_crc(id, payload) {
+        true => Ok((id, len)),
+        false => Err(DecodeError::Malformed)
+    }
+}
+
+pub fn write_answer<S: Sink>(sink: &mut S, id: u8, answer: &Answer) -> Result<(), S::Error> {
+    let mut frame = FrameWriter::begin(sink, id, answer.encoded_len())?;
+    match answer {
+        Answer::Ack => frame.write(&[ACK])?,
+        Answer::Nak => frame.write(&[NAK])?,
+        Answer::Reply(reply) => write_reply(reply, &mut frame)?
+    }
+    frame.finish()
+}
+
+/// read the next answer, returns the id of the request it is for
+pub fn read_answer<S: Source>(source: &mut S) -> Result<(u8, Answer), DecodeError<S::Error>> {
+    let mut buf = [0; MAX_ANSWER_LEN];
+    let (id, len) = read_frame(source, &mut buf)?;
+
+    let answer = match &buf[..len] {
+        [ACK] => Answer::Ack,
+        [NAK] => Answer::Nak,
+        payload => {
+            let mut cursor = Cursor::new(payload);
+            let reply = read_reply(&mut cursor).map_err(|_| DecodeError::Malformed)?;
+            if !cursor.is_empty() {
+                return Err(DecodeError::Malformed)
+            }
+            Answer::Reply(reply)
+        }
+    };
+    Ok((id, answer))
+}
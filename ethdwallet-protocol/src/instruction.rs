@@ -0,0 +1,264 @@
+use crate::{Sink, FrameWriter, EthAddr, LABEL_LEN, HEADER_FIELDS_LEN};
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    /// ECIES on secp256k1 with AES-128-CTR and HMAC-SHA256, as go-ethereum does
+    ///
+    /// ephemeral_pubkey(65) + iv(16) + ciphertext + hmac(32)
+    Secp256k1,
+    /// NaCl box on the key itself, used by eth_decrypt of MetaMask
+    ///
+    /// ephemeral_pubkey(32) + nonce(24) + tag(16) + ciphertext
+    X25519XSalsa20Poly1305
+}
+
+/// what happens when the duress zone is opened
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuressPolicy {
+    /// only open the decoy accounts
+    Decoy,
+    /// open the decoy accounts, then erase every other zone
+    Wipe
+}
+
+/// a bit for each instruction code of this version
+pub const INSTRUCTION_SET: u64 = (1 << 26) - 1;
+
+/// defines an instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction<'raw> {
+    /// [0, account_id, raw]
+    SignTransaction(u8, &'raw [u8]),
+    /// [1, account_id]
+    GetAddress(u8),
+    /// [2]
+    GetAddressList,
+    /// [3, label(8), addr(20)]
+    AddBookEntry([u8; LABEL_LEN], EthAddr),
+    /// [4, entry_idx]
+    RemoveBookEntry(u8),
+    /// [5, account_id, enabled]
+    SetAllowlistOnly(u8, bool),
+    /// [6]
+    GetAddressBook,
+    /// [7]
+    SetupHiddenZone,
+    /// [8]
+    Unlock,
+    /// [9, wipe]
+    SetupDuressZone(DuressPolicy),
+    /// [10, enabled]
+    SetBlindSigning(bool),
+    /// [11, account_id, hash(32)]
+    SignHash(u8, [u8; 32]),
+    /// [12, account_id, scheme, payload]
+    Decrypt(u8, Scheme, &'raw [u8]),
+    /// [13, account_id]
+    GetEncryptionPubkey(u8),
+    /// [14]
+    GetDiagnostics,
+    /// [15]
+    GetFirmwareInfo,
+    /// [16, header(80)]
+    FirmwareBegin(&'raw [u8; HEADER_FIELDS_LEN]),
+    /// [17, offset(4), data]
+    FirmwareChunk(u32, &'raw [u8]),
+    /// [18]
+    FirmwareFinish,
+    /// [19]
+    GetInfo,
+    /// [20, account_id, size(4)], a transaction too long for one request
+    SignBegin(u8, u32),
+    /// [21, offset(4), data]
+    SignChunk(u32, &'raw [u8]),
+    /// [22]
+    SignFinish,
+    /// [23, baud(4)], one of BAUD_RATES, see `BAUD_CONFIRM_MS`
+    SetBaudRate(u32),
+    /// [24, start(4)], entries from the sequence number `start` on
+    ReadAuditLog(u32),
+    /// [25], the report is cleared once sent
+    GetCrashReport
+}
+
+impl<'raw> Instruction<'raw> {
+    /// decode the content of a request, None if it is not a valid instruction
+    pub fn decode(value: &'raw [u8]) -> Option<Self> {
+        Some(match *value.first()? {
+            0 if value.len() > 2 => {
+                Self::SignTransaction(value[1], &value[2..])
+            },
+            1 if value.len() == 2 => Self::GetAddress(value[1]),
+            2 if value.len() == 1 => Self::GetAddressList,
+            3 if value.len() == 1 + LABEL_LEN + 20 => Self::AddBookEntry(
+                value[1..1 + LABEL_LEN].try_into().unwrap(),
+                value[1 + LABEL_LEN..].try_into().unwrap()
+            ),
+            4 if value.len() == 2 => Self::RemoveBookEntry(value[1]),
+            5 if value.len() == 3 => Self::SetAllowlistOnly(value[1], value[2] != 0),
+            6 if value.len() == 1 => Self::GetAddressBook,
+            7 if value.len() == 1 => Self::SetupHiddenZone,
+            8 if value.len() == 1 => Self::Unlock,
+            9 if value.len() == 2 => Self::SetupDuressZone(match value[1] {
+                0 => DuressPolicy::Decoy,
+                _ => DuressPolicy::Wipe
+            }),
+            10 if value.len() == 2 => Self::SetBlindSigning(value[1] != 0),
+            11 if value.len() == 2 + 32 => Self::SignHash(
+                value[1], value[2..].try_into().unwrap()
+            ),
+            12 if value.len() > 3 => Self::Decrypt(
+                value[1],
+                match value[2] {
+                    0 => Scheme::Secp256k1,
+                    1 => Scheme::X25519XSalsa20Poly1305,
+                    _ => return None
+                },
+                &value[3..]
+            ),
+            13 if value.len() == 2 => Self::GetEncryptionPubkey(value[1]),
+            14 if value.len() == 1 => Self::GetDiagnostics,
+            15 if value.len() == 1 => Self::GetFirmwareInfo,
+            16 if value.len() == 1 + HEADER_FIELDS_LEN => Self::FirmwareBegin(
+                value[1..].try_into().unwrap()
+            ),
+            17 if value.len() > 5 => Self::FirmwareChunk(
+                u32::from_le_bytes(value[1..5].try_into().unwrap()), &value[5..]
+            ),
+            18 if value.len() == 1 => Self::FirmwareFinish,
+            19 if value.len() == 1 => Self::GetInfo,
+            20 if value.len() == 6 => Self::SignBegin(
+                value[1], u32::from_le_bytes(value[2..].try_into().unwrap())
+            ),
+            21 if value.len() > 5 => Self::SignChunk(
+                u32::from_le_bytes(value[1..5].try_into().unwrap()), &value[5..]
+            ),
+            22 if value.len() == 1 => Self::SignFinish,
+            23 if value.len() == 5 => Self::SetBaudRate(
+                u32::from_le_bytes(value[1..].try_into().unwrap())
+            ),
+            24 if value.len() == 5 => Self::ReadAuditLog(
+                u32::from_le_bytes(value[1..].try_into().unwrap())
+            ),
+            25 if value.len() == 1 => Self::GetCrashReport,
+            _ => return None
+        })
+    }
+
+    /// the leading byte of the content
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::SignTransaction(..) => 0x00,
+            Self::GetAddress(_) => 0x01,
+            Self::GetAddressList => 0x02,
+            Self::AddBookEntry(..) => 0x03,
+            Self::RemoveBookEntry(_) => 0x04,
+            Self::SetAllowlistOnly(..) => 0x05,
+            Self::GetAddressBook => 0x06,
+            Self::SetupHiddenZone => 0x07,
+            Self::Unlock => 0x08,
+            Self::SetupDuressZone(_) => 0x09,
+            Self::SetBlindSigning(_) => 0x0a,
+            Self::SignHash(..) => 0x0b,
+            Self::Decrypt(..) => 0x0c,
+            Self::GetEncryptionPubkey(_) => 0x0d,
+            Self::GetDiagnostics => 0x0e,
+            Self::GetFirmwareInfo => 0x0f,
+            Self::FirmwareBegin(_) => 0x10,
+            Self::FirmwareChunk(..) => 0x11,
+            Self::FirmwareFinish => 0x12,
+            Self::GetInfo => 0x13,
+            Self::SignBegin(..) => 0x14,
+            Self::SignChunk(..) => 0x15,
+            Self::SignFinish => 0x16,
+            Self::SetBaudRate(_) => 0x17,
+            Self::ReadAuditLog(_) => 0x18,
+            Self::GetCrashReport => 0x19,
+        }
+    }
+
+    /// length of the payload of the request frame
+    pub fn content_len(&self) -> usize {
+        1 + match self {
+            Self::SignTransaction(_, raw) => 1 + raw.len(),
+            Self::AddBookEntry(..) => LABEL_LEN + 20,
+            Self::SetAllowlistOnly(..) => 2,
+            Self::SignHash(..) => 1 + 32,
+            Self::Decrypt(_, _, payload) => 2 + payload.len(),
+            Self::FirmwareBegin(_) => HEADER_FIELDS_LEN,
+            Self::FirmwareChunk(_, data) | Self::SignChunk(_, data) => 4 + data.len(),
+            Self::SignBegin(..) => 1 + 4,
+            Self::SetBaudRate(_) | Self::ReadAuditLog(_) => 4,
+            Self::GetAddress(_) | Self::RemoveBookEntry(_) | Self::SetupDuressZone(_)
+            | Self::SetBlindSigning(_) | Self::GetEncryptionPubkey(_) => 1,
+            Self::GetAddressList | Self::GetAddressBook | Self::SetupHiddenZone
+            | Self::Unlock | Self::GetDiagnostics | Self::GetFirmwareInfo
+            | Self::FirmwareFinish | Self::GetInfo | Self::SignFinish
+            | Self::GetCrashReport => 0
+        }
+    }
+
+    /// write the request frame, the wallet echoes `id` in its answers
+    pub fn write_request<S: Sink>(&self, id: u8, sink: &mut S) -> Result<(), S::Error> {
+        let mut frame = FrameWriter::begin(sink, id, self.content_len())?;
+        let sink = &mut frame;
+        sink.write(&[self.code()])?;
+
+        match self {
+            Self::SignTransaction(idx, raw) => {
+                sink.write(&[*idx])?;
+                sink.write(raw)?;
+            },
+            Self::GetAddress(idx)
+            | Self::RemoveBookEntry(idx)
+            | Self::GetEncryptionPubkey(idx) => {
+                sink.write(&[*idx])?;
+            },
+            Self::AddBookEntry(label, addr) => {
+                sink.write(label)?;
+                sink.write(addr)?;
+            },
+            Self::SetAllowlistOnly(idx, enabled) => {
+                sink.write(&[*idx, *enabled as u8])?;
+            },
+            Self::SetupDuressZone(policy) => {
+                sink.write(&[*policy as u8])?;
+            },
+            Self::SetBlindSigning(enabled) => {
+                sink.write(&[*enabled as u8])?;
+            },
+            Self::SignHash(idx, hash) => {
+                sink.write(&[*idx])?;
+                sink.write(hash)?;
+            },
+            Self::Decrypt(idx, scheme, payload) => {
+                sink.write(&[*idx, *scheme as u8])?;
+                sink.write(payload)?;
+            },
+            Self::FirmwareBegin(header) => {
+                sink.write(*header)?;
+            },
+            Self::FirmwareChunk(offset, data) | Self::SignChunk(offset, data) => {
+                sink.write(&offset.to_le_bytes())?;
+                sink.write(data)?;
+            },
+            Self::SignBegin(idx, size) => {
+                sink.write(&[*idx])?;
+                sink.write(&size.to_le_bytes())?;
+            },
+            Self::SetBaudRate(baud) => {
+                sink.write(&baud.to_le_bytes())?;
+            },
+            Self::ReadAuditLog(start) => {
+                sink.write(&start.to_le_bytes())?;
+            },
+            Self::GetAddressList | Self::GetAddressBook | Self::SetupHiddenZone
+            | Self::Unlock | Self::GetDiagnostics | Self::GetFirmwareInfo
+            | Self::FirmwareFinish | Self::GetInfo | Self::SignFinish
+            | Self::GetCrashReport => {}
+        }
+        frame.finish()
+    }
+}
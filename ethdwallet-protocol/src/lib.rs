@@ -0,0 +1,77 @@
+// the serial protocol between the wallet and the host, shared by the firmware and the CLI
+//
+// both directions send frames:
+// MSG_MAGIC(1 byte) + ID(1 byte) + LEN(4 bytes) + PAYLOAD(LEN bytes) + CRC-32(4 bytes),
+// integers are little endian and the CRC covers ID, LEN and PAYLOAD.
+//
+// the payload of a request is an instruction, its first byte is the code.
+// the wallet answers every request frame with ACK or NAK, then a reply echoing the id:
+// 0x00 + response tag + response, or 0xff + error report
+#![no_std]
+
+mod audit;
+mod crash;
+mod error;
+mod frame;
+mod instruction;
+mod response;
+
+pub use audit::{AuditEntry, AuditOp, AUDIT_ENTRY_LEN, AUDIT_PAGE_LEN};
+pub use crash::{CrashReport, CrashCause, CRASH_MSG_LEN, CRASH_REPORT_LEN};
+pub use error::{WalletError, ErrorCategory, ErrorReport, MAX_REASON_LEN};
+pub use instruction::{Instruction, Scheme, DuressPolicy, INSTRUCTION_SET};
+pub use response::{
+    Response, Reply, Signature, BookEntry, Diagnostics, Info, read_reply, write_reply
+};
+pub use frame::{
+    Answer, FrameWriter, Cursor, frame_crc, read_frame, read_answer, write_answer,
+    FRAME_OVERHEAD, FRAME_TIMEOUT_MS, MAX_ANSWER_LEN,
+    DEFAULT_BAUD_RATE, BAUD_RATES, BAUD_CONFIRM_MS
+};
+
+/// raised when a change breaks the hosts or the wallets of the last version
+pub const PROTOCOL_VERSION: u16 = 2;
+
+pub const MSG_MAGIC: u8 = 0xff;
+pub const MAX_MSG_LEN: usize = 1024;
+
+pub const ACCOUNT_NUM: usize = 32;
+/// max number of recipients in the address book
+pub const BOOK_SIZE: usize = 16;
+/// a label fills exactly the 8 digits of the segment display
+pub const LABEL_LEN: usize = 8;
+/// the signed fields of a firmware image header: 
+/// magic, version, slot, padding, size, then the signature
+pub const HEADER_FIELDS_LEN: usize = 4 + 4 + 1 + 3 + 4 + 64;
+
+pub type EthAddr = [u8; 20];
+/// uncompressed secp256k1 public key, without the 0x04 prefix
+pub type PubKey = [u8; 64];
+
+/// where encoded bytes go, e.g. the serial port
+pub trait Sink {
+    type Error;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// where bytes to decode come from, `read` fills the whole buffer or fails
+pub trait Source {
+    type Error;
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError<E> {
+    /// the source failed
+    Io(E),
+    /// the bytes do not follow the protocol
+    Malformed
+}
+
+impl<E> From<E> for DecodeError<E> {
+    fn from(e: E) -> Self {
+        Self::Io(e)
+    }
+}
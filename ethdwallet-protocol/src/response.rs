@@ -0,0 +1,356 @@
+use crate::{
+    Sink, Source, DecodeError, ErrorReport, AuditEntry, AUDIT_ENTRY_LEN, AUDIT_PAGE_LEN,
+    CrashReport, CRASH_REPORT_LEN,
+    EthAddr, PubKey, ACCOUNT_NUM, BOOK_SIZE, LABEL_LEN, MAX_MSG_LEN, PROTOCOL_VERSION
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Signature {
+    pub r: [u8; 32],
+    pub s: [u8; 32],
+    pub v: u8
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BookEntry {
+    pub used: bool,
+    pub label: [u8; LABEL_LEN],
+    pub addr: EthAddr
+}
+
+/// how the wallet recovered from its flash
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Diagnostics {
+    pub status: u8,
+    /// index of the slot read from
+    pub slot: u8,
+    pub seq: u32,
+    /// format version of the record
+    pub version: u16,
+    /// copies passing their CRC
+    pub valid: u8,
+    /// copies equal to the one in use
+    pub agreeing: u8,
+    /// copies kept in each slot
+    pub copies: u8
+}
+
+/// what the wallet runs and what it supports
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Info {
+    pub protocol_version: u16,
+    pub firmware_version: u32,
+    /// commit the firmware is built from, zero if unknown
+    pub git_hash: [u8; 20],
+    pub account_num: u8,
+    pub max_msg_len: u32,
+    /// a bit for each instruction code, see `INSTRUCTION_SET`
+    pub instructions: u64,
+    pub initialized: bool,
+    /// the passcode is entered, the cipher is filled
+    pub unlocked: bool,
+    /// unique id of the MCU, zero if it has none
+    pub uid: [u8; 12]
+}
+
+impl Info {
+    /// the wallet speaks this version of the protocol
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+            && self.account_num as usize == ACCOUNT_NUM
+            && self.max_msg_len as usize == MAX_MSG_LEN
+    }
+
+    pub fn supports(&self, code: u8) -> bool {
+        code < 64 && self.instructions & (1 << code) != 0
+    }
+}
+
+// the firmware answers one request at a time, the size does not add up
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Response {
+    Signature(Signature),
+    Address((EthAddr, PubKey)),
+    AddressList([EthAddr; ACCOUNT_NUM]),
+    Done,
+    AddressBook([BookEntry; BOOK_SIZE]),
+    /// (buffer, length)
+    Plaintext([u8; MAX_MSG_LEN], usize),
+    EncryptionPubkey([u8; 32]),
+    Diagnostics(Diagnostics),
+    /// (running slot, version)
+    FirmwareInfo(u8, u32),
+    Info(Info),
+    /// (entries, count), the entries are in order and the page is full
+    /// unless the log ends
+    AuditLog([AuditEntry; AUDIT_PAGE_LEN], usize),
+    /// None if the wallet did not crash since the last read
+    CrashReport(Option<CrashReport>)
+}
+
+/// what the wallet answers to a request
+pub type Reply = Result<Response, ErrorReport>;
+
+impl Response {
+    /// the leading byte of a response
+    pub fn tag(&self) -> u8 {
+        match self {
+            Self::Signature(_) => 0x00,
+            Self::Address(_) => 0x01,
+            Self::AddressList(_) => 0x02,
+            Self::Done => 0x03,
+            Self::AddressBook(_) => 0x04,
+            Self::Plaintext(..) => 0x05,
+            Self::EncryptionPubkey(_) => 0x06,
+            Self::Diagnostics(_) => 0x07,
+            Self::FirmwareInfo(..) => 0x08,
+            Self::Info(_) => 0x09,
+            Self::AuditLog(..) => 0x0a,
+            Self::CrashReport(_) => 0x0b,
+        }
+    }
+
+    /// length of the response written by `write`, with the tag
+    pub fn encoded_len(&self) -> usize {
+        1 + match self {
+            Self::Signature(_) => 32 + 32 + 1,
+            Self::Address(_) => 20 + 64,
+            Self::AddressList(_) => 20 * ACCOUNT_NUM,
+            Self::Done => 0,
+            Self::AddressBook(_) => (1 + LABEL_LEN + 20) * BOOK_SIZE,
+            Self::Plaintext(_, len) => 4 + len,
+            Self::EncryptionPubkey(_) => 32,
+            Self::Diagnostics(_) => 11,
+            Self::FirmwareInfo(..) => 1 + 4,
+            Self::Info(_) => 2 + 4 + 20 + 1 + 4 + 8 + 1 + 1 + 12,
+            Self::AuditLog(_, count) => 1 + AUDIT_ENTRY_LEN * count,
+            Self::CrashReport(report) => 1 + report.map_or(0, |_| CRASH_REPORT_LEN),
+        }
+    }
+
+    pub fn write<S: Sink>(&self, sink: &mut S) -> Result<(), S::Error> {
+        sink.write(&[self.tag()])?;
+
+        match self {
+            Self::Signature(Signature { r, s, v }) => {
+                sink.write(r)?;
+                sink.write(s)?;
+                sink.write(&[*v])?;
+            },
+            Self::Address((addr, pubkey)) => {
+                sink.write(addr)?;
+                sink.write(pubkey)?;
+            },
+            Self::AddressList(list) => {
+                list.iter().try_for_each(|addr| sink.write(addr))?;
+            },
+            Self::Done => {},
+            Self::AddressBook(entries) => {
+                entries.iter().try_for_each(|entry| {
+                    sink.write(&[entry.used as u8])?;
+                    sink.write(&entry.label)?;
+                    sink.write(&entry.addr)
+                })?;
+            },
+            Self::Plaintext(buf, len) => {
+                sink.write(&(*len as u32).to_le_bytes())?;
+                sink.write(&buf[..*len])?;
+            },
+            Self::EncryptionPubkey(pubkey) => {
+                sink.write(pubkey)?;
+            },
+            Self::Diagnostics(diag) => {
+                sink.write(&[diag.status, diag.slot])?;
+                sink.write(&diag.seq.to_le_bytes())?;
+                sink.write(&diag.version.to_le_bytes())?;
+                sink.write(&[diag.valid, diag.agreeing, diag.copies])?;
+            },
+            Self::FirmwareInfo(slot, version) => {
+                sink.write(&[*slot])?;
+                sink.write(&version.to_le_bytes())?;
+            },
+            Self::Info(info) => {
+                sink.write(&info.protocol_version.to_le_bytes())?;
+                sink.write(&info.firmware_version.to_le_bytes())?;
+                sink.write(&info.git_hash)?;
+                sink.write(&[info.account_num])?;
+                sink.write(&info.max_msg_len.to_le_bytes())?;
+                sink.write(&info.instructions.to_le_bytes())?;
+                sink.write(&[info.initialized as u8, info.unlocked as u8])?;
+                sink.write(&info.uid)?;
+            },
+            Self::AuditLog(entries, count) => {
+                sink.write(&[*count as u8])?;
+                entries[..*count].iter().try_for_each(|entry| sink.write(&entry.encode()))?;
+            },
+            Self::CrashReport(report) => {
+                sink.write(&[report.is_some() as u8])?;
+                if let Some(report) = report {
+                    sink.write(&report.encode())?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    pub fn read<S: Source>(source: &mut S) -> Result<Self, DecodeError<S::Error>> {
+        let mut tag = [0];
+        source.read(&mut tag)?;
+
+        Ok(match tag[0] {
+            0x00 => {
+                let mut r = [0; 32];
+                let mut s = [0; 32];
+                let mut v = [0];
+                source.read(&mut r)?;
+                source.read(&mut s)?;
+                source.read(&mut v)?;
+
+                Self::Signature(Signature { r, s, v: v[0] })
+            },
+            0x01 => {
+                let mut addr = [0; 20];
+                let mut pubkey = [0; 64];
+                source.read(&mut addr)?;
+                source.read(&mut pubkey)?;
+
+                Self::Address((addr, pubkey))
+            },
+            0x02 => {
+                let mut addrs = [[0; 20]; ACCOUNT_NUM];
+                addrs.iter_mut().try_for_each(|addr| source.read(addr))?;
+
+                Self::AddressList(addrs)
+            },
+            0x03 => Self::Done,
+            0x04 => {
+                let mut entries = [BookEntry {
+                    used: false,
+                    label: [0; LABEL_LEN],
+                    addr: [0; 20]
+                }; BOOK_SIZE];
+                entries.iter_mut().try_for_each(|entry| {
+                    let mut used = [0];
+                    source.read(&mut used)?;
+                    source.read(&mut entry.label)?;
+                    source.read(&mut entry.addr)?;
+                    entry.used = used[0] != 0;
+                    Ok::<(), S::Error>(())
+                })?;
+
+                Self::AddressBook(entries)
+            },
+            0x05 => {
+                let mut len = [0; 4];
+                source.read(&mut len)?;
+                let len = u32::from_le_bytes(len) as usize;
+                if len > MAX_MSG_LEN {
+                    return Err(DecodeError::Malformed)
+                }
+                let mut buf = [0; MAX_MSG_LEN];
+                source.read(&mut buf[..len])?;
+
+                Self::Plaintext(buf, len)
+            },
+            0x06 => {
+                let mut pubkey = [0; 32];
+                source.read(&mut pubkey)?;
+
+                Self::EncryptionPubkey(pubkey)
+            },
+            0x07 => {
+                let mut diag = [0; 11];
+                source.read(&mut diag)?;
+
+                Self::Diagnostics(Diagnostics {
+                    status: diag[0],
+                    slot: diag[1],
+                    seq: u32::from_le_bytes(diag[2..6].try_into().unwrap()),
+                    version: u16::from_le_bytes(diag[6..8].try_into().unwrap()),
+                    valid: diag[8],
+                    agreeing: diag[9],
+                    copies: diag[10]
+                })
+            },
+            0x08 => {
+                let mut info = [0; 5];
+                source.read(&mut info)?;
+
+                Self::FirmwareInfo(info[0], u32::from_le_bytes(info[1..].try_into().unwrap()))
+            },
+            0x09 => {
+                let mut info = [0; 53];
+                source.read(&mut info)?;
+
+                Self::Info(Info {
+                    protocol_version: u16::from_le_bytes(info[0..2].try_into().unwrap()),
+                    firmware_version: u32::from_le_bytes(info[2..6].try_into().unwrap()),
+                    git_hash: info[6..26].try_into().unwrap(),
+                    account_num: info[26],
+                    max_msg_len: u32::from_le_bytes(info[27..31].try_into().unwrap()),
+                    instructions: u64::from_le_bytes(info[31..39].try_into().unwrap()),
+                    initialized: info[39] != 0,
+                    unlocked: info[40] != 0,
+                    uid: info[41..].try_into().unwrap()
+                })
+            },
+            0x0a => {
+                let mut count = [0];
+                source.read(&mut count)?;
+                let count = count[0] as usize;
+                if count > AUDIT_PAGE_LEN {
+                    return Err(DecodeError::Malformed)
+                }
+                let mut entries = [AuditEntry::EMPTY; AUDIT_PAGE_LEN];
+                entries[..count].iter_mut().try_for_each(|entry| {
+                    let mut bytes = [0; AUDIT_ENTRY_LEN];
+                    source.read(&mut bytes)?;
+                    *entry = AuditEntry::decode(&bytes).ok_or(DecodeError::Malformed)?;
+                    Ok::<(), DecodeError<S::Error>>(())
+                })?;
+
+                Self::AuditLog(entries, count)
+            },
+            0x0b => {
+                let mut present = [0];
+                source.read(&mut present)?;
+                Self::CrashReport(match present[0] {
+                    0 => None,
+                    1 => {
+                        let mut bytes = [0; CRASH_REPORT_LEN];
+                        source.read(&mut bytes)?;
+                        Some(CrashReport::decode(&bytes).ok_or(DecodeError::Malformed)?)
+                    },
+                    _ => return Err(DecodeError::Malformed)
+                })
+            },
+            _ => return Err(DecodeError::Malformed)
+        })
+    }
+}
+
+/// 0x00 and the response, or 0xff and the error report
+pub fn write_reply<S: Sink>(reply: &Reply, sink: &mut S) -> Result<(), S::Error> {
+    match reply {
+        Ok(resp) => {
+            sink.write(&[0x00])?;
+            resp.write(sink)
+        },
+        Err(report) => {
+            sink.write(&[0xff])?;
+            report.write(sink)
+        }
+    }
+}
+
+pub fn read_reply<S: Source>(source: &mut S) -> Result<Reply, DecodeError<S::Error>> {
+    let mut state = [0];
+    source.read(&mut state)?;
+
+    match state[0] {
+        0x00 => Response::read(source).map(Ok),
+        0xff => ErrorReport::read(source).map(Err),
+        _ => Err(DecodeError::Malformed)
+    }
+}
@@ -0,0 +1,325 @@
+use ethdwallet_protocol::*;
+
+struct Bytes(Vec<u8>);
+
+impl Sink for Bytes {
+    type Error = ();
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        self.0.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+fn round_trip_instruction(instr: Instruction) {
+    let mut bytes = Bytes(Vec::new());
+    instr.write_request(0x42, &mut bytes).unwrap();
+    let msg = bytes.0;
+
+    assert_eq!(msg[0], MSG_MAGIC);
+    assert!(INSTRUCTION_SET & (1 << instr.code()) != 0);
+    assert_eq!(msg.len(), FRAME_OVERHEAD + instr.content_len());
+
+    let mut buf = [0; MAX_MSG_LEN];
+    let mut cursor = Cursor::new(&msg);
+    let (id, len) = read_frame(&mut cursor, &mut buf).unwrap();
+    assert!(cursor.is_empty());
+    assert_eq!(id, 0x42);
+    assert_eq!(Instruction::decode(&buf[..len]), Some(instr));
+}
+
+fn round_trip_reply(reply: Reply) {
+    let mut bytes = Bytes(Vec::new());
+    write_reply(&reply, &mut bytes).unwrap();
+
+    let mut cursor = Cursor::new(&bytes.0);
+    assert_eq!(read_reply(&mut cursor), Ok(reply));
+    assert!(cursor.is_empty());
+
+    round_trip_answer(Answer::Reply(reply));
+}
+
+fn answer_frame(id: u8, answer: &Answer) -> Vec<u8> {
+    let mut bytes = Bytes(Vec::new());
+    write_answer(&mut bytes, id, answer).unwrap();
+    bytes.0
+}
+
+fn round_trip_answer(answer: Answer) {
+    let frame = answer_frame(7, &answer);
+    let mut cursor = Cursor::new(&frame);
+    assert_eq!(read_answer(&mut cursor), Ok((7, answer)));
+    assert!(cursor.is_empty());
+}
+
+#[test]
+fn instructions() {
+    let header = [0x5a; HEADER_FIELDS_LEN];
+    [
+        Instruction::SignTransaction(3, &[0xc0, 0x01, 0x02]),
+        Instruction::GetAddress(31),
+        Instruction::GetAddressList,
+        Instruction::AddBookEntry(*b"ALICE\0\0\0", [0x11; 20]),
+        Instruction::RemoveBookEntry(15),
+        Instruction::SetAllowlistOnly(2, true),
+        Instruction::SetAllowlistOnly(2, false),
+        Instruction::GetAddressBook,
+        Instruction::SetupHiddenZone,
+        Instruction::Unlock,
+        Instruction::SetupDuressZone(DuressPolicy::Decoy),
+        Instruction::SetupDuressZone(DuressPolicy::Wipe),
+        Instruction::SetBlindSigning(true),
+        Instruction::SignHash(0, [0xab; 32]),
+        Instruction::Decrypt(1, Scheme::Secp256k1, &[1, 2, 3]),
+        Instruction::Decrypt(1, Scheme::X25519XSalsa20Poly1305, &[4; 100]),
+        Instruction::GetEncryptionPubkey(7),
+        Instruction::GetDiagnostics,
+        Instruction::GetFirmwareInfo,
+        Instruction::FirmwareBegin(&header),
+        Instruction::FirmwareChunk(0x200, &[0xee; 512]),
+        Instruction::FirmwareFinish,
+        Instruction::GetInfo,
+        Instruction::SignBegin(4, 100_000),
+        Instruction::SignChunk(0x3fb, &[0x99; 1000]),
+        Instruction::SignFinish,
+        Instruction::SetBaudRate(921_600),
+        Instruction::ReadAuditLog(0x0102_0304),
+        Instruction::GetCrashReport
+    ].into_iter().for_each(round_trip_instruction);
+}
+
+#[test]
+fn invalid_instructions() {
+    assert_eq!(Instruction::decode(&[]), None);
+    // unknown instruction
+    assert_eq!(Instruction::decode(&[0x7f]), None);
+    // wrong length
+    assert_eq!(Instruction::decode(&[0x01]), None);
+    assert_eq!(Instruction::decode(&[0x02, 0x00]), None);
+    assert_eq!(Instruction::decode(&[0x0b, 0x00, 0x01]), None);
+    // unknown scheme
+    assert_eq!(Instruction::decode(&[0x0c, 0x00, 0x02, 0x00]), None);
+}
+
+#[test]
+fn responses() {
+    let mut plaintext = [0; MAX_MSG_LEN];
+    plaintext[..5].copy_from_slice(b"hello");
+    let mut book = [BookEntry { used: false, label: [0; LABEL_LEN], addr: [0; 20] }; BOOK_SIZE];
+    book[3] = BookEntry { used: true, label: *b"BOB\0\0\0\0\0", addr: [0x22; 20] };
+    let mut addrs = [[0; 20]; ACCOUNT_NUM];
+    addrs.iter_mut().enumerate().for_each(|(idx, addr)| addr.fill(idx as u8));
+    let mut log = [AuditEntry::EMPTY; AUDIT_PAGE_LEN];
+    log[0] = AuditEntry {
+        seq: 41, op: AuditOp::SignHash, account: 3, result: None,
+        digest: [0x66; 32], prev: [0x88; 32]
+    };
+    log[1] = AuditEntry {
+        seq: 42, op: AuditOp::SignTransaction, account: 31,
+        result: Some(WalletError::UserRejected), digest: [0x99; 32], prev: [0xaa; 32]
+    };
+    let mut panic = CrashReport::new(CrashCause::Panic);
+    panic.reset_flags = 0x14;
+    panic.push_message(b"src/signing.rs:42: attempt to add with overflow");
+    let mut fault = CrashReport::new(CrashCause::HardFault);
+    fault.reset_flags = 0x14;
+    (fault.pc, fault.lr, fault.xpsr) = (0x0800_1234, 0xffff_fff9, 0x6100_0003);
+    (fault.cfsr, fault.hfsr, fault.mmfar, fault.bfar) =
+        (0x0000_8200, 0x4000_0000, 0xe000_ed34, 0x2002_0000);
+
+    [
+        Response::Signature(Signature { r: [1; 32], s: [2; 32], v: 1 }),
+        Response::Address(([0x33; 20], [0x44; 64])),
+        Response::AddressList(addrs),
+        Response::Done,
+        Response::AddressBook(book),
+        Response::Plaintext(plaintext, 5),
+        Response::Plaintext([0; MAX_MSG_LEN], 0),
+        Response::EncryptionPubkey([0x55; 32]),
+        Response::Diagnostics(Diagnostics {
+            status: 1, slot: 1, seq: 0x01020304, version: 2, valid: 6, agreeing: 5, copies: 7
+        }),
+        Response::FirmwareInfo(1, 42),
+        Response::Info(Info {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: 3,
+            git_hash: [0xde; 20],
+            account_num: ACCOUNT_NUM as u8,
+            max_msg_len: MAX_MSG_LEN as u32,
+            instructions: INSTRUCTION_SET,
+            initialized: true,
+            unlocked: false,
+            uid: [0x77; 12]
+        }),
+        Response::AuditLog(log, 2),
+        Response::AuditLog([AuditEntry::EMPTY; AUDIT_PAGE_LEN], 0),
+        Response::CrashReport(Some(panic)),
+        Response::CrashReport(Some(fault)),
+        Response::CrashReport(Some(CrashReport::new(CrashCause::SoftWatchdog))),
+        Response::CrashReport(None)
+    ].into_iter().map(Ok).for_each(round_trip_reply);
+}
+
+#[test]
+fn errors() {
+    (0..=u8::MAX).filter_map(WalletError::from_code).for_each(|e| {
+        let report = ErrorReport::from(e);
+        assert_eq!(report.error(), Some(e));
+        assert_eq!(report.category, e.category());
+        assert!(!report.reason().is_empty() && report.reason().len() <= MAX_REASON_LEN);
+        round_trip_reply(Err(report));
+    });
+    round_trip_reply(Err(ErrorReport::with_arg(WalletError::AccountIdxOOB, Some(40))));
+    round_trip_reply(Err(ErrorReport::with_arg(WalletError::SigningIncomplete, Some(0))));
+    assert_eq!(WalletError::from_code(WalletError::FlashError as u8), Some(WalletError::FlashError));
+}
+
+#[test]
+fn unknown_errors() {
+    // a code from a newer wallet still has its category and reason
+    let report = ErrorReport::new(ErrorCategory::Policy, 0xfe, Some(7), b"too late today");
+    round_trip_reply(Err(report));
+    assert_eq!(report.error(), None);
+    assert_eq!(report.reason(), "too late today");
+
+    // reasons are short printable ASCII
+    let report = ErrorReport::new(ErrorCategory::Internal, 0, None, &[b'x'; 100]);
+    assert_eq!(report.reason().len(), MAX_REASON_LEN);
+    let report = ErrorReport::new(ErrorCategory::Internal, 0, None, "caf\u{e9}\n".as_bytes());
+    assert_eq!(report.reason(), "caf???");
+}
+
+#[test]
+fn crash_messages() {
+    // cut to the buffer, printable ASCII only
+    let mut report = CrashReport::new(CrashCause::Panic);
+    report.push_message(b"src/main.rs:1: ");
+    report.push_message("caf\u{e9}\n".as_bytes());
+    assert_eq!(report.message(), "src/main.rs:1: caf???");
+    report.push_message(&[b'x'; 200]);
+    assert_eq!(report.message().len(), CRASH_MSG_LEN);
+    assert_eq!(CrashReport::decode(&report.encode()), Some(report));
+    // the causes are never renumbered
+    assert_eq!(CrashCause::Panic as u8, 0);
+    assert_eq!(CrashCause::SoftWatchdog as u8, 3);
+    assert_eq!(CrashCause::from_code(4), None);
+}
+
+#[test]
+fn error_codes_are_stable() {
+    // the codes are what older hosts know, never renumber them
+    assert_eq!(WalletError::HalInitError as u8, 0);
+    assert_eq!(WalletError::SerialTxError as u8, 7);
+    assert_eq!(WalletError::I2cError as u8, 8);
+    assert_eq!(WalletError::WalletNotInitialized as u8, 9);
+    assert_eq!(WalletError::FlashError as u8, 25);
+    assert_eq!(WalletError::SigningIncomplete as u8, 26);
+    (0..=u8::MAX).for_each(|code| {
+        if let Some(e) = WalletError::from_code(code) {
+            assert_eq!(e as u8, code);
+        }
+        if let Some(category) = ErrorCategory::from_code(code) {
+            assert_eq!(category as u8, code);
+        }
+    });
+    assert_eq!(ErrorCategory::Transport as u8, 0);
+    assert_eq!(ErrorCategory::Internal as u8, 7);
+}
+
+#[test]
+fn malformed_replies() {
+    // unknown state
+    assert_eq!(read_reply(&mut Cursor::new(&[0x01])), Err(DecodeError::Malformed));
+    // unknown response
+    assert_eq!(read_reply(&mut Cursor::new(&[0x00, 0x7f])), Err(DecodeError::Malformed));
+    // plaintext longer than a message
+    let mut bytes = vec![0x00, 0x05];
+    bytes.extend(((MAX_MSG_LEN + 1) as u32).to_le_bytes());
+    assert_eq!(read_reply(&mut Cursor::new(&bytes)), Err(DecodeError::Malformed));
+    // more audit entries than a page
+    let bytes = [0x00, 0x0a, AUDIT_PAGE_LEN as u8 + 1];
+    assert_eq!(read_reply(&mut Cursor::new(&bytes)), Err(DecodeError::Malformed));
+    // audit entry of an unknown operation
+    let mut bytes = vec![0x00, 0x0a, 1];
+    bytes.extend([0x02; AUDIT_ENTRY_LEN]);
+    assert_eq!(read_reply(&mut Cursor::new(&bytes)), Err(DecodeError::Malformed));
+    // crash report of an unknown cause, or with a message too long
+    let mut bytes = vec![0x00, 0x0b, 1, 0x04];
+    bytes.extend([0x00; CRASH_REPORT_LEN - 1]);
+    assert_eq!(read_reply(&mut Cursor::new(&bytes)), Err(DecodeError::Malformed));
+    let mut bytes = vec![0x00, 0x0b, 1, 0x00, 0x00, CRASH_MSG_LEN as u8 + 1];
+    bytes.extend([0x00; CRASH_REPORT_LEN - 3]);
+    assert_eq!(read_reply(&mut Cursor::new(&bytes)), Err(DecodeError::Malformed));
+    // truncated
+    assert_eq!(read_reply(&mut Cursor::new(&[0x00, 0x06, 0x00])), Err(DecodeError::Io(())));
+    // unknown category
+    let report = [0xff, 0x7f, 0x00, 0x00, 0, 0, 0, 0, 0x00];
+    assert_eq!(read_reply(&mut Cursor::new(&report)), Err(DecodeError::Malformed));
+    // reason longer than allowed
+    let mut report = vec![0xff, 0x01, 0x04, 0x00, 0, 0, 0, 0, MAX_REASON_LEN as u8 + 1];
+    report.extend([b'a'; MAX_REASON_LEN + 1]);
+    assert_eq!(read_reply(&mut Cursor::new(&report)), Err(DecodeError::Malformed));
+}
+
+#[test]
+fn acknowledgements() {
+    round_trip_answer(Answer::Ack);
+    round_trip_answer(Answer::Nak);
+}
+
+#[test]
+fn damaged_frames() {
+    let frame = answer_frame(3, &Answer::Reply(Ok(Response::FirmwareInfo(1, 42))));
+
+    // any flipped bit fails the CRC
+    (0..frame.len() * 8).skip(8).for_each(|bit| {
+        let mut damaged = frame.clone();
+        damaged[bit / 8] ^= 1 << (bit % 8);
+        assert!(read_answer(&mut Cursor::new(&damaged)).is_err(), "bit {}", bit);
+    });
+
+    // a dropped byte as well
+    (1..frame.len()).for_each(|idx| {
+        let mut damaged = frame.clone();
+        damaged.remove(idx);
+        assert!(read_answer(&mut Cursor::new(&damaged)).is_err(), "byte {}", idx);
+    });
+}
+
+#[test]
+fn resync_after_garbage() {
+    let mut bytes = vec![0x00, 0x13, 0x37];
+    bytes.extend(answer_frame(9, &Answer::Ack));
+    bytes.extend(answer_frame(9, &Answer::Reply(Ok(Response::Done))));
+
+    let mut cursor = Cursor::new(&bytes);
+    assert_eq!(read_answer(&mut cursor), Ok((9, Answer::Ack)));
+    assert_eq!(read_answer(&mut cursor), Ok((9, Answer::Reply(Ok(Response::Done)))));
+    assert!(cursor.is_empty());
+}
+
+#[test]
+fn oversized_frames() {
+    let mut frame = vec![MSG_MAGIC, 1];
+    frame.extend(((MAX_ANSWER_LEN + 1) as u32).to_le_bytes());
+    assert_eq!(read_answer(&mut Cursor::new(&frame)), Err(DecodeError::Malformed));
+}
+
+#[test]
+fn instruction_set() {
+    let info = Info {
+        protocol_version: PROTOCOL_VERSION,
+        firmware_version: 0,
+        git_hash: [0; 20],
+        account_num: ACCOUNT_NUM as u8,
+        max_msg_len: MAX_MSG_LEN as u32,
+        instructions: INSTRUCTION_SET,
+        initialized: false,
+        unlocked: false,
+        uid: [0; 12]
+    };
+    assert!(info.is_compatible());
+    assert!(info.supports(Instruction::GetInfo.code()));
+    assert!(!info.supports(0xff));
+    assert!(!Info { protocol_version: PROTOCOL_VERSION + 1, ..info }.is_compatible());
+}
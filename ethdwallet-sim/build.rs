@@ -0,0 +1,11 @@
+use std::process::Command;
+
+fn main() {
+    // the commit is reported by GetInfo, left out if git is missing
+    let head = Command::new("git").args(["rev-parse", "HEAD"]).output().ok();
+    if let Some(head) = head.filter(|out| out.status.success()) {
+        println!("cargo:rustc-env=GIT_HASH={}", String::from_utf8_lossy(&head.stdout).trim());
+    }
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+}
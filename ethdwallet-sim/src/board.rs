@@ -0,0 +1,322 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write, Seek, SeekFrom, BufRead, BufReader},
+    mem::size_of,
+    os::unix::io::{FromRawFd, AsRawFd},
+    path::PathBuf,
+    ptr::null_mut,
+    sync::{OnceLock, atomic::{AtomicPtr, Ordering}},
+    thread, time::{Duration, Instant}
+};
+
+use ethdwallet_protocol::CrashReport;
+use nix::{
+    poll::{poll, PollFd, PollFlags},
+    pty::openpty, sys::termios, unistd::ttyname
+};
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use rand_core::OsRng;
+
+use crate::{
+    device::Device,
+    error::{Error, Result},
+    hal::{WalletStorage, AuditStorage, FirmwareStorage, KeyInput, Display, Transport, EntropySource, System},
+    input::{MsgBuffer, MsgBufferState, FIXED_KEY_LEN},
+    display::{Screen, glyph, draw},
+    entropy::Trng,
+    firmware::HEADER_FIELDS_LEN,
+    wallet::storage::{Slot, SLOT_NUM},
+    audit::{LogSector, LOG_SECTOR_NUM}
+};
+
+/// the wallet slots, loaded from the flash file
+static SLOTS: AtomicPtr<Slot> = AtomicPtr::new(null_mut());
+/// the audit log sectors, after the slots in the flash file
+static LOG: AtomicPtr<LogSector> = AtomicPtr::new(null_mut());
+const LOG_OFFSET: usize = SLOT_NUM * size_of::<Slot>();
+
+/// when the simulator started, see `System::millis`
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// load the flash file and open a pseudo-terminal for the host,
+/// returns the path of the terminal along with the device
+pub fn init(flash: &str, iwdg: Duration) -> io::Result<(PathBuf, Device<Board>)> {
+    let mut file = OpenOptions::new()
+        .read(true).write(true).create(true).truncate(false)
+        .open(flash)?;
+    // a new file reads as erased sectors
+    let mut bytes = vec![0xff; LOG_OFFSET + LOG_SECTOR_NUM * size_of::<LogSector>()];
+    let mut loaded = Vec::new();
+    file.read_to_end(&mut loaded)?;
+    let len = loaded.len().min(bytes.len());
+    bytes[..len].copy_from_slice(&loaded[..len]);
+    file.set_len(bytes.len() as u64)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&bytes)?;
+
+    SLOTS.store(load(&bytes[..LOG_OFFSET]), Ordering::SeqCst);
+    LOG.store(load(&bytes[LOG_OFFSET..]), Ordering::SeqCst);
+
+    let pty = openpty(None, None)?;
+    let mut attrs = termios::tcgetattr(pty.slave)?;
+    termios::cfmakeraw(&mut attrs);
+    termios::tcsetattr(pty.slave, termios::SetArg::TCSANOW, &attrs)?;
+    let tty = ttyname(pty.slave)?;
+
+    // the same as the device, the RNG is seeded even if the TRNG fails
+    let mut trng = Trng::new(OsRng);
+    let mut seed = [0; 32];
+    let _ = trng.startup().and_then(|_| trng.fill(&mut seed));
+
+    let board = Board {
+        flash: file,
+        master: BufReader::new(unsafe { File::from_raw_fd(pty.master) }),
+        _slave: unsafe { File::from_raw_fd(pty.slave) },
+        trng,
+        iwdg,
+        fed: Instant::now()
+    };
+    Ok((tty, Device::new(board, ChaCha20Rng::from_seed(seed))))
+}
+
+/// copy the bytes of sectors to memory which is never freed
+fn load<T>(bytes: &[u8]) -> *mut T {
+    let num = bytes.len() / size_of::<T>();
+    let mut sectors: Vec<T> = Vec::with_capacity(num);
+    unsafe {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), sectors.as_mut_ptr() as *mut u8, bytes.len());
+        sectors.set_len(num);
+    }
+    Box::leak(sectors.into_boxed_slice()).as_mut_ptr()
+}
+
+/// the board as a Linux process: file backed flash, the keypad on stdin,
+/// the display on stdout and the host on a pseudo-terminal
+pub struct Board {
+    flash: File,
+    /// the bytes read past a frame stay buffered for the next one
+    master: BufReader<File>,
+    /// kept open, so reading the master does not fail between two clients
+    _slave: File,
+    trng: Trng<OsRng>,
+    /// the timeout of the IWDG of the board, see `System::feed_watchdog`
+    iwdg: Duration,
+    fed: Instant
+}
+
+impl Board {
+    fn slot_bytes(slot: usize) -> &'static mut [u8] {
+        unsafe {
+            let slot = SLOTS.load(Ordering::SeqCst).add(slot);
+            std::slice::from_raw_parts_mut(slot as *mut u8, size_of::<Slot>())
+        }
+    }
+
+    fn log_bytes(sector: usize) -> &'static mut [u8] {
+        unsafe {
+            let sector = LOG.load(Ordering::SeqCst).add(sector);
+            std::slice::from_raw_parts_mut(sector as *mut u8, size_of::<LogSector>())
+        }
+    }
+
+    /// write bytes of a sector back to the flash file at `pos`
+    fn persist(&mut self, pos: usize, bytes: &[u8]) -> Result<()> {
+        self.flash.seek(SeekFrom::Start(pos as u64))
+            .and_then(|_| self.flash.write_all(bytes))
+            .map_err(|_| Error::Flash)
+    }
+}
+
+/// like NOR flash, programming only clears bits
+fn program<'a>(sector: &'a mut [u8], offset: usize, data: &[u8]) -> Result<&'a [u8]> {
+    let bytes = sector.get_mut(offset..offset + data.len()).ok_or(Error::Flash)?;
+    bytes.iter_mut().zip(data).for_each(|(byte, data)| *byte &= data);
+    Ok(bytes)
+}
+
+impl WalletStorage for Board {
+    fn slots() -> [&'static Slot; SLOT_NUM] {
+        let base = SLOTS.load(Ordering::SeqCst);
+        unsafe { [&*base, &*base.add(1)] }
+    }
+
+    fn erase(&mut self, slot: usize) -> Result<()> {
+        Self::slot_bytes(slot).fill(0xff);
+        self.persist(slot * size_of::<Slot>(), Self::slot_bytes(slot))
+    }
+
+    fn program(&mut self, slot: usize, offset: usize, data: &[u8]) -> Result<()> {
+        let bytes = program(Self::slot_bytes(slot), offset, data)?;
+        self.persist(slot * size_of::<Slot>() + offset, bytes)
+    }
+}
+
+impl AuditStorage for Board {
+    fn log_sectors() -> [&'static LogSector; LOG_SECTOR_NUM] {
+        let base = LOG.load(Ordering::SeqCst);
+        unsafe { [&*base, &*base.add(1)] }
+    }
+
+    fn erase_log(&mut self, sector: usize) -> Result<()> {
+        Self::log_bytes(sector).fill(0xff);
+        self.persist(LOG_OFFSET + sector * size_of::<LogSector>(), Self::log_bytes(sector))
+    }
+
+    fn program_log(&mut self, sector: usize, offset: usize, data: &[u8]) -> Result<()> {
+        let bytes = program(Self::log_bytes(sector), offset, data)?;
+        self.persist(LOG_OFFSET + sector * size_of::<LogSector>() + offset, bytes)
+    }
+}
+
+/// there is no bootloader to hand an image to, updates are accepted and dropped
+impl FirmwareStorage for Board {
+    fn image_header(_slot: usize) -> [u8; HEADER_FIELDS_LEN] {
+        [0xff; HEADER_FIELDS_LEN]
+    }
+
+    fn image_confirmed(_slot: usize) -> bool {
+        true
+    }
+
+    fn erase_image(&mut self, slot: usize) -> Result<()> {
+        println!("firmware: erase slot {}", slot);
+        Ok(())
+    }
+
+    fn program_image(&mut self, _slot: usize, _offset: usize, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn prompt(text: &str) -> String {
+    print!("{}", text);
+    io::stdout().flush().unwrap();
+
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line).unwrap() == 0 {
+        // stdin is closed, nobody is left to press keys
+        std::process::exit(0)
+    }
+    line.trim().to_string()
+}
+
+// the board feeds the IWDG while it waits for a key, the prompts count as fed
+impl KeyInput for Board {
+    fn wait_for_key(&mut self) -> [u8; FIXED_KEY_LEN] {
+        loop {
+            let line = prompt(&format!("keypad ({} digits): ", FIXED_KEY_LEN));
+            self.fed = Instant::now();
+            if line.len() == FIXED_KEY_LEN && line.bytes().all(|ch| ch.is_ascii_digit()) {
+                // the keypad reports the segments of the digit pressed
+                let mut key = [0; FIXED_KEY_LEN];
+                key.iter_mut().zip(line.bytes()).for_each(|(key, ch)| *key = glyph(ch));
+                return key
+            }
+        }
+    }
+
+    fn wait_for_confirm(&mut self) -> bool {
+        loop {
+            let line = prompt("confirm? [y/n]: ");
+            self.fed = Instant::now();
+            match line.as_str() {
+                "y" | "Y" => return true,
+                "n" | "N" => return false,
+                _ => continue
+            }
+        }
+    }
+}
+
+impl Display for Board {
+    /// the terminal does not animate, a text longer than the display takes more lines
+    fn show(&mut self, screen: &Screen) -> Result<()> {
+        for line in screen.pages().flat_map(draw) {
+            println!("{}", String::from_utf8_lossy(&line));
+        }
+        Ok(())
+    }
+}
+
+impl Transport for Board {
+    fn receive(&mut self, deadline: Option<u32>) -> Option<MsgBuffer> {
+        let mut buf = MsgBuffer::new();
+        while !buf.done() && !buf.timed_out(deadline, Self::millis()) {
+            // waiting for the host is not a hang
+            self.feed_watchdog();
+            // wake up in time for the deadline, or to feed the IWDG
+            // as the RTC wakes the board from STOP
+            let fd = self.master.get_ref().as_raw_fd();
+            let timeout = deadline.map_or(self.iwdg.as_millis() as i32 * 2 / 3, |_| 10);
+            let ready = !self.master.buffer().is_empty()
+                || poll(&mut [PollFd::new(fd, PollFlags::POLLIN)], timeout).map_or(true, |n| n > 0);
+            if !ready {
+                continue
+            }
+
+            match self.master.fill_buf() {
+                Ok(bytes) if !bytes.is_empty() => {
+                    let taken = buf.read(bytes, Self::millis());
+                    self.master.consume(taken);
+                },
+                _ => buf.state = MsgBufferState::Damaged
+            }
+        }
+        Some(buf).filter(|buf| buf.done())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.master.get_mut().write_all(bytes).map_err(|_| Error::SerialTxError)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.master.get_mut().flush().map_err(|_| Error::SerialTxError)
+    }
+
+    /// a pseudo-terminal has no baud rate
+    fn set_baud_rate(&mut self, _baud: u32) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl EntropySource for Board {
+    fn fill(&mut self, dest: &mut [u8]) -> Result<()> {
+        self.trng.fill(dest)
+    }
+}
+
+impl System for Board {
+    fn delay_us(&mut self, us: u32) {
+        thread::sleep(Duration::from_micros(us as u64));
+    }
+
+    fn millis() -> u32 {
+        START.get_or_init(Instant::now).elapsed().as_millis() as u32
+    }
+
+    /// there is no MCU to identify
+    fn unique_id() -> [u8; 12] {
+        [0; 12]
+    }
+
+    /// a feed later than the timeout after the one before means the board
+    /// was reset by the IWDG meanwhile
+    fn feed_watchdog(&mut self) {
+        if self.fed.elapsed() > self.iwdg {
+            println!("IWDG reset, start the simulator again");
+            std::process::exit(1)
+        }
+        self.fed = Instant::now();
+    }
+
+    fn reset() -> ! {
+        println!("reset, start the simulator again");
+        std::process::exit(0)
+    }
+
+    /// a panic is printed by the simulator
+    fn take_crash_report(&mut self) -> Option<CrashReport> {
+        None
+    }
+}
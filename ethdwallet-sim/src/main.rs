@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use clap::Parser;
+
+// the code running on the device, only `board` is replaced
+#[path = "../../ethdwallet/src"]
+#[allow(dead_code)]
+mod shared {
+    pub mod error;
+    pub mod device;
+    pub mod hal;
+    pub mod input;
+    pub mod display;
+    pub mod tx;
+    pub mod entropy;
+    pub mod firmware;
+    pub mod signing;
+    pub mod audit;
+    pub mod main_loop;
+    pub mod wallet;
+}
+
+use shared::{error, device, hal, input, display, tx, entropy, firmware, signing, audit, main_loop, wallet};
+
+mod board;
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod tests;
+
+#[derive(Parser)]
+struct Args {
+    /// file backing the wallet and audit log sectors, created if it does not exist
+    #[clap(short, long, default_value = "flash.bin")]
+    flash: String,
+    /// milliseconds the IWDG waits to be fed, as the board's
+    #[clap(long, default_value_t = 30_000)]
+    iwdg_ms: u64
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let (tty, mut dev) = match board::init(&args.flash, Duration::from_millis(args.iwdg_ms)) {
+        Ok(init) => init,
+        Err(e) => {
+            eprintln!("failed to set up the simulator: {}", e);
+            std::process::exit(1)
+        }
+    };
+    println!("serial port: {}", tty.display());
+
+    if let Err(e) = wallet::initializer::try_initialize_wallet(&mut dev) {
+        eprintln!("failed to initialize the wallet: {:?}", e);
+        std::process::exit(1)
+    }
+    main_loop::main_loop(&mut dev)
+}
@@ -0,0 +1,188 @@
+// a board for the unit tests: flash in memory, the keys and the answers of the user
+// queued up front, and whatever is sent to the host kept for the test to read.
+// the flash of a thread is its own, so the tests run side by side
+use std::{cell::Cell, collections::VecDeque, mem::size_of};
+
+use ethdwallet_protocol::CrashReport;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+use crate::{
+    device::Device,
+    error::{Error, Result},
+    hal::{WalletStorage, AuditStorage, FirmwareStorage, KeyInput, Display, Transport, EntropySource, System},
+    input::{MsgBuffer, FIXED_KEY_LEN},
+    display::{Screen, glyph},
+    firmware::HEADER_FIELDS_LEN,
+    wallet::storage::{Slot, SLOT_NUM},
+    audit::{LogSector, LOG_SECTOR_NUM}
+};
+
+thread_local! {
+    static SLOTS: Cell<*mut Slot> = Cell::new(erased(SLOT_NUM));
+    static LOG: Cell<*mut LogSector> = Cell::new(erased(LOG_SECTOR_NUM));
+    static NOW: Cell<u32> = const { Cell::new(0) };
+}
+
+/// erased sectors which are never freed
+fn erased<T>(num: usize) -> *mut T {
+    let mut sectors: Vec<T> = Vec::with_capacity(num);
+    unsafe {
+        std::ptr::write_bytes(sectors.as_mut_ptr() as *mut u8, 0xff, num * size_of::<T>());
+        sectors.set_len(num);
+    }
+    Box::leak(sectors.into_boxed_slice()).as_mut_ptr()
+}
+
+fn bytes<T>(base: *mut T, idx: usize) -> &'static mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(base.add(idx) as *mut u8, size_of::<T>()) }
+}
+
+/// like NOR flash, programming only clears bits
+fn program(sector: &mut [u8], offset: usize, data: &[u8]) -> Result<()> {
+    let bytes = sector.get_mut(offset..offset + data.len()).ok_or(Error::Flash)?;
+    bytes.iter_mut().zip(data).for_each(|(byte, data)| *byte &= data);
+    Ok(())
+}
+
+/// the segments of the digits, as the keypad reports them
+pub fn key(digits: &[u8; FIXED_KEY_LEN]) -> [u8; FIXED_KEY_LEN] {
+    digits.map(glyph)
+}
+
+#[derive(Default)]
+pub struct MockBoard {
+    /// typed in turn, a test waiting for a key without one queued fails
+    pub keys: VecDeque<[u8; FIXED_KEY_LEN]>,
+    pub confirms: VecDeque<bool>,
+    /// everything written to the host
+    pub sent: Vec<u8>,
+    pub screens: usize,
+    pub fed: usize
+}
+
+impl MockBoard {
+    pub fn device() -> Device<Self> {
+        Device::new(Self::default(), ChaCha20Rng::seed_from_u64(1))
+    }
+}
+
+impl WalletStorage for MockBoard {
+    fn slots() -> [&'static Slot; SLOT_NUM] {
+        let base = SLOTS.with(Cell::get);
+        unsafe { [&*base, &*base.add(1)] }
+    }
+
+    fn erase(&mut self, slot: usize) -> Result<()> {
+        bytes(SLOTS.with(Cell::get), slot).fill(0xff);
+        Ok(())
+    }
+
+    fn program(&mut self, slot: usize, offset: usize, data: &[u8]) -> Result<()> {
+        program(bytes(SLOTS.with(Cell::get), slot), offset, data)
+    }
+}
+
+impl AuditStorage for MockBoard {
+    fn log_sectors() -> [&'static LogSector; LOG_SECTOR_NUM] {
+        let base = LOG.with(Cell::get);
+        unsafe { [&*base, &*base.add(1)] }
+    }
+
+    fn erase_log(&mut self, sector: usize) -> Result<()> {
+        bytes(LOG.with(Cell::get), sector).fill(0xff);
+        Ok(())
+    }
+
+    fn program_log(&mut self, sector: usize, offset: usize, data: &[u8]) -> Result<()> {
+        program(bytes(LOG.with(Cell::get), sector), offset, data)
+    }
+}
+
+impl FirmwareStorage for MockBoard {
+    fn image_header(_slot: usize) -> [u8; HEADER_FIELDS_LEN] {
+        [0xff; HEADER_FIELDS_LEN]
+    }
+
+    fn image_confirmed(_slot: usize) -> bool {
+        true
+    }
+
+    fn erase_image(&mut self, _slot: usize) -> Result<()> {
+        Ok(())
+    }
+
+    fn program_image(&mut self, _slot: usize, _offset: usize, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl KeyInput for MockBoard {
+    fn wait_for_key(&mut self) -> [u8; FIXED_KEY_LEN] {
+        self.keys.pop_front().expect("no key queued")
+    }
+
+    fn wait_for_confirm(&mut self) -> bool {
+        self.confirms.pop_front().expect("no answer queued")
+    }
+}
+
+impl Display for MockBoard {
+    fn show(&mut self, _screen: &Screen) -> Result<()> {
+        self.screens += 1;
+        Ok(())
+    }
+}
+
+/// the tests hand requests to `serve`, nothing is received
+impl Transport for MockBoard {
+    fn receive(&mut self, _deadline: Option<u32>) -> Option<MsgBuffer> {
+        None
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.sent.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_baud_rate(&mut self, _baud: u32) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl EntropySource for MockBoard {
+    fn fill(&mut self, dest: &mut [u8]) -> Result<()> {
+        ChaCha20Rng::seed_from_u64(2).fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl System for MockBoard {
+    fn delay_us(&mut self, us: u32) {
+        NOW.with(|now| now.set(now.get().wrapping_add(us / 1000)));
+    }
+
+    fn millis() -> u32 {
+        NOW.with(Cell::get)
+    }
+
+    fn unique_id() -> [u8; 12] {
+        [0; 12]
+    }
+
+    fn feed_watchdog(&mut self) {
+        self.fed += 1;
+    }
+
+    fn reset() -> ! {
+        panic!("reset")
+    }
+
+    fn take_crash_report(&mut self) -> Option<CrashReport> {
+        None
+    }
+}
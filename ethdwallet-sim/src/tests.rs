@@ -0,0 +1,735 @@
+// the request handling of the firmware on `mock::MockBoard`, a request goes through
+// `serve` as a frame and the answers are read back from what the board sent
+use ethdwallet_protocol::{
+    Instruction, Response, Reply, Answer, WalletError, DuressPolicy, Scheme, FrameWriter, Sink, Source, read_answer,
+    LABEL_LEN
+};
+use std::mem::offset_of;
+
+use chacha20::cipher::{StreamCipher, StreamCipherSeek};
+use k256::{ecdsa::recoverable::Signature as RSignature, elliptic_curve::sec1::ToEncodedPoint};
+
+use crate::{
+    device::Device,
+    error::Error,
+    hal::WalletStorage,
+    input::{MsgBuffer, MsgBufferState},
+    main_loop::serve,
+    mock::{MockBoard, key},
+    wallet::{
+        Wallet, wallet, lock, initializer::{try_initialize_wallet, wipe_real_zones}, utils::get_cipher, ACCOUNT_NUM, MAIN_ZONE, HIDDEN_ZONE, DURESS_ZONE,
+        safe_zone::{ZKPLAIN, KEYS_OFFSET, SETTINGS_LEN, SETTINGS_MAGIC, Settings},
+        ecies::{decrypt, x25519_pubkey},
+        format::{
+            encode, verify, verify_legacy, decode, LegacyWallet, Persist, Writer,
+            MAGIC, LEGACY_SIZE, LEGACY_VERSION, FORMAT_VERSION
+        },
+        storage::{slots, committed_slots, write_wallet, Slot, RECORD_SIZE, SLOT_REPEAT, COMMIT_MAGIC},
+        recovery::RecoveryStatus
+    }
+};
+
+const PASSCODE: [u8; 8] = *b"12345678";
+
+struct Bytes<'a>(&'a mut Vec<u8>);
+
+impl Sink for Bytes<'_> {
+    type Error = ();
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        self.0.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+struct Sent<'a>(&'a [u8]);
+
+impl Source for Sent<'_> {
+    type Error = ();
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), ()> {
+        if self.0.len() < buf.len() {
+            return Err(())
+        }
+        let (head, rest) = self.0.split_at(buf.len());
+        buf.copy_from_slice(head);
+        self.0 = rest;
+        Ok(())
+    }
+}
+
+/// serve a request frame and return the reply after the ack
+fn serve_frame(dev: &mut Device<MockBoard>, id: u8, frame: &[u8]) -> Reply {
+    let mut buf = MsgBuffer::new();
+    assert_eq!(buf.read(frame, 0), frame.len());
+    assert!(matches!(buf.state, MsgBufferState::Finished));
+
+    dev.board.sent.clear();
+    serve(dev, &buf).unwrap();
+
+    let mut sent = Sent(&dev.board.sent);
+    assert_eq!(read_answer(&mut sent).unwrap(), (id, Answer::Ack));
+    let (reply_id, answer) = read_answer(&mut sent).unwrap();
+    assert_eq!(reply_id, id);
+    assert!(sent.0.is_empty());
+    match answer {
+        Answer::Reply(reply) => reply,
+        answer => panic!("not a reply: {:?}", answer)
+    }
+}
+
+fn request(dev: &mut Device<MockBoard>, id: u8, instr: &Instruction) -> Reply {
+    let mut frame = Vec::new();
+    instr.write_request(id, &mut Bytes(&mut frame)).unwrap();
+    serve_frame(dev, id, &frame)
+}
+
+fn error(reply: Reply) -> WalletError {
+    reply.expect_err("not an error").error().unwrap()
+}
+
+/// a wallet set up with `PASSCODE`, no dice rolled
+fn initialized() -> Device<MockBoard> {
+    let mut dev = MockBoard::device();
+    dev.board.keys.push_back(key(&PASSCODE));
+    dev.board.confirms.push_back(false);
+    try_initialize_wallet(&mut dev).unwrap();
+    dev
+}
+
+#[test]
+fn dispatch_without_wallet() {
+    let mut dev = MockBoard::device();
+    match request(&mut dev, 1, &Instruction::GetInfo) {
+        Ok(Response::Info(info)) => assert!(!info.initialized && !info.unlocked),
+        reply => panic!("{:?}", reply)
+    }
+    assert_eq!(error(request(&mut dev, 2, &Instruction::GetAddressList)), WalletError::WalletNotInitialized);
+}
+
+#[test]
+fn dispatch_invalid_instruction() {
+    let mut dev = initialized();
+    let mut frame = Vec::new();
+    let mut sink = Bytes(&mut frame);
+    let mut writer = FrameWriter::begin(&mut sink, 1, 1).unwrap();
+    writer.write(&[0xee]).unwrap();
+    writer.finish().unwrap();
+    let reply = serve_frame(&mut dev, 1, &frame);
+    assert_eq!(reply.unwrap_err().arg, Some(0xee));
+    assert_eq!(error(reply), WalletError::InvalidInstruction);
+}
+
+#[test]
+fn repeated_request_is_not_run_again() {
+    let mut dev = initialized();
+    dev.board.keys.push_back(key(&PASSCODE));
+    assert_eq!(request(&mut dev, 5, &Instruction::Unlock), Ok(Response::Done));
+    // no key is queued, a second unlock would wait for one
+    assert_eq!(request(&mut dev, 5, &Instruction::Unlock), Ok(Response::Done));
+}
+
+#[test]
+fn unlock() {
+    let mut dev = initialized();
+    dev.board.keys.push_back(key(b"87654321"));
+    assert_eq!(error(request(&mut dev, 1, &Instruction::Unlock)), WalletError::WrongPassword);
+
+    dev.board.keys.push_back(key(&PASSCODE));
+    assert_eq!(request(&mut dev, 2, &Instruction::Unlock), Ok(Response::Done));
+    match request(&mut dev, 3, &Instruction::GetInfo) {
+        Ok(Response::Info(info)) => assert!(info.initialized && info.unlocked),
+        reply => panic!("{:?}", reply)
+    }
+    assert!(dev.board.fed > 0);
+}
+
+#[test]
+fn sign_hash() {
+    let mut dev = initialized();
+    let hash = [0x5a; 32];
+    dev.board.keys.push_back(key(&PASSCODE));
+    assert_eq!(
+        error(request(&mut dev, 1, &Instruction::SignHash(0, hash))),
+        WalletError::BlindSigningDisabled
+    );
+
+    dev.board.keys.push_back(key(&PASSCODE));
+    assert_eq!(request(&mut dev, 2, &Instruction::SetBlindSigning(true)), Ok(Response::Done));
+
+    dev.board.confirms.push_back(false);
+    assert_eq!(error(request(&mut dev, 3, &Instruction::SignHash(0, hash))), WalletError::UserRejected);
+
+    // the prompt is shown before the user answers
+    let screens = dev.board.screens;
+    dev.board.confirms.push_back(true);
+    let sig = match request(&mut dev, 4, &Instruction::SignHash(0, hash)) {
+        Ok(Response::Signature(sig)) => sig,
+        reply => panic!("{:?}", reply)
+    };
+    assert!(dev.board.screens > screens);
+    let pubkey = match request(&mut dev, 5, &Instruction::GetAddress(0)) {
+        Ok(Response::Address((_, pubkey))) => pubkey,
+        reply => panic!("{:?}", reply)
+    };
+
+    let sig = RSignature::try_from(&[&sig.r[..], &sig.s, &[sig.v]].concat()[..]).unwrap();
+    let signer = sig.recover_verifying_key_from_digest_bytes(&hash.into()).unwrap();
+    assert_eq!(&signer.to_encoded_point(false).as_bytes()[1..], &pubkey[..]);
+}
+
+#[test]
+fn sign_hash_allowlist_only() {
+    let mut dev = initialized();
+    dev.board.keys.push_back(key(&PASSCODE));
+    assert_eq!(request(&mut dev, 1, &Instruction::SetBlindSigning(true)), Ok(Response::Done));
+    dev.board.keys.push_back(key(&PASSCODE));
+    assert_eq!(request(&mut dev, 2, &Instruction::SetAllowlistOnly(0, true)), Ok(Response::Done));
+    lock(&mut dev);
+
+    // the policy is read once the passcode opens the zone
+    let hash = [0x5a; 32];
+    dev.board.keys.push_back(key(&PASSCODE));
+    assert_eq!(error(request(&mut dev, 3, &Instruction::SignHash(0, hash))), WalletError::RecipientNotAllowed);
+
+    dev.board.confirms.push_back(true);
+    assert!(matches!(request(&mut dev, 4, &Instruction::SignHash(1, hash)), Ok(Response::Signature(_))));
+}
+
+fn addr_list(dev: &mut Device<MockBoard>, id: u8, passcode: &[u8; 8]) -> Vec<[u8; 20]> {
+    lock(dev);
+    dev.board.keys.push_back(key(passcode));
+    match request(dev, id, &Instruction::GetAddressList) {
+        Ok(Response::AddressList(list)) => list.to_vec(),
+        reply => panic!("{:?}", reply)
+    }
+}
+
+/// the labels and the addresses in the book of the zone the passcode opens
+fn book(dev: &mut Device<MockBoard>, id: u8, passcode: &[u8; 8]) -> Vec<([u8; LABEL_LEN], [u8; 20])> {
+    lock(dev);
+    dev.board.keys.push_back(key(passcode));
+    match request(dev, id, &Instruction::GetAddressBook) {
+        Ok(Response::AddressBook(entries)) => entries.iter()
+            .filter(|entry| entry.used)
+            .map(|entry| (entry.label, entry.addr))
+            .collect(),
+        reply => panic!("{:?}", reply)
+    }
+}
+
+#[test]
+fn settings_of_each_zone() {
+    let mut dev = initialized();
+    dev.board.keys.extend([key(&PASSCODE), key(b"11112222")]);
+    assert_eq!(request(&mut dev, 1, &Instruction::SetupHiddenZone), Ok(Response::Done));
+
+    let alice = (*b"alice\0\0\0", [0xa1; 20]);
+    dev.board.keys.push_back(key(&PASSCODE));
+    assert_eq!(request(&mut dev, 2, &Instruction::AddBookEntry(alice.0, alice.1)), Ok(Response::Done));
+    dev.board.keys.push_back(key(&PASSCODE));
+    assert_eq!(request(&mut dev, 3, &Instruction::SetBlindSigning(true)), Ok(Response::Done));
+    let bob = (*b"bob\0\0\0\0\0", [0xb0; 20]);
+    dev.board.keys.push_back(key(b"11112222"));
+    assert_eq!(request(&mut dev, 4, &Instruction::AddBookEntry(bob.0, bob.1)), Ok(Response::Done));
+
+    assert_eq!(book(&mut dev, 5, &PASSCODE), [alice]);
+    assert_eq!(book(&mut dev, 6, b"11112222"), [bob]);
+    assert_eq!(
+        error(request(&mut dev, 7, &Instruction::SignHash(0, [0x5a; 32]))), 
+        WalletError::BlindSigningDisabled
+    );
+
+    // nothing is listed for a wrong passcode
+    lock(&mut dev);
+    dev.board.keys.push_back(key(b"87654321"));
+    assert_eq!(error(request(&mut dev, 8, &Instruction::GetAddressBook)), WalletError::WrongPassword);
+}
+
+#[test]
+fn duress_changes_nothing_of_the_real_zones() {
+    let mut dev = initialized();
+    dev.board.keys.extend([key(&PASSCODE), key(b"33334444")]);
+    assert_eq!(request(&mut dev, 1, &Instruction::SetupDuressZone(DuressPolicy::Decoy)), Ok(Response::Done));
+    let decoy = addr_list(&mut dev, 2, b"33334444");
+
+    let mallory = (*b"mallory\0", [0x66; 20]);
+    dev.board.keys.push_back(key(b"33334444"));
+    assert_eq!(request(&mut dev, 3, &Instruction::AddBookEntry(mallory.0, mallory.1)), Ok(Response::Done));
+    dev.board.keys.push_back(key(b"33334444"));
+    assert_eq!(request(&mut dev, 4, &Instruction::SetBlindSigning(true)), Ok(Response::Done));
+    dev.board.keys.push_back(key(b"33334444"));
+    assert_eq!(request(&mut dev, 5, &Instruction::SetAllowlistOnly(0, true)), Ok(Response::Done));
+    assert_eq!(book(&mut dev, 6, b"33334444"), [mallory]);
+
+    // the settings of the main zone are its own
+    assert_eq!(book(&mut dev, 7, &PASSCODE), []);
+    assert_eq!(
+        error(request(&mut dev, 8, &Instruction::SignHash(0, [0x5a; 32]))), 
+        WalletError::BlindSigningDisabled
+    );
+
+    // and the zones are set up from the real ones only
+    for (id, instr) in [(9, Instruction::SetupDuressZone(DuressPolicy::Wipe)), (10, Instruction::SetupHiddenZone)] {
+        lock(&mut dev);
+        dev.board.keys.push_back(key(b"33334444"));
+        assert_eq!(error(request(&mut dev, id, &instr)), WalletError::WrongPassword);
+    }
+    assert!(!dev.pending_wipe);
+    assert_eq!(addr_list(&mut dev, 11, b"33334444"), decoy);
+}
+
+#[test]
+fn duress_wipe_leaves_nothing_of_the_real_zones() {
+    let mut dev = initialized();
+    dev.board.keys.extend([key(&PASSCODE), key(b"11112222")]);
+    assert_eq!(request(&mut dev, 1, &Instruction::SetupHiddenZone), Ok(Response::Done));
+    dev.board.keys.extend([key(&PASSCODE), key(b"33334444")]);
+    assert_eq!(request(&mut dev, 2, &Instruction::SetupDuressZone(DuressPolicy::Wipe)), Ok(Response::Done));
+    let decoy = addr_list(&mut dev, 3, b"33334444");
+    dev.pending_wipe = false;
+
+    // the slot not in use holds copies of the first firmware instead
+    let (copy, _) = legacy_copy(&PASSCODE);
+    assert_eq!(dev.active_slot, 1);
+    dev.board.erase(0).unwrap();
+    dev.board.program(0, 0, &copy).unwrap();
+
+    let real = wallet(&mut dev);
+    let mut secrets: Vec<&[u8]> = [MAIN_ZONE, HIDDEN_ZONE].iter()
+        .flat_map(|idx| [&real.zones[*idx].zkmagic[..], &real.zones[*idx].otp_secret])
+        .chain(real.zones[MAIN_ZONE].keys.iter().chain(&real.zones[HIDDEN_ZONE].keys).map(|key| &key[..]))
+        .chain(real.addrs.iter().map(|addr| &addr[..]))
+        .collect();
+    secrets.push(&copy[offset_of!(LegacyWallet, zone.keys)..][..32]);
+
+    lock(&mut dev);
+    dev.board.keys.push_back(key(b"33334444"));
+    assert_eq!(request(&mut dev, 4, &Instruction::Unlock), Ok(Response::Done));
+    assert!(dev.pending_wipe);
+    // as the main loop does after the response
+    let wallet = wallet(&mut dev);
+    assert!(wipe_real_zones(&mut dev, &wallet).is_ok());
+
+    assert_eq!(committed_slots::<MockBoard>().count(), 2);
+    for slot in slots::<MockBoard>() {
+        let bytes = slot.records.as_flattened();
+        for secret in &secrets {
+            assert!(!bytes.windows(secret.len()).any(|window| window == *secret));
+        }
+    }
+
+    // the decoy is all there is at the next start
+    let mut dev = restart(dev);
+    for (id, passcode) in [(5, &PASSCODE), (6, b"11112222")] {
+        dev.board.keys.push_back(key(passcode));
+        assert_eq!(error(request(&mut dev, id, &Instruction::Unlock)), WalletError::WrongPassword);
+    }
+    // it opens the main zone now, which wipes nothing
+    assert_eq!(addr_list(&mut dev, 7, b"33334444"), decoy);
+    assert!(!dev.pending_wipe);
+}
+
+#[test]
+fn addresses_need_the_passcode() {
+    let mut dev = initialized();
+    dev.board.keys.push_back(key(b"87654321"));
+    assert_eq!(error(request(&mut dev, 1, &Instruction::GetAddressList)), WalletError::WrongPassword);
+    dev.board.keys.push_back(key(b"87654321"));
+    assert_eq!(error(request(&mut dev, 2, &Instruction::GetAddress(0))), WalletError::WrongPassword);
+
+    let list = addr_list(&mut dev, 3, &PASSCODE);
+    match request(&mut dev, 4, &Instruction::GetAddress(2)) {
+        Ok(Response::Address((addr, _))) => assert_eq!(addr, list[2]),
+        reply => panic!("{:?}", reply)
+    }
+}
+
+#[test]
+fn duress_setup_with_the_hidden_passcode() {
+    let mut dev = initialized();
+    dev.board.keys.extend([key(&PASSCODE), key(b"11112222")]);
+    assert_eq!(request(&mut dev, 1, &Instruction::SetupHiddenZone), Ok(Response::Done));
+    let hidden = addr_list(&mut dev, 2, b"11112222");
+    assert_ne!(hidden, addr_list(&mut dev, 3, &PASSCODE));
+
+    // accepted like any other passcode, the hidden zone is still the one opened
+    dev.board.keys.extend([key(&PASSCODE), key(b"11112222")]);
+    assert_eq!(request(&mut dev, 4, &Instruction::SetupDuressZone(DuressPolicy::Wipe)), Ok(Response::Done));
+    assert_eq!(addr_list(&mut dev, 5, b"11112222"), hidden);
+}
+
+#[test]
+fn hidden_setup_answers_the_main_zone_the_same() {
+    let mut dev = initialized();
+    dev.board.keys.extend([key(&PASSCODE), key(b"33334444")]);
+    assert_eq!(request(&mut dev, 1, &Instruction::SetupDuressZone(DuressPolicy::Decoy)), Ok(Response::Done));
+
+    // the duress zone is refused like a wrong passcode, before the new one is asked for
+    dev.board.keys.push_back(key(b"33334444"));
+    assert_eq!(error(request(&mut dev, 2, &Instruction::SetupHiddenZone)), WalletError::WrongPassword);
+
+    dev.board.keys.extend([key(&PASSCODE), key(b"11112222")]);
+    assert_eq!(request(&mut dev, 3, &Instruction::SetupHiddenZone), Ok(Response::Done));
+    let hidden = addr_list(&mut dev, 4, b"11112222");
+
+    // done again with a hidden zone in use, which is replaced
+    dev.board.keys.extend([key(&PASSCODE), key(b"55556666")]);
+    assert_eq!(request(&mut dev, 5, &Instruction::SetupHiddenZone), Ok(Response::Done));
+    dev.board.keys.push_back(key(b"11112222"));
+    assert_eq!(error(request(&mut dev, 6, &Instruction::Unlock)), WalletError::WrongPassword);
+    let replaced = addr_list(&mut dev, 7, b"55556666");
+    assert_ne!(replaced, hidden);
+
+    // the hidden zone replaces itself
+    dev.board.keys.extend([key(b"55556666"), key(b"77778888")]);
+    assert_eq!(request(&mut dev, 8, &Instruction::SetupHiddenZone), Ok(Response::Done));
+    assert_ne!(addr_list(&mut dev, 9, b"77778888"), replaced);
+}
+
+/// a copy as the first firmware wrote it: the accounts in plaintext, the keys encrypted,
+/// the CRC taken with its field zeroed. returns the copy and its addresses
+fn legacy_copy(passcode: &[u8; 8]) -> (Vec<u8>, Vec<[u8; 20]>) {
+    let iv = [7; 12];
+    let mut cipher = get_cipher(key(passcode), &iv);
+    let mut zkmagic = ZKPLAIN;
+    cipher.apply_keystream(&mut zkmagic);
+    let mut keys = [0x42; 32 * ACCOUNT_NUM];
+    cipher.seek(KEYS_OFFSET);
+    cipher.apply_keystream(&mut keys);
+    let addrs: Vec<[u8; 20]> = (0..ACCOUNT_NUM as u8).map(|i| [i; 20]).collect();
+
+    let mut copy = vec![0; LEGACY_SIZE];
+    let mut put = |offset: usize, bytes: &[u8]| copy[offset..offset + bytes.len()].copy_from_slice(bytes);
+    put(offset_of!(LegacyWallet, initialized), &[1]);
+    put(offset_of!(LegacyWallet, zone.zkmagic), &zkmagic);
+    put(offset_of!(LegacyWallet, zone.keys), &keys);
+    put(offset_of!(LegacyWallet, chacha_iv), &iv);
+    put(offset_of!(LegacyWallet, addrs), &addrs.concat());
+    put(offset_of!(LegacyWallet, pubkeys), &[0x64; 64 * ACCOUNT_NUM]);
+    let crc = crc32fast::hash(&copy);
+    copy[offset_of!(LegacyWallet, crc)..][..4].copy_from_slice(&crc.to_le_bytes());
+    (copy, addrs)
+}
+
+#[test]
+fn migrate_legacy_copy() {
+    let (mut copy, addrs) = legacy_copy(&PASSCODE);
+    let wallet = decode(LEGACY_VERSION, verify_legacy(&copy).unwrap()).unwrap();
+    assert!(wallet.initialized);
+    assert_eq!(wallet.addrs.to_vec(), addrs);
+    assert_eq!(wallet.pubkeys, [[0x64; 64]; ACCOUNT_NUM]);
+
+    // the same wallet after a round trip through the current format
+    let mut record = [0xff; RECORD_SIZE];
+    let len = encode(&wallet, &mut record);
+    let (version, payload) = verify(&record).unwrap();
+    assert_eq!(version, FORMAT_VERSION);
+    let mut again = [0xff; RECORD_SIZE];
+    assert_eq!(encode(&decode(version, payload).unwrap(), &mut again), len);
+    assert_eq!(record, again);
+
+    copy[offset_of!(LegacyWallet, addrs)] ^= 1;
+    assert!(verify_legacy(&copy).is_none());
+
+    // the accounts are encrypted at the first start, once the passcode opens the wallet
+    let mut dev = MockBoard::device();
+    dev.wallet = Some(wallet);
+    dev.board.keys.extend([key(b"87654321"), key(&PASSCODE)]);
+    try_initialize_wallet(&mut dev).unwrap();
+    assert!(dev.board.keys.is_empty());
+    assert_eq!(addr_list(&mut dev, 1, &PASSCODE), addrs);
+}
+
+#[test]
+fn migrate_legacy_sector() {
+    let (copy, addrs) = legacy_copy(&PASSCODE);
+    let mut dev = MockBoard::device();
+    let copies = RECORD_SIZE * SLOT_REPEAT / copy.len();
+    for i in 0..copies {
+        dev.board.program(0, i * copy.len(), &copy).unwrap();
+    }
+    // a corrupted copy is outvoted
+    dev.board.program(0, 3, &[0]).unwrap();
+
+    assert!(wallet(&mut dev).initialized);
+    assert_eq!(dev.diagnostics.version, LEGACY_VERSION);
+    assert_eq!((dev.diagnostics.valid as usize, dev.diagnostics.agreeing as usize), (copies - 1, copies - 1));
+    // written to slot B, then to slot A over the copies
+    assert_eq!(committed_slots::<MockBoard>().map(|(idx, _)| idx).collect::<Vec<_>>(), [0, 1]);
+    assert_eq!(dev.active_slot, 0);
+
+    dev.board.keys.push_back(key(&PASSCODE));
+    try_initialize_wallet(&mut dev).unwrap();
+    assert_eq!(addr_list(&mut dev, 1, &PASSCODE), addrs);
+
+    // read back from the slots at the next start
+    let mut dev = Device::new(MockBoard::default(), dev.rng);
+    let wallet = wallet(&mut dev);
+    assert_eq!(dev.diagnostics.version, FORMAT_VERSION);
+    assert!(wallet.initialized);
+    try_initialize_wallet(&mut dev).unwrap();
+    assert_eq!(addr_list(&mut dev, 2, &PASSCODE), addrs);
+}
+
+/// the wallet written twice, to slot 1 then to slot 0 with another payload
+fn written_twice() -> Device<MockBoard> {
+    let mut dev = initialized();
+    let mut wallet = wallet(&mut dev);
+    wallet.chacha_ivs[DURESS_ZONE][0] ^= 1;
+    write_wallet(&mut dev, &wallet).unwrap();
+    assert_eq!(dev.active_slot, 0);
+    dev
+}
+
+/// start again on the same flash and recover the wallet
+fn restart(dev: Device<MockBoard>) -> Device<MockBoard> {
+    let mut dev = Device::new(MockBoard::default(), dev.rng);
+    wallet(&mut dev);
+    dev
+}
+
+/// offsets of bytes which are not zero in the copies of a slot, clearing one breaks the CRC
+fn nonzero(slot: usize) -> Vec<usize> {
+    let record = &slots::<MockBoard>()[slot].records[0];
+    (0..RECORD_SIZE).filter(|offset| record[*offset] != 0).take(SLOT_REPEAT).collect()
+}
+
+fn corrupt(dev: &mut Device<MockBoard>, slot: usize, copy: usize, offset: usize) {
+    dev.board.program(slot, copy * RECORD_SIZE + offset, &[0]).unwrap();
+}
+
+fn recovered(dev: &Device<MockBoard>) -> (u8, u8, u8, u8) {
+    let diag = dev.diagnostics;
+    (diag.status as u8, diag.slot, diag.valid, diag.agreeing)
+}
+
+#[test]
+fn recover_voted_then_repaired() {
+    let dev = restart(written_twice());
+    assert_eq!(recovered(&dev), (RecoveryStatus::Clean as u8, 0, 7, 7));
+
+    let mut dev = dev;
+    for copy in 0..2 {
+        corrupt(&mut dev, 0, copy, 0);
+    }
+    let mut dev = restart(dev);
+    assert_eq!(recovered(&dev), (RecoveryStatus::Voted as u8, 0, 5, 5));
+
+    for copy in 2..4 {
+        corrupt(&mut dev, 0, copy, 0);
+    }
+    let dev = restart(dev);
+    assert_eq!(recovered(&dev), (RecoveryStatus::Repaired as u8, 0, 3, 3));
+    // written again to the other slot
+    assert_eq!(dev.active_slot, 1);
+    let dev = restart(dev);
+    assert_eq!(recovered(&dev), (RecoveryStatus::Clean as u8, 1, 7, 7));
+}
+
+#[test]
+fn recover_fell_back() {
+    let mut dev = written_twice();
+    for copy in 0..SLOT_REPEAT {
+        corrupt(&mut dev, 0, copy, 0);
+    }
+    let dev = restart(dev);
+    assert_eq!(recovered(&dev), (RecoveryStatus::FellBack as u8, 1, 7, 7));
+    assert!(wallet(&mut { dev }).initialized);
+}
+
+#[test]
+fn recover_tie_falls_back() {
+    let mut dev = written_twice();
+    let [newest, older] = slots::<MockBoard>().map(|slot| slot.records[0].to_vec());
+    let seq = slots::<MockBoard>()[0].seq;
+
+    // three copies of each version and one erased
+    dev.board.erase(0).unwrap();
+    for copy in 0..SLOT_REPEAT - 1 {
+        let record = if copy % 2 == 0 { &newest } else { &older };
+        dev.board.program(0, copy * RECORD_SIZE, record).unwrap();
+    }
+    dev.board.program(0, offset_of!(Slot, seq), &seq.to_le_bytes()).unwrap();
+    dev.board.program(0, offset_of!(Slot, commit), &COMMIT_MAGIC.to_le_bytes()).unwrap();
+
+    let dev = restart(dev);
+    assert_eq!(recovered(&dev), (RecoveryStatus::FellBack as u8, 1, 7, 7));
+    // the tie is left for the next start to find, not written back
+    assert_eq!(committed_slots::<MockBoard>().next().map(|(idx, slot)| (idx, slot.seq)), Some((0, seq)));
+}
+
+#[test]
+fn recover_reconstructed() {
+    let mut dev = written_twice();
+    for slot in 0..2 {
+        for (copy, offset) in nonzero(slot).into_iter().enumerate() {
+            corrupt(&mut dev, slot, copy, offset);
+        }
+    }
+    let dev = restart(dev);
+    assert_eq!(recovered(&dev), (RecoveryStatus::Reconstructed as u8, 0, 0, 0));
+    assert_eq!(dev.active_slot, 1);
+    let dev = restart(dev);
+    assert_eq!(recovered(&dev), (RecoveryStatus::Clean as u8, 1, 7, 7));
+}
+
+#[test]
+fn recover_unrecoverable() {
+    let mut dev = written_twice();
+    for slot in 0..2 {
+        for copy in 0..SLOT_REPEAT {
+            corrupt(&mut dev, slot, copy, 0);
+        }
+    }
+    let mut dev = restart(dev);
+    assert_eq!(recovered(&dev).0, RecoveryStatus::Unrecoverable as u8);
+
+    // the flash is kept for diagnostics, no new wallet is set up
+    try_initialize_wallet(&mut dev).unwrap();
+    assert_eq!(committed_slots::<MockBoard>().map(|(_, slot)| slot.seq).collect::<Vec<_>>(), [1, 0]);
+    assert_eq!(error(request(&mut dev, 1, &Instruction::GetAddressList)), WalletError::WalletCorrupted);
+}
+
+fn unhex(hex: &str) -> Vec<u8> {
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+}
+
+/// the account of bob in the examples of @metamask/eth-sig-util
+const BOB: &str = "7e5374ec2ef0d91761a6e72fdf8f6ac665519bfdf6da0a2329cf0d804514b816";
+const MESSAGE: &[u8] = b"My name is Satoshi Buterin";
+
+/// the ephemeral key, the nonce and the box (tag then ciphertext) of the
+/// encrypted data in the tests of @metamask/eth-sig-util, decoded from base64
+fn x25519_payload() -> Vec<u8> {
+    unhex(concat!(
+        "1411f5fe90041ce396d782eedc55a4815dea38472e2fbf19cbea96d51c333174",
+        "d5dbd63bbb8e9c19ceee234327d90ef694dab0bb8a3657a3",
+        "7fc901725fcd0b27f7b326df6f000a93f9e9d81cedf654559197a3afaba1e458273651ff89ceb60d9cf2"
+    ))
+}
+
+/// the ephemeral key, the iv, the ciphertext and the tag of geth's `ecies.Encrypt`
+/// with ECIES_AES128_SHA256 and no shared info, the ephemeral key is 0x5c..5c
+/// and the iv 00..0f
+fn secp256k1_payload() -> Vec<u8> {
+    unhex(concat!(
+        "04474c9c627ad19d0ae5c70050703dc62ebbee99885d054623f59f8f79a277904e",
+        "22717a261036655ca9f3e10b77031c1b5a35924d24735ebb24e655981febe9a4",
+        "000102030405060708090a0b0c0d0e0f",
+        "adb70a0aea918e1db4a85c3b18bdd673bb0fd7cae95505dce897",
+        "0d19802e48e72fae4958e9b692749c31ea81e52ed1823581668fe98604d570ec"
+    ))
+}
+
+fn bob() -> [u8; 32] {
+    unhex(BOB).try_into().unwrap()
+}
+
+#[test]
+fn decrypt_x25519_known_answer() {
+    assert_eq!(
+        x25519_pubkey(&bob()).to_vec(),
+        unhex("0b960c35da84e242e0c508493b531fb907073f98635525f36a6cddfd3c654745")
+    );
+    let mut payload = x25519_payload();
+    let plaintext = decrypt(Scheme::X25519XSalsa20Poly1305, &bob(), &mut payload).unwrap();
+    assert_eq!(&payload[plaintext], MESSAGE);
+}
+
+#[test]
+fn decrypt_secp256k1_known_answer() {
+    let mut payload = secp256k1_payload();
+    let plaintext = decrypt(Scheme::Secp256k1, &bob(), &mut payload).unwrap();
+    assert_eq!(&payload[plaintext], MESSAGE);
+}
+
+#[test]
+fn decrypt_refuses_bad_ciphertexts() {
+    let refused = |scheme, mut payload: Vec<u8>| matches!(
+        decrypt(scheme, &bob(), &mut payload), Err(Error::InvalidCiphertext)
+    );
+
+    for (scheme, payload, tag, ephemeral, min_len) in [
+        (Scheme::X25519XSalsa20Poly1305, x25519_payload(), 32 + 24, 0..32, 32 + 24 + 16),
+        (Scheme::Secp256k1, secp256k1_payload(), secp256k1_payload().len() - 32, 1..65, 65 + 16 + 32)
+    ] {
+        for offset in [tag, payload.len() - 1] {
+            let mut tampered = payload.clone();
+            tampered[offset] ^= 1;
+            assert!(refused(scheme, tampered));
+        }
+        assert!(refused(scheme, payload[..min_len - 1].to_vec()));
+
+        // a point of low order for x25519, not on the curve for secp256k1
+        let mut invalid = payload.clone();
+        invalid[ephemeral].fill(0);
+        assert!(refused(scheme, invalid));
+    }
+}
+
+/// a record of version 1: the zones without their settings, then the settings
+/// shared by every zone in plaintext
+fn v1_record(wallet: &Wallet, shared: &Settings) -> Vec<u8> {
+    let mut payload = vec![wallet.initialized as u8];
+    for zone in &wallet.zones {
+        payload.extend(zone.zkmagic);
+        payload.extend(zone.keys.as_flattened());
+        payload.extend(zone.otp_secret);
+        payload.extend(zone.addrs.as_flattened());
+        payload.extend(zone.pubkeys.as_flattened());
+        payload.push(zone.policy);
+    }
+    payload.extend(wallet.chacha_ivs.as_flattened());
+    payload.extend(wallet.addrs.as_flattened());
+    payload.extend(wallet.pubkeys.as_flattened());
+    let mut settings = [0; SETTINGS_LEN - SETTINGS_MAGIC.len()];
+    shared.write(&mut Writer::new(&mut settings));
+    payload.extend(settings);
+
+    let mut record = [&MAGIC[..], &1u16.to_le_bytes(), &(payload.len() as u32).to_le_bytes(), &payload].concat();
+    record.extend(crc32fast::hash(&record).to_le_bytes());
+    record
+}
+
+#[test]
+fn migrate_v1_record() {
+    let mut dev = initialized();
+    dev.board.keys.extend([key(&PASSCODE), key(b"11112222")]);
+    assert_eq!(request(&mut dev, 1, &Instruction::SetupHiddenZone), Ok(Response::Done));
+    let hidden = addr_list(&mut dev, 2, b"11112222");
+
+    let alice = (*b"alice\0\0\0", [0xa1; 20]);
+    let mut shared = Settings::new();
+    shared.book.add(alice.0, alice.1).unwrap();
+    shared.blind_signing = true;
+    let record = v1_record(&wallet(&mut dev), &shared);
+    for slot in 0..2 {
+        dev.board.erase(slot).unwrap();
+    }
+    dev.board.program(0, 0, &record).unwrap();
+    dev.board.program(0, offset_of!(Slot, seq), &0u32.to_le_bytes()).unwrap();
+    dev.board.program(0, offset_of!(Slot, commit), &COMMIT_MAGIC.to_le_bytes()).unwrap();
+
+    // written again in the current version, the shared settings still in plaintext
+    let mut dev = restart(dev);
+    assert_eq!(dev.diagnostics.version, 1);
+    assert!(wallet(&mut dev).unsealed.is_some());
+    assert_eq!(committed_slots::<MockBoard>().next().map(|(idx, _)| idx), Some(1));
+
+    // the hidden passcode does not seal them
+    dev.board.keys.extend([key(b"11112222"), key(&PASSCODE)]);
+    try_initialize_wallet(&mut dev).unwrap();
+    assert!(dev.board.keys.is_empty());
+
+    let mut dev = restart(dev);
+    assert_eq!(dev.diagnostics.version, FORMAT_VERSION);
+    assert!(wallet(&mut dev).unsealed.is_none());
+    assert_eq!(book(&mut dev, 3, &PASSCODE), [alice]);
+    assert_eq!(book(&mut dev, 4, b"11112222"), []);
+    assert_eq!(addr_list(&mut dev, 5, b"11112222"), hidden);
+    assert_eq!(
+        error(request(&mut dev, 6, &Instruction::SignHash(0, [0x5a; 32]))), 
+        WalletError::BlindSigningDisabled
+    );
+}
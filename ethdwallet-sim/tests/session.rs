@@ -0,0 +1,145 @@
+// a scripted session with the simulator: the keys are typed on its stdin,
+// the requests sent and the answers read on its pseudo-terminal, as the CLI does
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration
+};
+
+use ethdwallet_protocol::{Instruction, Response, Reply, Answer, WalletError, Sink, Source, read_answer};
+
+const PASSCODE: &str = "12345678";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Sim {
+    child: Child,
+    stdin: ChildStdin,
+    port: File,
+    /// bytes read from the port by a thread, so a missing answer times out
+    rx: Receiver<u8>,
+    flash: PathBuf
+}
+
+impl Sim {
+    /// start on a new flash file, the wallet is set up with `PASSCODE`
+    fn start(name: &str, args: &[&str]) -> Self {
+        let flash = std::env::temp_dir().join(format!("ethdwallet-sim-{}-{}.bin", name, std::process::id()));
+        let _ = fs::remove_file(&flash);
+        let mut child = Command::new(env!("CARGO_BIN_EXE_ethdwallet-sim"))
+            .arg("--flash").arg(&flash)
+            .args(args)
+            .stdin(Stdio::piped()).stdout(Stdio::piped())
+            .spawn().unwrap();
+
+        let mut stdin = child.stdin.take().unwrap();
+        // the passcode, then no dice
+        writeln!(stdin, "{}\nn", PASSCODE).unwrap();
+
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        let tty = line.trim().strip_prefix("serial port: ").unwrap().to_string();
+        // keep reading, the simulator must not block on a full pipe
+        thread::spawn(move || std::io::copy(&mut stdout, &mut std::io::sink()));
+
+        let port = OpenOptions::new().read(true).write(true).open(tty).unwrap();
+        let mut reader = port.try_clone().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut byte = [0];
+            while reader.read_exact(&mut byte).is_ok() && tx.send(byte[0]).is_ok() {}
+        });
+        Self { child, stdin, port, rx, flash }
+    }
+
+    fn type_keys(&mut self, line: &str) {
+        writeln!(self.stdin, "{}", line).unwrap();
+    }
+
+    /// send `instr` and return the reply after the ack
+    fn request(&mut self, id: u8, instr: &Instruction) -> Reply {
+        instr.write_request(id, &mut Port(&mut self.port)).unwrap();
+        let mut source = Received(&self.rx);
+        assert_eq!(read_answer(&mut source).unwrap(), (id, Answer::Ack));
+        match read_answer(&mut source).unwrap() {
+            (reply_id, Answer::Reply(reply)) if reply_id == id => reply,
+            answer => panic!("not the reply: {:?}", answer)
+        }
+    }
+}
+
+impl Drop for Sim {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_file(&self.flash);
+    }
+}
+
+struct Port<'a>(&'a mut File);
+
+impl Sink for Port<'_> {
+    type Error = ();
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        self.0.write_all(bytes).map_err(|_| ())
+    }
+}
+
+struct Received<'a>(&'a Receiver<u8>);
+
+impl Source for Received<'_> {
+    type Error = ();
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), ()> {
+        buf.iter_mut().try_for_each(|byte| {
+            *byte = self.0.recv_timeout(TIMEOUT).map_err(|_| ())?;
+            Ok(())
+        })
+    }
+}
+
+#[test]
+fn unlock_and_read_address() {
+    let mut sim = Sim::start("unlock", &[]);
+
+    match sim.request(1, &Instruction::GetInfo) {
+        Ok(Response::Info(info)) => assert!(info.is_compatible() && info.initialized && !info.unlocked),
+        reply => panic!("{:?}", reply)
+    }
+
+    sim.type_keys("87654321");
+    let reply = sim.request(2, &Instruction::Unlock);
+    assert_eq!(reply.unwrap_err().error(), Some(WalletError::WrongPassword));
+
+    sim.type_keys(PASSCODE);
+    assert_eq!(sim.request(3, &Instruction::Unlock), Ok(Response::Done));
+    match sim.request(4, &Instruction::GetInfo) {
+        Ok(Response::Info(info)) => assert!(info.unlocked),
+        reply => panic!("{:?}", reply)
+    }
+
+    let list = match sim.request(5, &Instruction::GetAddressList) {
+        Ok(Response::AddressList(list)) => list,
+        reply => panic!("{:?}", reply)
+    };
+    match sim.request(6, &Instruction::GetAddress(1)) {
+        Ok(Response::Address((addr, _))) => assert_eq!(addr, list[1]),
+        reply => panic!("{:?}", reply)
+    }
+}
+
+#[test]
+fn idle_past_the_iwdg_timeout() {
+    let mut sim = Sim::start("idle", &["--iwdg-ms", "300"]);
+    assert!(sim.request(1, &Instruction::GetInfo).is_ok());
+
+    // nothing from the host for several IWDG periods
+    thread::sleep(Duration::from_millis(1000));
+    assert_eq!(sim.child.try_wait().unwrap(), None);
+    assert!(sim.request(2, &Instruction::GetInfo).is_ok());
+}
@@ -12,15 +12,24 @@ use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
+use std::process::Command;
 
 fn main() {
     dotenv::dotenv().ok();
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let memory: &[u8] = match (
+        env::var_os("CARGO_FEATURE_QEMU"), env::var_os("CARGO_FEATURE_SLOT_B")
+    ) {
+        // QEMU boots the image without the bootloader
+        (Some(_), _) => include_bytes!("memory-qemu.x"),
+        (None, Some(_)) => include_bytes!("memory-b.x"),
+        (None, None) => include_bytes!("memory.x")
+    };
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
@@ -29,4 +38,14 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory-b.x");
+    println!("cargo:rerun-if-changed=memory-qemu.x");
+
+    // the commit is reported by GetInfo, left out if git is missing
+    let head = Command::new("git").args(["rev-parse", "HEAD"]).output().ok();
+    if let Some(head) = head.filter(|out| out.status.success()) {
+        println!("cargo:rustc-env=GIT_HASH={}", String::from_utf8_lossy(&head.stdout).trim());
+    }
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
 }
@@ -0,0 +1,47 @@
+/* the olimex-stm32-h405 machine of QEMU, an STM32F405 with the same memory map */
+MEMORY
+{
+  /* there is no bootloader, the firmware starts at the beginning of the flash
+     and may take sectors 0 to 7 */
+  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 512K
+  /* sector 8, wallet slot A */
+  DATA_A (rw)  : ORIGIN = 0x08080000, LENGTH = 128K
+  /* sector 9, wallet slot B */
+  DATA_B (rw)  : ORIGIN = 0x080A0000, LENGTH = 128K
+  /* sectors 10 and 11, the audit log */
+  LOG_A (rw)  : ORIGIN = 0x080C0000, LENGTH = 128K
+  LOG_B (rw)  : ORIGIN = 0x080E0000, LENGTH = 128K
+  /* RAM begins at 0x20000000 and has a size of 112kB*/
+  RAM : ORIGIN = 0x20000000, LENGTH = 112K
+}
+
+SECTIONS
+{
+  .wallet_a (NOLOAD) : 
+  {
+    . = ALIGN(16);
+    KEEP(*(.wallet_a));
+    . = ALIGN(16);
+  } > DATA_A
+
+  .wallet_b (NOLOAD) : 
+  {
+    . = ALIGN(16);
+    KEEP(*(.wallet_b));
+    . = ALIGN(16);
+  } > DATA_B
+
+  .log_a (NOLOAD) : 
+  {
+    . = ALIGN(16);
+    KEEP(*(.log_a));
+    . = ALIGN(16);
+  } > LOG_A
+
+  .log_b (NOLOAD) : 
+  {
+    . = ALIGN(16);
+    KEEP(*(.log_b));
+    . = ALIGN(16);
+  } > LOG_B
+}
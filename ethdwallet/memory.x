@@ -1,20 +1,48 @@
 /* see https://sciencezero.4hv.org/index.php?title=STM32F407_Microcontroller */
 MEMORY
 {
-  /* Flash memory begins at 0x80000000 and has a size of 1MB*/
-  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 512K
-  /* sector 8 */
-  DATA (rw)  : ORIGIN = 0x08080000, LENGTH = 128K
+  /* Flash memory begins at 0x80000000 and has a size of 1MB.
+     the bootloader takes sectors 0 to 3, this is application slot A (sectors 4 and 5) 
+     after the 512 bytes image header */
+  FLASH (rx) : ORIGIN = 0x08010200, LENGTH = 192K - 0x200
+  /* sector 8, wallet slot A */
+  DATA_A (rw)  : ORIGIN = 0x08080000, LENGTH = 128K
+  /* sector 9, wallet slot B */
+  DATA_B (rw)  : ORIGIN = 0x080A0000, LENGTH = 128K
+  /* sectors 10 and 11, the audit log */
+  LOG_A (rw)  : ORIGIN = 0x080C0000, LENGTH = 128K
+  LOG_B (rw)  : ORIGIN = 0x080E0000, LENGTH = 128K
   /* RAM begins at 0x20000000 and has a size of 112kB*/
   RAM : ORIGIN = 0x20000000, LENGTH = 112K
 }
 
 SECTIONS
 {
-  .wallet : 
+  .wallet_a (NOLOAD) : 
   {
     . = ALIGN(16);
-    KEEP(*(.wallet));
+    KEEP(*(.wallet_a));
     . = ALIGN(16);
-  } > DATA
+  } > DATA_A
+
+  .wallet_b (NOLOAD) : 
+  {
+    . = ALIGN(16);
+    KEEP(*(.wallet_b));
+    . = ALIGN(16);
+  } > DATA_B
+
+  .log_a (NOLOAD) : 
+  {
+    . = ALIGN(16);
+    KEEP(*(.log_a));
+    . = ALIGN(16);
+  } > LOG_A
+
+  .log_b (NOLOAD) : 
+  {
+    . = ALIGN(16);
+    KEEP(*(.log_b));
+    . = ALIGN(16);
+  } > LOG_B
 }
@@ -0,0 +1,121 @@
+// the audit log of signing operations, in flash sectors of its own.
+// entries are only appended, each holds the keccak256 of the one before,
+// so an entry changed or removed breaks the chain.
+// the sectors are filled in turn, the older one is erased when the newer one is full
+use ethdwallet_protocol::{AuditEntry, AuditOp, WalletError, AUDIT_ENTRY_LEN, AUDIT_PAGE_LEN};
+use sha3::{Digest, Keccak256};
+
+use crate::{
+    device::Device,
+    error::Result,
+    hal::{AuditStorage, Hal}
+};
+
+pub const LOG_SECTOR_NUM: usize = 2;
+pub const SECTOR_ENTRIES: usize = 128 * 1024 / AUDIT_ENTRY_LEN;
+
+pub type RawEntry = [u8; AUDIT_ENTRY_LEN];
+
+const ERASED: u32 = 0xffff_ffff;
+
+#[repr(C, align(16))]
+pub struct LogSector {
+    pub entries: [RawEntry; SECTOR_ENTRIES]
+}
+
+impl LogSector {
+    pub const fn erased() -> Self {
+        Self { entries: [[0xff; AUDIT_ENTRY_LEN]; SECTOR_ENTRIES] }
+    }
+
+    /// entries programmed so far, torn ones included
+    fn used(&self) -> usize {
+        self.entries.iter()
+            .rposition(|raw| raw.iter().any(|byte| *byte != 0xff))
+            .map_or(0, |idx| idx + 1)
+    }
+}
+
+/// where the next entry goes
+#[derive(Clone, Copy)]
+pub struct Tail {
+    sector: usize,
+    idx: usize,
+    seq: u32,
+    prev: [u8; 32]
+}
+
+/// the sequence number is programmed last,
+/// an entry without it was torn by a reset and is skipped
+fn seq(raw: &RawEntry) -> Option<u32> {
+    let seq = u32::from_le_bytes(raw[..4].try_into().unwrap());
+    (seq != ERASED).then_some(seq)
+}
+
+/// find the tail after the newest entry in flash
+fn find_tail<B: AuditStorage>() -> Tail {
+    let sectors = B::log_sectors();
+    let newest = sectors.iter().enumerate()
+        .flat_map(|(sector, log)| log.entries.iter().map(move |raw| (sector, raw)))
+        .filter_map(|(sector, raw)| Some((sector, seq(raw)?, raw)))
+        .max_by_key(|(_, seq, _)| *seq);
+
+    match newest {
+        Some((sector, seq, raw)) => Tail {
+            sector,
+            idx: sectors[sector].used(),
+            seq: seq + 1,
+            prev: Keccak256::digest(raw).into()
+        },
+        None => Tail { sector: 0, idx: sectors[0].used(), seq: 0, prev: [0; 32] }
+    }
+}
+
+fn append<B: Hal>(
+    dev: &mut Device<B>, op: AuditOp, account: u8, digest: [u8; 32], result: Option<WalletError>
+) -> Result<()> {
+    let mut tail = dev.audit_tail.unwrap_or_else(find_tail::<B>);
+    if tail.idx == SECTOR_ENTRIES {
+        tail.sector = (tail.sector + 1) % LOG_SECTOR_NUM;
+        tail.idx = 0;
+        dev.board.erase_log(tail.sector)?;
+    }
+
+    let raw = AuditEntry { seq: tail.seq, op, account, result, digest, prev: tail.prev }.encode();
+    let offset = tail.idx * AUDIT_ENTRY_LEN;
+    dev.board.program_log(tail.sector, offset + 4, &raw[4..])?;
+    dev.board.program_log(tail.sector, offset, &raw[..4])?;
+
+    dev.audit_tail = Some(Tail {
+        idx: tail.idx + 1,
+        seq: tail.seq + 1,
+        prev: Keccak256::digest(raw).into(),
+        ..tail
+    });
+    Ok(())
+}
+
+/// run a signing operation and log it whatever the result,
+/// a signature is only sent once its entry is written
+pub fn logged<B: Hal, T>(
+    dev: &mut Device<B>, op: AuditOp, account: u8, digest: [u8; 32],
+    sign: impl FnOnce(&mut Device<B>) -> Result<T>
+) -> Result<T> {
+    let result = sign(dev);
+    append(dev, op, account, digest, result.as_ref().err().map(|e| (*e).into()))?;
+    result
+}
+
+/// the entries from the sequence number `start` on, the oldest first
+pub fn read<B: AuditStorage>(start: u32) -> ([AuditEntry; AUDIT_PAGE_LEN], usize) {
+    let mut sectors = B::log_sectors();
+    sectors.sort_by_key(|log| log.entries.iter().find_map(seq));
+
+    let mut page = [AuditEntry::EMPTY; AUDIT_PAGE_LEN];
+    let entries = sectors.iter()
+        .flat_map(|log| log.entries.iter())
+        .filter(|raw| seq(raw).is_some_and(|seq| seq >= start))
+        .filter_map(AuditEntry::decode);
+    let count = page.iter_mut().zip(entries).map(|(slot, entry)| *slot = entry).count();
+    (page, count)
+}
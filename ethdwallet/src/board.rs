@@ -0,0 +1,455 @@
+use core::{
+    ptr::addr_of,
+    sync::atomic::{self, AtomicBool, AtomicU32, Ordering}
+};
+
+use cortex_m::{peripheral::{SCB, NVIC}, prelude::*};
+use ethdwallet_protocol::CrashReport;
+use heapless::spsc::Consumer;
+use stm32f4::stm32f407::{USART1, TIM1, TIM3, DMA2, GPIOF, EXTI, RTC, Interrupt};
+use stm32f4xx_hal::{
+    gpio::{Input, Pin},
+    serial::{Tx, self, config::InvalidConfig},
+    i2c::{I2c1, self}, flash::{LockedFlash, FlashExt, self}, rng::Rng,
+    rcc::Clocks, timer::Delay, watchdog::IndependentWatchdog, signature::Uid
+};
+
+use crate::{
+    error::{Error, Result},
+    hal::{WalletStorage, AuditStorage, FirmwareStorage, KeyInput, Display, Transport, EntropySource, System},
+    input::{MsgBuffer, MsgBufferState, KeyInputBuffer, KeyInputState, FIXED_KEY_LEN},
+    display::{Screen, DIGIT_NUM},
+    entropy::Trng,
+    firmware::{HEADER_FIELDS_LEN, SLOT_LEN, CONFIRMED_OFFSET, CONFIRMED_MAGIC},
+    tasks::app,
+    init::restore_clocks,
+    wallet::storage::{Slot, SLOT_NUM},
+    audit::{LogSector, LOG_SECTOR_NUM}
+};
+
+pub static LED_STATE: AtomicBool = AtomicBool::new(true);
+
+/// the DMA stream of USART1 RX and its channel
+pub const RX_STREAM: usize = 2;
+pub const RX_CHANNEL: u8 = 4;
+/// DMA wraps around it, longer than a frame
+pub const RX_RING_LEN: usize = 2048;
+pub static mut RX_RING: [u8; RX_RING_LEN] = [0; RX_RING_LEN];
+/// USART1 ran into a framing, noise or overrun error
+pub static RX_ERROR: AtomicBool = AtomicBool::new(false);
+
+pub type I2cPins = (Pin<'B', 6, Input>, Pin<'B', 7, Input>);
+pub type I2cType = I2c1<I2cPins>;
+// I2C1 open :
+// [
+//  false <repeats 56 times>, true,
+//  false <repeats 15 times>, true,
+//  false, false, false, false, true,
+//  false, true, false <repeats 48 times>
+// ]
+
+pub type TIM1Delay = Delay<TIM1, 15000>;
+pub type TIM3Delay = Delay<TIM3, 1000000>;
+
+/// passcodes and decisions finished on the keypad, see `tasks::app::keypad`
+pub const KEY_QUEUE_LEN: usize = 2;
+
+/// the serial task waits this long for the host before the device enters STOP
+pub const STOP_AFTER_MS: u32 = 30_000;
+/// the IWDG resets the device when it is not fed for this long,
+/// it is fed by every wait for the host or a key, so only a stuck request resets
+pub const IWDG_TIMEOUT_MS: u32 = 30_000;
+/// the RTC wakes the device from STOP this often to feed the IWDG,
+/// both count on the LSI, so whatever its frequency the IWDG is fed in time
+pub const STOP_WAKEUP_MS: u32 = 20_000;
+// the IWDG counts up to 4096 ticks of the LSI (32kHz) / 256
+const _: () = assert!(STOP_WAKEUP_MS < IWDG_TIMEOUT_MS && IWDG_TIMEOUT_MS <= 4096 * 256 / 32);
+/// SLEEPDEEP in the system control register, WFI enters STOP instead of sleep
+const SCB_SCR_SLEEPDEEP: u32 = 1 << 2;
+
+/// milliseconds awake since the start, counted by SysTick
+pub static TICKS: AtomicU32 = AtomicU32::new(0);
+
+/// the device is alive, set when the watchdog is fed
+pub static WATCHDOG: AtomicBool = AtomicBool::new(true);
+
+pub const ZLG7290_ADDR: u8 = 0x38;
+/// display ram of the first (leftmost) digit, followed by the other 7 digits
+pub const ZLG7290_DPRAM: u8 = 0x10;
+
+pub const SECTIOR_BASE: usize = 0x0800_0000;
+/// the sectors of wallet slot A and slot B
+pub const WALLET_SECTORS: [u8; SLOT_NUM] = [8, 9];
+pub const LOG_SECTORS: [u8; LOG_SECTOR_NUM] = [10, 11];
+
+// the image layout is defined by ethdwallet-boot, keep these in sync with it
+pub const IMAGE_BASES: [usize; 2] = [0x0801_0000, 0x0804_0000];
+/// slot B only uses `SLOT_LEN` of its sectors, the same as slot A
+pub const IMAGE_SECTORS: [&[u8]; 2] = [&[4, 5], &[6, 7]];
+
+/// These static variables are in the flash memory, so they are persistent.
+/// They are not loaded when the firmware is flashed,
+/// a new device starts with erased sectors
+#[link_section = ".wallet_a"]
+#[no_mangle]
+pub static mut WALLET_A: Slot = Slot::erased();
+
+#[link_section = ".wallet_b"]
+#[no_mangle]
+pub static mut WALLET_B: Slot = Slot::erased();
+
+#[link_section = ".log_a"]
+#[no_mangle]
+pub static mut LOG_A: LogSector = LogSector::erased();
+
+#[link_section = ".log_b"]
+#[no_mangle]
+pub static mut LOG_B: LogSector = LogSector::erased();
+
+/// the STM32F407 on the FS-STM32F407 board, with a ZLG7290 for the keypad
+/// and the display, and the host on USART1.
+///
+/// owned by the serial task, the ZLG7290 belongs to the keypad and display
+/// tasks and is reached by spawning them
+pub struct Board {
+    pub flash: LockedFlash,
+    pub tx: Tx<USART1>,
+    pub trng: Trng<Rng>,
+    pub delay: TIM1Delay,
+    pub iwdg: IndependentWatchdog,
+    pub clocks: Clocks,
+    /// what the keypad task finished, after `listen` asked for it
+    pub keys: Consumer<'static, KeyInputBuffer, KEY_QUEUE_LEN>,
+    /// where the next byte of RX_RING is read from
+    pub rx_tail: usize,
+    /// an animated screen and when it was shown
+    pub screen: Option<(Screen, u32)>,
+    /// the segments last sent to the display task
+    pub frame: [u8; DIGIT_NUM]
+}
+
+impl WalletStorage for Board {
+    fn slots() -> [&'static Slot; SLOT_NUM] {
+        unsafe { [&*addr_of!(WALLET_A), &*addr_of!(WALLET_B)] }
+    }
+
+    fn erase(&mut self, slot: usize) -> Result<()> {
+        self.flash.unlocked().erase(WALLET_SECTORS[slot])?;
+        Ok(())
+    }
+
+    fn program(&mut self, slot: usize, offset: usize, data: &[u8]) -> Result<()> {
+        let addr = Self::slots()[slot] as *const Slot as usize + offset;
+        self.flash.unlocked().program(addr - SECTIOR_BASE, data.iter())?;
+        set_led(true);
+        Ok(())
+    }
+}
+
+impl AuditStorage for Board {
+    fn log_sectors() -> [&'static LogSector; LOG_SECTOR_NUM] {
+        unsafe { [&*addr_of!(LOG_A), &*addr_of!(LOG_B)] }
+    }
+
+    fn erase_log(&mut self, sector: usize) -> Result<()> {
+        self.flash.unlocked().erase(LOG_SECTORS[sector])?;
+        Ok(())
+    }
+
+    fn program_log(&mut self, sector: usize, offset: usize, data: &[u8]) -> Result<()> {
+        let addr = Self::log_sectors()[sector] as *const LogSector as usize + offset;
+        self.flash.unlocked().program(addr - SECTIOR_BASE, data.iter())?;
+        Ok(())
+    }
+}
+
+impl FirmwareStorage for Board {
+    fn image_header(slot: usize) -> [u8; HEADER_FIELDS_LEN] {
+        unsafe { *(IMAGE_BASES[slot] as *const [u8; HEADER_FIELDS_LEN]) }
+    }
+
+    fn image_confirmed(slot: usize) -> bool {
+        let confirmed = unsafe { *((IMAGE_BASES[slot] + CONFIRMED_OFFSET) as *const u32) };
+        confirmed == CONFIRMED_MAGIC
+    }
+
+    fn erase_image(&mut self, slot: usize) -> Result<()> {
+        let mut unlocked = self.flash.unlocked();
+        IMAGE_SECTORS[slot].iter().try_for_each(|sector| {
+            unlocked.erase(*sector)
+        })?;
+        Ok(())
+    }
+
+    fn program_image(&mut self, slot: usize, offset: usize, data: &[u8]) -> Result<()> {
+        if offset + data.len() > SLOT_LEN {
+            return Err(Error::Flash)
+        }
+        self.flash.unlocked().program(
+            IMAGE_BASES[slot] + offset - SECTIOR_BASE, data.iter()
+        )?;
+        Ok(())
+    }
+}
+
+impl KeyInput for Board {
+    /// the keypad task echoes the digits, the screen stops
+    fn wait_for_key(&mut self) -> [u8; FIXED_KEY_LEN] {
+        self.screen = None;
+        self.wait_until(KeyInputState::Reading(0), |buf| match buf.state {
+            KeyInputState::Finished => Some(buf.buf),
+            _ => None
+        })
+    }
+
+    fn wait_for_confirm(&mut self) -> bool {
+        self.wait_until(KeyInputState::Confirming, |buf| match buf.state {
+            KeyInputState::Decided(accepted) => Some(accepted),
+            _ => None
+        })
+    }
+}
+
+impl Board {
+    /// have the keypad task read keys from `state` on, until `f` returns
+    /// some result from what it finished
+    fn wait_until<T, F>(&mut self, state: KeyInputState, mut f: F) -> T
+    where
+        F: FnMut(&KeyInputBuffer) -> Option<T>
+    {
+        // the keypad task is idle between two waits, there is room to spawn it
+        let _ = app::listen::spawn(state);
+
+        loop {
+            match self.keys.dequeue() {
+                Some(buf) => if let Some(result) = f(&buf) {
+                    return result
+                },
+                None => {
+                    // waiting for the user is not a hang
+                    self.feed_watchdog();
+                    self.animate();
+                    cortex_m::asm::wfi()
+                }
+            }
+        }
+    }
+
+    /// enter STOP until the host starts a frame or a key is pressed,
+    /// then run on the clocks of `clock_init` again.
+    ///
+    /// SysTick, TIM2 and DMA have no clock in STOP, so `millis` and the soft watchdog
+    /// pause, the IWDG keeps counting on the LSI and the RTC wakes the device
+    /// to feed it. the byte which woke the device is lost, the host gets a NAK
+    /// or no answer and sends the request again
+    fn stop(&mut self) {
+        // a blinking screen is not left dark
+        if let Some((screen, _)) = self.screen {
+            let _ = self.paint(screen.frame(0));
+        }
+
+        let exti = unsafe { &*EXTI::ptr() };
+        // the interrupt waking the device runs once the clocks are back
+        cortex_m::interrupt::free(|_| {
+            exti.pr.write(|w| w.pr10().set_bit());
+            exti.imr.modify(|_, w| w.mr10().set_bit());
+            unsafe { (*SCB::PTR).scr.modify(|scr| scr | SCB_SCR_SLEEPDEEP) };
+            loop {
+                self.iwdg.feed();
+                cortex_m::asm::dsb();
+                cortex_m::asm::wfi();
+                // only the RTC woke the device, STOP again on the HSI
+                let pr = exti.pr.read();
+                if pr.pr22().bit_is_clear() || pr.pr10().bit_is_set() || pr.pr13().bit_is_set() {
+                    break
+                }
+                clear_rtc_wakeup();
+                NVIC::unpend(Interrupt::RTC_WKUP);
+            }
+            unsafe { (*SCB::PTR).scr.modify(|scr| scr & !SCB_SCR_SLEEPDEEP) };
+
+            restore_clocks();
+            exti.imr.modify(|_, w| w.mr10().clear_bit());
+            exti.pr.write(|w| w.pr10().set_bit());
+            // EXTI15_10 is the keypad's, unless a key woke the device as well
+            if exti.pr.read().pr13().bit_is_clear() {
+                NVIC::unpend(Interrupt::EXTI15_10);
+            }
+        });
+    }
+}
+
+impl Display for Board {
+    fn show(&mut self, screen: &Screen) -> Result<()> {
+        self.screen = screen.is_animated().then_some((*screen, Self::millis()));
+        self.paint(screen.frame(0))
+    }
+}
+
+impl Board {
+    /// drawn by the display task, which resets the bus if the ZLG7290 does not answer
+    fn paint(&mut self, segs: [u8; DIGIT_NUM]) -> Result<()> {
+        self.frame = segs;
+        app::show::spawn(segs).map_err(|_| Error::I2cError)
+    }
+
+    /// send the frame due of an animated screen, if it changed
+    fn animate(&mut self) {
+        if let Some((screen, since)) = self.screen {
+            let segs = screen.frame(Self::millis().wrapping_sub(since));
+            if segs != self.frame {
+                let _ = self.paint(segs);
+            }
+        }
+    }
+}
+
+impl Transport for Board {
+    fn receive(&mut self, deadline: Option<u32>) -> Option<MsgBuffer> {
+        let mut buf = MsgBuffer::new();
+        let mut idle_since = Self::millis();
+        loop {
+            // the RX stream only belongs to the DMA interrupt, reading NDTR is harmless
+            let dma = unsafe { &*DMA2::ptr() };
+            let head = (RX_RING_LEN - dma.st[RX_STREAM].ndtr.read().ndt().bits() as usize)
+                % RX_RING_LEN;
+            let tail = self.rx_tail;
+            // the bytes before `head` are written by DMA
+            atomic::fence(Ordering::Acquire);
+
+            if head != tail {
+                let end = if head > tail { head } else { RX_RING_LEN };
+                let bytes = unsafe { &(&*addr_of!(RX_RING))[tail..end] };
+                let taken = buf.read(bytes, Self::millis());
+                self.rx_tail = (tail + taken) % RX_RING_LEN;
+                idle_since = Self::millis();
+            }
+            if RX_ERROR.swap(false, Ordering::Relaxed) {
+                buf.state = MsgBufferState::Damaged;
+            }
+            if buf.done() {
+                return Some(buf)
+            }
+            if buf.timed_out(deadline, Self::millis()) {
+                return None
+            }
+
+            if head == tail {
+                // nothing is on the way and no deadline runs on SysTick
+                let idle = matches!(buf.state, MsgBufferState::PendingStart) && deadline.is_none()
+                    && Self::millis().wrapping_sub(idle_since) >= STOP_AFTER_MS;
+                if idle {
+                    self.stop();
+                    idle_since = Self::millis();
+                } else {
+                    self.animate();
+                    // woken by the idle line, half of the ring or SysTick
+                    cortex_m::asm::wfi();
+                }
+                // waiting for the host is not a hang
+                self.feed_watchdog();
+            }
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.tx.bwrite_all(bytes)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.tx.bflush()?;
+        Ok(())
+    }
+
+    /// oversampling by 16 as `serial_init` sets up, fine up to PCLK2 / 16
+    fn set_baud_rate(&mut self, baud: u32) -> Result<()> {
+        let pclk = self.clocks.pclk2().raw();
+        if pclk / 16 < baud {
+            return Err(Error::InvalidSerialConfig)
+        }
+        let usart = unsafe { &*USART1::ptr() };
+        usart.brr.write(|w| unsafe { w.bits((pclk + baud / 2) / baud) });
+        Ok(())
+    }
+}
+
+impl EntropySource for Board {
+    fn fill(&mut self, dest: &mut [u8]) -> Result<()> {
+        self.trng.fill(dest)
+    }
+}
+
+impl System for Board {
+    fn delay_us(&mut self, us: u32) {
+        self.delay.delay_us(us);
+    }
+
+    fn millis() -> u32 {
+        TICKS.load(Ordering::Relaxed)
+    }
+
+    fn unique_id() -> [u8; 12] {
+        let uid = Uid::get();
+        let mut id = [0; 12];
+        id[..2].copy_from_slice(&uid.x().to_le_bytes());
+        id[2..4].copy_from_slice(&uid.y().to_le_bytes());
+        id[4] = uid.waf_num();
+        id[5..].copy_from_slice(uid.lot_num().as_bytes());
+        id
+    }
+
+    fn feed_watchdog(&mut self) {
+        self.iwdg.feed();
+        WATCHDOG.store(true, Ordering::SeqCst);
+    }
+
+    fn reset() -> ! {
+        SCB::sys_reset()
+    }
+
+    fn take_crash_report(&mut self) -> Option<CrashReport> {
+        crate::crash::take()
+    }
+}
+
+/// the RTC wakeup timer ran out, see `STOP_WAKEUP_MS`.
+/// WUTF is not write protected
+pub fn clear_rtc_wakeup() {
+    let (rtc, exti) = unsafe { (&*RTC::ptr(), &*EXTI::ptr()) };
+    rtc.isr.modify(|_, w| w.wutf().clear_bit());
+    exti.pr.write(|w| w.pr22().set_bit());
+}
+
+/// the LED on PF10, written through BSRR so that any task can set it
+pub fn set_led(on: bool) {
+    let gpiof = unsafe { &*GPIOF::ptr() };
+    gpiof.bsrr.write(|w| match on {
+        true => w.bs10().set_bit(),
+        false => w.br10().set_bit()
+    });
+}
+
+impl From<i2c::Error> for Error {
+    fn from(_: i2c::Error) -> Self {
+        Self::I2cError
+    }
+}
+
+impl From<serial::Error> for Error {
+    fn from(_: serial::Error) -> Self {
+        Self::SerialTxError
+    }
+}
+
+impl From<flash::Error> for Error {
+    fn from(_: flash::Error) -> Self {
+        Self::Flash
+    }
+}
+
+impl From<InvalidConfig> for Error {
+    fn from(_: InvalidConfig) -> Self {
+        Self::InvalidSerialConfig
+    }
+}
@@ -0,0 +1,134 @@
+// the report of the last crash, kept in the backup SRAM through the reset which follows.
+// a panic or a HardFault writes it and resets, the start after it adds the reset flags
+// of RCC_CSR, or writes a report of its own when the IWDG reset the device.
+// it is kept until the host reads it with GetCrashReport
+use core::{fmt::{self, Write}, panic::PanicInfo, ptr};
+
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::{exception, ExceptionFrame};
+use ethdwallet_protocol::{CrashReport, CrashCause, CRASH_REPORT_LEN};
+use stm32f4::stm32f407::{RCC, PWR};
+
+/// start of the 4K backup SRAM
+const BKPSRAM: usize = 0x4002_4000;
+/// a report is stored, the SRAM holds anything after a power loss
+const MAGIC: u32 = 0x4352_4153;
+/// IWDGRSTF of RCC_CSR >> 24
+const IWDG_RESET: u8 = 1 << 5;
+
+#[repr(C)]
+struct Stored {
+    magic: u32,
+    crc: u32,
+    report: [u8; CRASH_REPORT_LEN]
+}
+
+fn stored() -> *mut Stored {
+    BKPSRAM as *mut Stored
+}
+
+/// clock the backup SRAM and allow writes to it, nothing needs to be set up before
+fn unlock() {
+    let (rcc, pwr) = unsafe { (&*RCC::ptr(), &*PWR::ptr()) };
+    rcc.apb1enr.modify(|_, w| w.pwren().enabled());
+    pwr.cr.modify(|_, w| w.dbp().set_bit());
+    rcc.ahb1enr.modify(|_, w| w.bkpsramen().enabled());
+}
+
+fn load() -> Option<CrashReport> {
+    let stored = unsafe { ptr::read_volatile(stored()) };
+    match stored.magic == MAGIC && stored.crc == crc32fast::hash(&stored.report) {
+        true => CrashReport::decode(&stored.report),
+        false => None
+    }
+}
+
+fn store(report: &CrashReport) {
+    let report = report.encode();
+    let stored = Stored { magic: MAGIC, crc: crc32fast::hash(&report), report };
+    unsafe { ptr::write_volatile(self::stored(), stored) }
+}
+
+/// the fault status and address registers, whatever the cause
+fn with_fault_status(cause: CrashCause) -> CrashReport {
+    let scb = unsafe { &*SCB::PTR };
+    let mut report = CrashReport::new(cause);
+    report.cfsr = scb.cfsr.read();
+    report.hfsr = scb.hfsr.read();
+    report.mmfar = scb.mmfar.read();
+    report.bfar = scb.bfar.read();
+    report
+}
+
+/// keep `report` and reset
+fn crash(report: &CrashReport) -> ! {
+    cortex_m::interrupt::disable();
+    unlock();
+    store(report);
+    SCB::sys_reset()
+}
+
+/// called by the start, before anything can crash again
+pub fn record_reset(rcc: &RCC) {
+    unlock();
+    let flags = (rcc.csr.read().bits() >> 24) as u8;
+    rcc.csr.modify(|_, w| w.rmvf().set_bit());
+
+    match load() {
+        Some(mut report) if report.reset_flags == 0 => {
+            report.reset_flags = flags;
+            store(&report);
+        },
+        _ if flags & IWDG_RESET != 0 => {
+            let mut report = with_fault_status(CrashCause::Iwdg);
+            report.reset_flags = flags;
+            store(&report);
+        },
+        // an older report not read yet
+        _ => {}
+    }
+}
+
+/// the report of the last crash, cleared once taken
+pub fn take() -> Option<CrashReport> {
+    unlock();
+    let report = load();
+    unsafe { ptr::write_volatile(ptr::addr_of_mut!((*stored()).magic), 0) }
+    report
+}
+
+/// a watchdog other than the IWDG found the device stuck
+pub fn reset(cause: CrashCause) -> ! {
+    crash(&with_fault_status(cause))
+}
+
+struct Message<'a>(&'a mut CrashReport);
+
+impl Write for Message<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.push_message(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    let mut report = with_fault_status(CrashCause::Panic);
+    let mut message = Message(&mut report);
+    if let Some(location) = info.location() {
+        let _ = write!(message, "{}:{}: ", location.file(), location.line());
+    }
+    let _ = write!(message, "{}", info.message());
+    crash(&report)
+}
+
+#[allow(non_snake_case)]
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    let mut report = with_fault_status(CrashCause::HardFault);
+    report.pc = frame.pc();
+    report.lr = frame.lr();
+    report.xpsr = frame.xpsr();
+    crash(&report)
+}
@@ -0,0 +1,77 @@
+// everything the protocol owns: the board and the state of the wallet and the session.
+// it is handed down as `&mut Device` to whatever needs either of them,
+// nothing is shared with the interrupts. `B` is the backend, see `hal`
+use chacha20::ChaCha20;
+use ethdwallet_protocol::Reply;
+use rand_chacha::ChaCha20Rng;
+
+use crate::{
+    audit::Tail,
+    error::{Error, Result},
+    hal::Hal,
+    firmware::UpdateProgress,
+    signing::SignSession,
+    wallet::{Wallet, recovery::Diagnostics}
+};
+
+pub struct Device<B: Hal> {
+    pub board: B,
+    pub rng: ChaCha20Rng,
+
+    /// the zone opened by the passcode along with its cipher
+    pub cipher: Option<(usize, ChaCha20)>,
+    /// set when the duress passcode asks to erase the real zones
+    pub pending_wipe: bool,
+
+    /// the slot the wallet in use is read from, written slots become active
+    pub active_slot: usize,
+    /// the sequence number last written, not every backend can read flash back
+    /// before the next start (QEMU maps it as ROM)
+    pub written_seq: Option<u32>,
+    /// recovered from flash on the first use, see `wallet::wallet`
+    pub wallet: Option<Wallet>,
+    pub diagnostics: Diagnostics,
+
+    pub firmware_update: Option<UpdateProgress>,
+    /// set when an update is written, the device resets after the response is sent
+    pub pending_reset: bool,
+    /// set by SetBaudRate, the port switches after the response is sent
+    pub pending_baud: Option<u32>,
+
+    pub sign_session: Option<SignSession>,
+    /// where the next entry of the audit log goes, found in flash on the first one
+    pub audit_tail: Option<Tail>,
+
+    /// the id and the CRC of the last request with its reply,
+    /// sent again if the host repeats the request because the reply was lost
+    pub last_reply: Option<(u8, u32, Reply)>
+}
+
+impl<B: Hal> Device<B> {
+    pub fn new(board: B, rng: ChaCha20Rng) -> Self {
+        Self {
+            board,
+            rng,
+            cipher: None,
+            pending_wipe: false,
+            active_slot: 0,
+            written_seq: None,
+            wallet: None,
+            diagnostics: Diagnostics::new(),
+            firmware_update: None,
+            pending_reset: false,
+            pending_baud: None,
+            sign_session: None,
+            audit_tail: None,
+            last_reply: None
+        }
+    }
+
+    /// the unlocked zone and its cipher
+    pub fn unlocked(&mut self) -> Result<(usize, &mut ChaCha20)> {
+        match &mut self.cipher {
+            Some((zone, cipher)) => Ok((*zone, cipher)),
+            None => Err(Error::WrongPassword)
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use crate::{
+    error::Result,
+    hal::Display
+};
+
+pub const DIGIT_NUM: usize = 8;
+
+macro_rules! conv_seg7 {
+    ($($e:expr),*) => {
+        $(
+            1 << $e
+        )|*
+    }
+}
+
+pub const SEG7_PLACEHOLDER: u8 = 1  << 1;
+pub const SEG7_BLANK: u8 = 0;
+/// the decimal point, right of the digit
+pub const SEG7_POINT: u8 = 1 << 0;
+
+/// encode an ascii character for the segment display, letters are matched
+/// whatever their case and the ones without a glyph (M, V, W, X, Z) are rendered blank
+/// ```
+/// ----7----
+/// |       |
+/// 2       6
+/// |       |
+/// |---1---|
+/// |       |
+/// 3       5
+/// |       |
+/// ----4---- 0
+/// ```
+pub const fn glyph(ch: u8) -> u8 {
+    match ch.to_ascii_uppercase() {
+        b'0' => conv_seg7!(2, 3, 4, 5, 6, 7),
+        b'1' => conv_seg7!(5, 6),
+        b'2' => conv_seg7!(1, 7, 3, 4, 6),
+        b'3' => conv_seg7!(5, 6, 1, 7, 4),
+        b'4' => conv_seg7!(1, 2, 5, 6),
+        b'5' => conv_seg7!(5, 4, 1, 7, 2),
+        b'6' => conv_seg7!(5, 4, 3, 2, 1, 7),
+        b'7' => conv_seg7!(5, 6, 7),
+        b'8' => conv_seg7!(1, 2, 3, 4, 5, 6, 7),
+        b'9' => conv_seg7!(1, 2, 4, 5, 6, 7),
+        b'A' => conv_seg7!(1, 2, 3, 5, 6, 7),
+        b'B' => conv_seg7!(1, 2, 3, 4, 5),
+        b'C' => conv_seg7!(2, 3, 4, 7),
+        b'D' => conv_seg7!(1, 3, 4, 5, 6),
+        b'E' => conv_seg7!(1, 2, 3, 4, 7),
+        b'F' => conv_seg7!(1, 2, 3, 7),
+        b'H' => conv_seg7!(1, 2, 3, 5, 6),
+        b'I' => conv_seg7!(2, 3),
+        b'L' => conv_seg7!(2, 3, 4),
+        b'N' => conv_seg7!(1, 3, 5),
+        b'P' => conv_seg7!(1, 2, 3, 6, 7),
+        b'R' => conv_seg7!(1, 3),
+        b'T' => conv_seg7!(1, 2, 3, 4),
+        b'Y' => conv_seg7!(1, 2, 4, 5, 6),
+        b'O' => glyph(b'0'),
+        b'S' => glyph(b'5'),
+        b'G' => conv_seg7!(2, 3, 4, 5, 7),
+        b'J' => conv_seg7!(3, 4, 5, 6),
+        b'K' => conv_seg7!(1, 2, 3, 5, 7),
+        b'Q' => conv_seg7!(1, 2, 5, 6, 7),
+        b'U' => conv_seg7!(2, 3, 4, 5, 6),
+        b'-' => SEG7_PLACEHOLDER,
+        b'_' => conv_seg7!(4),
+        b'=' => conv_seg7!(1, 4),
+        b'?' => conv_seg7!(1, 3, 6, 7),
+        b'.' => SEG7_POINT,
+        _ => SEG7_BLANK
+    }
+}
+
+/// the longest text on a screen, an address in hex with a few words
+pub const SCREEN_LEN: usize = 48;
+/// a scrolling screen moves by a digit every
+pub const SCROLL_MS: u32 = 400;
+/// a blinking screen is on, then off, for
+pub const BLINK_MS: u32 = 500;
+/// blanks between the end of a scrolling text and its start again
+const SCROLL_GAP: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Effect {
+    Still,
+    /// from right to left, only if the text does not fit
+    Scroll,
+    Blink
+}
+
+/// what the display shows, backends able to animate it
+/// draw `frame` as the time passes
+#[derive(Clone, Copy)]
+pub struct Screen {
+    segs: [u8; SCREEN_LEN],
+    len: usize,
+    effect: Effect
+}
+
+impl Screen {
+    /// ascii text cut to `SCREEN_LEN` digits, a '.' lights the point of the digit before it
+    pub fn text(text: &[u8]) -> Self {
+        let mut screen = Self { segs: [SEG7_BLANK; SCREEN_LEN], len: 0, effect: Effect::Still };
+        for ch in text {
+            match (ch, screen.len) {
+                (b'.', len) if len > 0 && screen.segs[len - 1] & SEG7_POINT == 0 => {
+                    screen.segs[len - 1] |= SEG7_POINT
+                },
+                (_, SCREEN_LEN) => break,
+                (ch, len) => {
+                    screen.segs[len] = glyph(*ch);
+                    screen.len += 1;
+                }
+            }
+        }
+        screen
+    }
+
+    /// every byte as two hex digits
+    pub fn hex(data: &[u8]) -> Self {
+        const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+        let mut text = [0; SCREEN_LEN];
+        let len = data.len().min(SCREEN_LEN / 2);
+        text.chunks_mut(2).zip(&data[..len]).for_each(|(digits, byte)| {
+            digits[0] = HEX[(byte >> 4) as usize];
+            digits[1] = HEX[(byte & 0xf) as usize];
+        });
+        Self::text(&text[..len * 2])
+    }
+
+    pub fn scrolling(self) -> Self {
+        Self { effect: Effect::Scroll, ..self }
+    }
+
+    pub fn blinking(self) -> Self {
+        Self { effect: Effect::Blink, ..self }
+    }
+
+    /// the frames change over time
+    pub fn is_animated(&self) -> bool {
+        match self.effect {
+            Effect::Still => false,
+            Effect::Scroll => self.len > DIGIT_NUM,
+            Effect::Blink => true
+        }
+    }
+
+    /// the digits shown `elapsed` milliseconds after the screen
+    pub fn frame(&self, elapsed: u32) -> [u8; DIGIT_NUM] {
+        let mut segs = [SEG7_BLANK; DIGIT_NUM];
+        let text = &self.segs[..self.len];
+        match self.effect {
+            Effect::Blink if (elapsed / BLINK_MS) % 2 == 1 => {},
+            Effect::Scroll if self.len > DIGIT_NUM => {
+                let period = self.len + SCROLL_GAP;
+                let shift = (elapsed / SCROLL_MS) as usize % period;
+                segs.iter_mut().enumerate().for_each(|(idx, seg)| {
+                    *seg = text.get((shift + idx) % period).copied().unwrap_or(SEG7_BLANK);
+                });
+            },
+            _ => segs.iter_mut().zip(text).for_each(|(seg, text)| *seg = *text)
+        }
+        segs
+    }
+
+    /// the text cut into displays, for backends which cannot animate
+    #[allow(dead_code)]
+    pub fn pages(&self) -> impl Iterator<Item = [u8; DIGIT_NUM]> + '_ {
+        let count = self.len.div_ceil(DIGIT_NUM).max(1);
+        (0..count).map(|page| {
+            let mut segs = [SEG7_BLANK; DIGIT_NUM];
+            segs.iter_mut().zip(&self.segs[page * DIGIT_NUM..self.len])
+                .for_each(|(seg, text)| *seg = *text);
+            segs
+        })
+    }
+}
+
+/// what the wallet tells the user without being asked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// the passcode is asked before the next signing
+    Locked,
+    /// sign a hash without knowing what it is
+    BlindSign,
+    /// the last request failed, with the code of `WalletError`
+    Error(u8)
+}
+
+impl Status {
+    pub fn screen(self) -> Screen {
+        match self {
+            Self::Locked => Screen::text(b"LOCKED"),
+            Self::BlindSign => Screen::text(b"bLInd SIGN?").scrolling(),
+            Self::Error(code) => {
+                Screen::text(&[b'E', b'r', b'r', b' ', b'0' + code / 10 % 10, b'0' + code % 10])
+                    .blinking()
+            }
+        }
+    }
+}
+
+/// show up to 8 ascii characters, the rest of the display is cleared
+pub fn show_text(board: &mut impl Display, text: &[u8]) -> Result<()> {
+    board.show(&Screen::text(text))
+}
+
+/// show `data` as hex digits, scrolling if it does not fit
+pub fn show_hex(board: &mut impl Display, data: &[u8]) -> Result<()> {
+    board.show(&Screen::hex(data).scrolling())
+}
+
+pub fn show_status(board: &mut impl Display, status: Status) -> Result<()> {
+    board.show(&status.screen())
+}
+
+/// columns taken by a digit drawn by `draw`
+pub const DRAWN_WIDTH: usize = 4;
+
+/// draw the segments as three lines of ascii, for backends printing
+/// to a terminal instead of driving a display
+#[allow(dead_code)]
+pub fn draw(segs: [u8; DIGIT_NUM]) -> [[u8; DIGIT_NUM * DRAWN_WIDTH]; 3] {
+    let mut lines = [[b' '; DIGIT_NUM * DRAWN_WIDTH]; 3];
+    for (idx, seg) in segs.iter().enumerate() {
+        let lit = |bit: u8, ch: u8| if seg & (1 << bit) != 0 { ch } else { b' ' };
+        let col = idx * DRAWN_WIDTH;
+        lines[0][col + 1] = lit(7, b'_');
+        lines[1][col..col + 3].copy_from_slice(&[lit(2, b'|'), lit(1, b'_'), lit(6, b'|')]);
+        lines[2][col..col + 4].copy_from_slice(
+            &[lit(3, b'|'), lit(4, b'_'), lit(5, b'|'), lit(0, b'.')]
+        );
+    }
+    lines
+}
@@ -0,0 +1,117 @@
+use rand::{Rng as _, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha3::{Keccak256, Digest};
+
+use crate::{
+    device::Device,
+    error::{Error, Result},
+    hal::Hal
+};
+
+/// min-entropy claimed for every byte of the TRNG, in bits
+const MIN_ENTROPY: usize = 4;
+/// repetition count test, 1 + ceil(20 / H) for a false positive rate of 2^-20
+const RCT_CUTOFF: usize = 1 + 20usize.div_ceil(MIN_ENTROPY);
+/// adaptive proportion test window for non-binary samples
+const APT_WINDOW: usize = 512;
+/// critical value of the binomial distribution for W = 512, H = 4 and alpha = 2^-20,
+/// see table 2 of NIST SP 800-90B
+const APT_CUTOFF: usize = 62;
+/// number of samples tested before the TRNG is used
+const STARTUP_SAMPLES: usize = 1024;
+
+/// NIST SP 800-90B health tests on the raw bytes of the TRNG
+pub struct HealthTest {
+    last: u8,
+    repeats: usize,
+    /// the first sample of the current window
+    apt_sample: u8,
+    apt_count: usize,
+    apt_seen: usize
+}
+
+impl HealthTest {
+    pub const fn new() -> Self {
+        Self {
+            last: 0,
+            repeats: 0,
+            apt_sample: 0,
+            apt_count: 0,
+            apt_seen: APT_WINDOW
+        }
+    }
+
+    /// run both tests on a sample, returns false if any of them fails
+    pub fn feed(&mut self, sample: u8) -> bool {
+        if self.repeats > 0 && sample == self.last {
+            self.repeats += 1;
+        } else {
+            self.last = sample;
+            self.repeats = 1;
+        }
+
+        if self.apt_seen == APT_WINDOW {
+            self.apt_sample = sample;
+            self.apt_count = 1;
+            self.apt_seen = 1;
+        } else {
+            self.apt_seen += 1;
+            if sample == self.apt_sample {
+                self.apt_count += 1;
+            }
+        }
+
+        self.repeats < RCT_CUTOFF && self.apt_count < APT_CUTOFF
+    }
+}
+
+/// a hardware TRNG, every byte it produces goes through the health tests.
+/// a failure is permanent until reset
+pub struct Trng<R: RngCore> {
+    rng: R,
+    health: HealthTest,
+    failed: bool
+}
+
+impl<R: RngCore> Trng<R> {
+    pub fn new(rng: R) -> Self {
+        Self { rng, health: HealthTest::new(), failed: false }
+    }
+
+    /// the startup test, samples are drawn and discarded
+    pub fn startup(&mut self) -> Result<()> {
+        let mut samples = [0; 64];
+        for _ in 0..STARTUP_SAMPLES / samples.len() {
+            self.fill(&mut samples)?;
+        }
+        Ok(())
+    }
+
+    pub fn fill(&mut self, dest: &mut [u8]) -> Result<()> {
+        if !self.failed {
+            // seed and clock errors of the peripheral fail the tests as well
+            self.failed = self.rng.try_fill_bytes(dest).is_err()
+                || !dest.iter().all(|sample| self.health.feed(*sample));
+        }
+
+        match self.failed {
+            true => Err(Error::EntropyFailure),
+            false => Ok(())
+        }
+    }
+}
+
+/// mix fresh output of the TRNG and `user_entropy` into the RNG.
+/// must succeed before any key is generated
+pub fn reseed<B: Hal>(dev: &mut Device<B>, user_entropy: &[u8]) -> Result<()> {
+    let mut fresh = [0; 32];
+    dev.board.fill(&mut fresh)?;
+
+    let seed = Keccak256::new()
+        .chain_update(dev.rng.gen::<[u8; 32]>())
+        .chain_update(fresh)
+        .chain_update(user_entropy)
+        .finalize();
+    dev.rng = ChaCha20Rng::from_seed(seed.into());
+    Ok(())
+}
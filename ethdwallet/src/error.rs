@@ -1,10 +1,8 @@
-use stm32f4xx_hal::{serial::{
-    config::InvalidConfig,
-    self
-}, i2c};
+use ethdwallet_protocol::WalletError;
 
 #[repr(u8)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     HalInitError,
     AccountIdxOOB,
@@ -15,12 +13,57 @@ pub enum Error {
     WrongPassword,
     SerialTxError,
     I2cError,
-    WalletNotInitialized
+    WalletNotInitialized,
+    BookFull,
+    BookIdxOOB,
+    BookEntryExists,
+    InvalidTransaction,
+    RecipientNotAllowed,
+    BlindSigningDisabled,
+    UserRejected,
+    InvalidCiphertext,
+    EntropyFailure,
+    WalletCorrupted,
+    UnsupportedFormat,
+    InvalidFirmware,
+    FirmwareRollback,
+    FirmwareIncomplete,
+    Flash,
+    SigningIncomplete
 }
 
-impl From<i2c::Error> for Error {
-    fn from(_: i2c::Error) -> Self {
-        Self::I2cError
+/// every error has a code on the wire, adding one here needs a new code
+/// in ethdwallet-protocol
+impl From<Error> for WalletError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::HalInitError => Self::HalInitError,
+            Error::AccountIdxOOB => Self::AccountIdxOOB,
+            Error::CryptoError => Self::CryptoError,
+            Error::InvalidSerialConfig => Self::InvalidSerialConfig,
+            Error::InvalidInstruction => Self::InvalidInstruction,
+            Error::SerialDataCorrupted => Self::SerialDataCorrupted,
+            Error::WrongPassword => Self::WrongPassword,
+            Error::SerialTxError => Self::SerialTxError,
+            Error::I2cError => Self::I2cError,
+            Error::WalletNotInitialized => Self::WalletNotInitialized,
+            Error::BookFull => Self::BookFull,
+            Error::BookIdxOOB => Self::BookIdxOOB,
+            Error::BookEntryExists => Self::BookEntryExists,
+            Error::InvalidTransaction => Self::InvalidTransaction,
+            Error::RecipientNotAllowed => Self::RecipientNotAllowed,
+            Error::BlindSigningDisabled => Self::BlindSigningDisabled,
+            Error::UserRejected => Self::UserRejected,
+            Error::InvalidCiphertext => Self::InvalidCiphertext,
+            Error::EntropyFailure => Self::EntropyFailure,
+            Error::WalletCorrupted => Self::WalletCorrupted,
+            Error::UnsupportedFormat => Self::UnsupportedFormat,
+            Error::InvalidFirmware => Self::InvalidFirmware,
+            Error::FirmwareRollback => Self::FirmwareRollback,
+            Error::FirmwareIncomplete => Self::FirmwareIncomplete,
+            Error::Flash => Self::FlashError,
+            Error::SigningIncomplete => Self::SigningIncomplete
+        }
     }
 }
 
@@ -30,21 +73,15 @@ impl From<&mut Error> for Error  {
     }
 }
 
-impl From<serial::Error> for Error {
-    fn from(_: serial::Error) -> Self {
-        Self::SerialTxError
-    }
-}
-
 impl From<k256::ecdsa::Error> for Error {
     fn from(_: k256::ecdsa::Error) -> Self {
         Self::CryptoError
     }
 }
 
-impl From<InvalidConfig> for Error {
-    fn from(_: InvalidConfig) -> Self {
-        Self::InvalidSerialConfig
+impl From<k256::elliptic_curve::Error> for Error {
+    fn from(_: k256::elliptic_curve::Error) -> Self {
+        Self::CryptoError
     }
 }
 
@@ -0,0 +1,122 @@
+use crate::{
+    device::Device,
+    error::{Error, Result},
+    hal::{FirmwareStorage, Hal},
+    display::show_text
+};
+
+// the image layout is defined by ethdwallet-boot, keep these in sync with it
+pub const IMAGE_MAGIC: [u8; 4] = *b"EWFW";
+pub const HEADER_LEN: usize = 0x200;
+pub use ethdwallet_protocol::HEADER_FIELDS_LEN;
+pub const SLOT_LEN: usize = 192 * 1024;
+pub const MAX_IMAGE_SIZE: usize = SLOT_LEN - HEADER_LEN;
+/// programmed in the padding of the header once the image has booted,
+/// the bootloader raises the rollback counter to its version after it
+pub const CONFIRMED_OFFSET: usize = HEADER_FIELDS_LEN + 4;
+pub const CONFIRMED_MAGIC: u32 = 0xb007_c0de;
+
+/// the slot this firmware is linked for
+#[cfg(not(feature = "slot-b"))]
+pub const RUNNING_SLOT: usize = 0;
+#[cfg(feature = "slot-b")]
+pub const RUNNING_SLOT: usize = 1;
+
+/// an update is always written to the slot not running
+pub const UPDATE_SLOT: usize = 1 - RUNNING_SLOT;
+
+#[derive(Clone, Copy)]
+pub struct UpdateProgress {
+    pub size: u32,
+    pub written: u32
+}
+
+/// the commit the firmware is built from, see build.rs
+pub fn git_hash() -> [u8; 20] {
+    let mut hash = [0; 20];
+    if let Some(hex) = option_env!("GIT_HASH") {
+        hash.iter_mut().zip(hex.as_bytes().chunks(2)).for_each(|(byte, digits)| {
+            *byte = core::str::from_utf8(digits).ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .unwrap_or(0)
+        });
+    }
+    hash
+}
+
+/// version in the header of the running image,
+/// 0 if the firmware was flashed without a header
+pub fn running_version<B: FirmwareStorage>() -> u32 {
+    let header = B::image_header(RUNNING_SLOT);
+    match header[..4] == IMAGE_MAGIC {
+        true => u32::from_le_bytes(header[4..8].try_into().unwrap()),
+        false => 0
+    }
+}
+
+/// tell the bootloader the running image boots. until then it is on trial,
+/// and the bootloader falls back to the other image after a reset
+pub fn confirm_boot<B: Hal>(dev: &mut Device<B>) -> Result<()> {
+    if B::image_header(RUNNING_SLOT)[..4] != IMAGE_MAGIC || B::image_confirmed(RUNNING_SLOT) {
+        return Ok(())
+    }
+    dev.board.program_image(RUNNING_SLOT, CONFIRMED_OFFSET, &CONFIRMED_MAGIC.to_le_bytes())
+}
+
+/// check the header, ask the user, then erase the update slot and program the header.
+/// the signature is checked by the bootloader before the image is booted
+pub fn begin<B: Hal>(dev: &mut Device<B>, header: &[u8; HEADER_FIELDS_LEN]) -> Result<()> {
+    let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let size = u32::from_le_bytes(header[12..16].try_into().unwrap());
+
+    if header[..4] != IMAGE_MAGIC
+        || header[8] as usize != UPDATE_SLOT
+        || size as usize > MAX_IMAGE_SIZE {
+        return Err(Error::InvalidFirmware)
+    }
+    // the bootloader would refuse it anyway
+    if version < running_version::<B>() {
+        return Err(Error::FirmwareRollback)
+    }
+
+    show_text(&mut dev.board, b"UPdAtE")?;
+    if !dev.board.wait_for_confirm() {
+        return Err(Error::UserRejected)
+    }
+
+    dev.board.erase_image(UPDATE_SLOT)?;
+    dev.board.program_image(UPDATE_SLOT, 0, header)?;
+
+    dev.firmware_update = Some(UpdateProgress { size, written: 0 });
+    Ok(())
+}
+
+/// chunks are written in order, `offset` is counted from the end of the header
+pub fn write_chunk<B: Hal>(dev: &mut Device<B>, offset: u32, data: &[u8]) -> Result<()> {
+    let Some(UpdateProgress { size, written }) = dev.firmware_update else {
+        return Err(Error::FirmwareIncomplete)
+    };
+    if offset != written || written as usize + data.len() > size as usize {
+        return Err(Error::FirmwareIncomplete)
+    }
+
+    dev.board.program_image(UPDATE_SLOT, HEADER_LEN + offset as usize, data)?;
+    dev.firmware_update = Some(UpdateProgress { size, written: written + data.len() as u32 });
+    Ok(())
+}
+
+/// bytes of the body written by the update in progress
+pub fn written<B: Hal>(dev: &Device<B>) -> Option<u32> {
+    dev.firmware_update.map(|progress| progress.written)
+}
+
+/// the device resets into the bootloader after the response is sent
+pub fn finish<B: Hal>(dev: &mut Device<B>) -> Result<()> {
+    match dev.firmware_update.take() {
+        Some(UpdateProgress { size, written }) if size == written => {
+            dev.pending_reset = true;
+            Ok(())
+        },
+        _ => Err(Error::FirmwareIncomplete)
+    }
+}
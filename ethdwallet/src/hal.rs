@@ -0,0 +1,102 @@
+// the hardware the wallet and the protocol are written against.
+//
+// the wallet, the protocol and everything they call are generic over `Hal`,
+// `board::Board` is the STM32F407 backend, the simulator and the tests have their own.
+// a backend owns its peripherals, it is handed down with the rest
+// of the state in `Device`; reading memory mapped flash, the clock or the id
+// needs nothing to be owned, so these are associated functions.
+
+use ethdwallet_protocol::CrashReport;
+
+use crate::{
+    error::Result,
+    input::{MsgBuffer, FIXED_KEY_LEN},
+    display::Screen,
+    audit::{LogSector, LOG_SECTOR_NUM},
+    firmware::HEADER_FIELDS_LEN,
+    wallet::storage::{Slot, SLOT_NUM}
+};
+
+/// the sectors holding the wallet slots
+pub trait WalletStorage {
+    /// the slots as they are in flash
+    fn slots() -> [&'static Slot; SLOT_NUM];
+    fn erase(&mut self, slot: usize) -> Result<()>;
+    /// program `data` at `offset` from the start of the slot
+    fn program(&mut self, slot: usize, offset: usize, data: &[u8]) -> Result<()>;
+}
+
+/// the sectors holding the audit log
+pub trait AuditStorage {
+    /// the sectors as they are in flash
+    fn log_sectors() -> [&'static LogSector; LOG_SECTOR_NUM];
+    fn erase_log(&mut self, sector: usize) -> Result<()>;
+    /// program `data` at `offset` from the start of the sector
+    fn program_log(&mut self, sector: usize, offset: usize, data: &[u8]) -> Result<()>;
+}
+
+/// the application slots written by a firmware update
+pub trait FirmwareStorage {
+    /// leading bytes of the image in `slot`, the header if there is one
+    fn image_header(slot: usize) -> [u8; HEADER_FIELDS_LEN];
+    /// the image in `slot` has confirmed its boot, see `firmware::confirm_boot`
+    fn image_confirmed(slot: usize) -> bool;
+    fn erase_image(&mut self, slot: usize) -> Result<()>;
+    /// program `data` at `offset` from the start of the slot, header included
+    fn program_image(&mut self, slot: usize, offset: usize, data: &[u8]) -> Result<()>;
+}
+
+pub trait KeyInput {
+    /// block until `FIXED_KEY_LEN` digits are typed
+    fn wait_for_key(&mut self) -> [u8; FIXED_KEY_LEN];
+    /// block until the user accepts (true) or rejects (false)
+    /// what is shown on the display
+    fn wait_for_confirm(&mut self) -> bool;
+}
+
+/// the 8 digit segment display
+pub trait Display {
+    /// replace what is shown, an animated screen runs while the backend waits
+    /// for a key or the host
+    fn show(&mut self, screen: &Screen) -> Result<()>;
+}
+
+/// the link to the host
+pub trait Transport {
+    /// block until a request frame is finished or found damaged,
+    /// the next frame is read into a new buffer.
+    /// None once `deadline` (see `System::millis`) passes and no frame is arriving
+    fn receive(&mut self, deadline: Option<u32>) -> Option<MsgBuffer>;
+    fn write(&mut self, bytes: &[u8]) -> Result<()>;
+    /// wait until every byte written is sent
+    fn flush(&mut self) -> Result<()>;
+    /// the bytes written so far are sent at the old rate
+    fn set_baud_rate(&mut self, baud: u32) -> Result<()>;
+}
+
+/// a TRNG whose output has passed the health tests
+pub trait EntropySource {
+    fn fill(&mut self, dest: &mut [u8]) -> Result<()>;
+}
+
+pub trait System {
+    fn delay_us(&mut self, us: u32);
+    /// milliseconds since the start, wrapping around
+    fn millis() -> u32;
+    /// unique id of the MCU, zero if there is none
+    fn unique_id() -> [u8; 12];
+    /// the host is alive, keep the device running
+    fn feed_watchdog(&mut self);
+    fn reset() -> !;
+    /// what ended the last run, None if it did not crash or it is read already
+    fn take_crash_report(&mut self) -> Option<CrashReport>;
+}
+
+/// everything a backend provides
+pub trait Hal:
+    WalletStorage + AuditStorage + FirmwareStorage + KeyInput
+    + Display + Transport + EntropySource + System {}
+
+impl<T> Hal for T where T:
+    WalletStorage + AuditStorage + FirmwareStorage + KeyInput
+    + Display + Transport + EntropySource + System {}
@@ -1,23 +1,24 @@
-use cortex_m::{interrupt::free, prelude::*};
-use stm32f4xx_hal::gpio::{Output, Input};
+use embedded_hal::blocking::delay::DelayUs;
+use stm32f4::stm32f407::I2C1;
+use stm32f4xx_hal::{gpio::{Output, Input}, rcc::Clocks};
 
-use crate::{global::*, init::i2c1_init};
+use crate::{board::*, error::{Error, Result}, init::i2c1_init};
 
-pub fn set_i2c_bus(pins: I2cPins, delay: &mut TIM1Delay) -> I2cPins {
+pub fn set_i2c_bus(pins: I2cPins, delay: &mut impl DelayUs<u32>) -> I2cPins {
     let (scl, sda) = pins;
     let mut scl = scl.into_mode::<Output>();
     let mut sda = sda.into_mode::<Output>();
     sda.set_high();
-    // Check that the SDA (data) is not being held low. 
+    // Check that the SDA (data) is not being held low.
     for _ in 0..20 {
         // If SDA is Low, then clock SCL Low for >5us then High>5us
         scl.set_low();
         delay.delay_us(5_u32);
         scl.set_high();
         delay.delay_us(5_u32);
-        // check SDA input again. 
+        // check SDA input again.
     }
-    // When SDA becomes High make SDA LOW while the SCL is High 
+    // When SDA becomes High make SDA LOW while the SCL is High
     sda.set_low();
     // wait >5us and make SDA high i.e. send I2C STOP control.
     delay.delay_us(5_u32);
@@ -29,21 +30,36 @@ pub fn set_i2c_bus(pins: I2cPins, delay: &mut TIM1Delay) -> I2cPins {
     )
 }
 
-pub fn reset_i2c1() {
-    free(|cs| {
-        let clk = CLOCK.borrow(cs).get().unwrap();
-        let i2c = I2C1.borrow(cs).take().unwrap();
-        let mut delay = DELAY.borrow(cs).take().unwrap();
-        let (i2c1, (scl, sda)) = i2c.release();
-        
+/// the ZLG7290 on I2C1, scanning the keypad and driving the display
+pub struct Panel {
+    /// only taken while the bus is reset
+    i2c: Option<I2cType>,
+    delay: TIM3Delay,
+    clocks: Clocks
+}
+
+impl Panel {
+    pub fn new(i2c1: I2C1, pins: I2cPins, mut delay: TIM3Delay, clocks: Clocks) -> Self {
+        let pins = set_i2c_bus(pins, &mut delay);
+        Self { i2c: Some(i2c1_init(pins, i2c1, &clocks)), delay, clocks }
+    }
 
-        I2C1.borrow(cs).set(Some(
-            i2c1_init(
-                set_i2c_bus((scl, sda), &mut delay), 
-                i2c1, &clk
-            )
-        ));
+    /// the bus is reset if the transfer fails
+    pub fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let result = self.i2c.as_mut().unwrap().write(ZLG7290_ADDR, bytes);
+        self.reset_on_error(result.map_err(Error::from))
+    }
 
-        DELAY.borrow(cs).set(Some(delay));
-    })
+    pub fn write_read(&mut self, bytes: &[u8], buf: &mut [u8]) -> Result<()> {
+        let result = self.i2c.as_mut().unwrap().write_read(ZLG7290_ADDR, bytes, buf);
+        self.reset_on_error(result.map_err(Error::from))
+    }
+
+    fn reset_on_error(&mut self, result: Result<()>) -> Result<()> {
+        if result.is_err() {
+            let (i2c1, pins) = self.i2c.take().unwrap().release();
+            self.i2c = Some(i2c1_init(set_i2c_bus(pins, &mut self.delay), i2c1, &self.clocks));
+        }
+        result
+    }
 }
@@ -1,29 +1,43 @@
-use core::intrinsics::transmute;
+use core::ptr::addr_of;
 
-use cortex_m::interrupt::free;
+use cortex_m::peripheral::{SYST, syst::SystClkSource};
 use fugit::TimerDurationU32;
+use heapless::spsc::Consumer;
 use stm32f4::stm32f407::{
     GPIOH, GPIOD, GPIOA, GPIOB,
-    self, USART1, I2C1, IWDG, TIM2
+    self, USART1, I2C1, IWDG, TIM2, DMA2, PWR, EXTI, RCC, RTC
 };
 use stm32f4xx_hal::{
     pac,
     i2c::{I2c1, self},
     rcc::{RccExt, Enable, Clocks, Rcc},
     prelude::*, 
-    gpio::{Edge, gpioa, gpiod}, flash::LockedFlash, serial::{Serial, self}, syscfg::SysCfg, rng::Rng, watchdog::IndependentWatchdog, timer::Event,
+    dma::traits::PeriAddress,
+    gpio::{Edge, Input, Pin, gpioa, gpiod}, flash::LockedFlash, serial::{Serial, Tx, self}, syscfg::SysCfg, rng::Rng, watchdog::IndependentWatchdog, timer::{Event, CounterUs},
 };
-use rand::{RngCore, SeedableRng};
+use ethdwallet_protocol::DEFAULT_BAUD_RATE;
+use rand::SeedableRng;
 use rand_chacha::ChaCha20Rng;
 
 use crate::{
-    ALLOCATOR,
-    set_global,
-    wallet::initializer::try_initialize_wallet,
-    global::*,
-    error::{Result, Error}, i2c::set_i2c_bus
+    board::*,
+    error::{Error, Result},
+    i2c::Panel,
+    input::KeyInputBuffer,
+    display::{DIGIT_NUM, SEG7_BLANK},
+    entropy::Trng
 };
 
+/// the peripherals, each handed to the task it belongs to
+pub struct Parts {
+    pub board: Board,
+    pub rng: ChaCha20Rng,
+    pub panel: Panel,
+    pub key_trigger: Pin<'D', 13, Input>,
+    pub dog: CounterUs<TIM2>,
+    pub rx_dma: DMA2
+}
+
 /// initialize GPIO
 /// 1. enable clocks on nessessary pins
 fn gpio_init(dp: &stm32f407::Peripherals) {
@@ -45,8 +59,58 @@ fn clock_init(rcc: Rcc) -> Clocks {
         .freeze()
 }
 
-/// initialize serial port
-fn serial_init(gpioa: gpioa::Parts, usart1: USART1, clk: &Clocks) -> Result<()> {
+/// after STOP the core runs from the HSI, start the HSE and the PLL again
+/// and switch back to them. the PLL, the prescalers and the flash latency
+/// set by `clock_init` are kept through STOP
+pub fn restore_clocks() {
+    let rcc = unsafe { &*RCC::ptr() };
+    rcc.cr.modify(|_, w| w.hseon().on());
+    while rcc.cr.read().hserdy().is_not_ready() {}
+    rcc.cr.modify(|_, w| w.pllon().on());
+    while rcc.cr.read().pllrdy().is_not_ready() {}
+    rcc.cfgr.modify(|_, w| w.sw().pll());
+    while !rcc.cfgr.read().sws().is_pll() {}
+}
+
+/// STOP keeps the regulator and the flash in low power, see `Board::stop`.
+/// a falling edge on PA10, the RX line of USART1, wakes the device
+/// as a key does, the line is only unmasked in STOP
+fn stop_mode_init(pwr: &PWR, exti: &EXTI, syscfg: &SysCfg) {
+    pwr.cr.modify(|_, w| w.pdds().clear_bit().lpds().set_bit().fpds().set_bit());
+    syscfg.exticr3.modify(|_, w| unsafe { w.exti10().bits(0) });
+    exti.ftsr.modify(|_, w| w.tr10().set_bit());
+}
+
+/// the wakeup timer of the RTC runs on the LSI, which the IWDG starts anyway,
+/// and raises EXTI line 22 every `STOP_WAKEUP_MS`, see `Board::stop`
+fn rtc_wakeup_init(rcc: &RCC, pwr: &PWR, rtc: &RTC, exti: &EXTI) {
+    rcc.csr.modify(|_, w| w.lsion().on());
+    while rcc.csr.read().lsirdy().is_not_ready() {}
+    pwr.cr.modify(|_, w| w.dbp().set_bit());
+    // the clock of the RTC is only chosen once after a reset of the backup domain,
+    // which leaves the backup SRAM as it is
+    if !rcc.bdcr.read().rtcsel().is_lsi() {
+        rcc.bdcr.modify(|_, w| w.bdrst().set_bit());
+        rcc.bdcr.modify(|_, w| w.bdrst().clear_bit());
+        rcc.bdcr.modify(|_, w| w.rtcsel().lsi());
+    }
+    rcc.bdcr.modify(|_, w| w.rtcen().enabled());
+
+    rtc.wpr.write(|w| w.key().bits(0xca));
+    rtc.wpr.write(|w| w.key().bits(0x53));
+    rtc.cr.modify(|_, w| w.wute().clear_bit());
+    while rtc.isr.read().wutwf().bit_is_clear() {}
+    // LSI / 16, 2 ticks a millisecond
+    rtc.wutr.write(|w| w.wut().bits((STOP_WAKEUP_MS * 2 - 1) as u16));
+    rtc.cr.modify(|_, w| w.wucksel().div16().wutie().set_bit().wute().set_bit());
+    rtc.wpr.write(|w| w.key().bits(0xff));
+
+    exti.rtsr.modify(|_, w| w.tr22().set_bit());
+    exti.imr.modify(|_, w| w.mr22().set_bit());
+}
+
+/// initialize serial port, DMA receives into RX_RING
+fn serial_init(gpioa: gpioa::Parts, usart1: USART1, dma2: &DMA2, clk: &Clocks) -> Result<Tx<USART1>> {
     let pins = (
         gpioa
             .pa9
@@ -56,55 +120,57 @@ fn serial_init(gpioa: gpioa::Parts, usart1: USART1, clk: &Clocks) -> Result<()>
             .into_alternate()
     );
     let config = serial::Config::default()
-        .baudrate(9600.bps())
+        .baudrate(DEFAULT_BAUD_RATE.bps())
         .wordlength_8()
-        .stopbits(serial::config::StopBits::STOP1);
+        .stopbits(serial::config::StopBits::STOP1)
+        .dma(serial::config::DmaConfig::Rx);
     let serial = Serial::new(
         usart1, pins, config, clk
     )?.with_u8_data();
 
     let (tx, mut rx) = serial.split();
-    rx.listen();
-    rx.unlisten_idle();
+    // the idle line after a frame wakes the serial task, errors damage the frame
+    rx.unlisten();
+    rx.listen_idle();
+    unsafe { (*USART1::ptr()).cr3.modify(|_, w| w.eie().enabled()) };
 
-    free(|cs| {
-        set_global!(SERIAL_RX, rx, cs);
-        set_global!(SERIAL_TX, tx, cs);
-    });
+    let stream = &dma2.st[RX_STREAM];
+    stream.cr.write(|w| w.en().disabled());
+    while stream.cr.read().en().is_enabled() {}
+    stream.par.write(|w| unsafe { w.pa().bits(rx.address()) });
+    stream.m0ar.write(|w| unsafe { w.m0a().bits(addr_of!(RX_RING) as u32) });
+    stream.ndtr.write(|w| w.ndt().bits(RX_RING_LEN as u16));
+    // half of the ring wakes the serial task as well, before DMA wraps around
+    stream.cr.write(|w| w
+        .chsel().bits(RX_CHANNEL)
+        .minc().incremented()
+        .dir().peripheral_to_memory()
+        .circ().enabled()
+        .htie().enabled()
+        .tcie().enabled()
+        .en().enabled()
+    );
 
-    Ok(())
+    Ok(tx)
 }
 
-fn keyboard_init(gpiod: gpiod::Parts, exti: &mut pac::EXTI, syscfg: &mut SysCfg) {
+/// the keypad task reads the ZLG7290 on every key pressed
+fn keyboard_init(gpiod: gpiod::Parts, exti: &mut pac::EXTI, syscfg: &mut SysCfg) -> Pin<'D', 13, Input> {
     // initialize triggering keyboard interrupt
     let mut key_trigger = gpiod.pd13.into_pull_down_input();
     key_trigger.make_interrupt_source(syscfg);
     key_trigger.trigger_on_edge(exti, Edge::Falling);
-
-    free(|cs| {
-        set_global!(KEY_TRIGGER, key_trigger, cs);
-    })
+    key_trigger.enable_interrupt(exti);
+    key_trigger
 }
 
-fn heap_init() {
-    use core::mem::MaybeUninit;
-    const HEAP_SIZE: usize = 1024;
-    static mut HEAP: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
-    unsafe { ALLOCATOR.init(HEAP.as_ptr() as usize, HEAP_SIZE) }
-}
-
-fn rng_init(mut rand_source: Rng) {
-    let seed: [u64; 4] = array_init::array_init(|_| {
-        rand_source.next_u64()
-    });
-    let rng = ChaCha20Rng::from_seed(unsafe {
-        // this is totally safe since their size are the same
-        transmute(seed)
-    });
-
-    free(|cs| {
-        set_global!(RNG, rng, cs);
-    })
+/// the RNG is seeded even if the TRNG fails its health tests, 
+/// key generation is refused later by `entropy::reseed`
+fn rng_init(rand_source: Rng) -> (Trng<Rng>, ChaCha20Rng) {
+    let mut trng = Trng::new(rand_source);
+    let mut seed = [0; 32];
+    let _ = trng.startup().and_then(|_| trng.fill(&mut seed));
+    (trng, ChaCha20Rng::from_seed(seed))
 }
 
 pub fn i2c1_init(pins: I2cPins, i2c1: I2C1, clk: &Clocks) -> I2cType {
@@ -119,29 +185,34 @@ pub fn i2c1_init(pins: I2cPins, i2c1: I2C1, clk: &Clocks) -> I2cType {
     )
 }
 
-fn watchdog_init(dog: IWDG, tim: TIM2, clks: &Clocks) {
+/// tick every millisecond, the request frames time out on it
+fn systick_init(syst: &mut SYST, clks: &Clocks) {
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(clks.sysclk().raw() / 1000 - 1);
+    syst.clear_current();
+    syst.enable_counter();
+    syst.enable_interrupt();
+}
+
+fn watchdog_init(
+    dog: IWDG, tim: TIM2, clks: &Clocks
+) -> Result<(IndependentWatchdog, CounterUs<TIM2>)> {
     let mut iwatchdog = IndependentWatchdog::new(dog);
-    iwatchdog.start(TimerDurationU32::minutes(5));
+    iwatchdog.start(TimerDurationU32::millis(IWDG_TIMEOUT_MS));
 
     let mut timer = tim.counter_us(clks);
-    timer.start(TimerDurationU32::minutes(1));
+    timer.start(TimerDurationU32::minutes(1)).map_err(|_| Error::HalInitError)?;
     timer.listen(Event::Update);
 
-    free(|cs| {
-        set_global!(IWDG, iwatchdog, cs);
-        set_global!(DOG_TIMER, timer, cs);
-    });
+    Ok((iwatchdog, timer))
 }
 
-pub fn init() -> Result<()> {
-    let (Some(mut dp), Some(_cp)) = (
-        stm32f407::Peripherals::take(),
-        cortex_m::peripheral::Peripherals::take(),
-    ) else {
-        return Err(Error::HalInitError)
-    };
-
-    heap_init();
+/// set up the board, the interrupts are enabled by RTIC once this returns
+pub fn init(
+    mut dp: stm32f407::Peripherals,
+    syst: &mut SYST,
+    keys: Consumer<'static, KeyInputBuffer, KEY_QUEUE_LEN>
+) -> Result<Parts> {
     gpio_init(&dp);
 
     // see https://github.com/probe-rs/probe-rs/issues/350
@@ -150,49 +221,43 @@ pub fn init() -> Result<()> {
         w.dbg_standby().set_bit();
         w.dbg_stop().set_bit()
     });
-    dp.RCC.ahb1enr.modify(|_, w| w.dma1en().enabled());
+    dp.RCC.ahb1enr.modify(|_, w| w.dma1en().enabled().dma2en().enabled());
+    dp.RCC.apb1enr.modify(|_, w| w.pwren().enabled());
+    crate::crash::record_reset(&dp.RCC);
+    rtc_wakeup_init(&dp.RCC, &dp.PWR, &dp.RTC, &dp.EXTI);
 
     let clocks = clock_init(dp.RCC.constrain());
-    watchdog_init(dp.IWDG, dp.TIM2, &clocks);
+    let (iwdg, dog) = watchdog_init(dp.IWDG, dp.TIM2, &clocks)?;
+    systick_init(syst, &clocks);
     
     let mut syscfg = dp.SYSCFG.constrain();
+    stop_mode_init(&dp.PWR, &dp.EXTI, &syscfg);
     
-    rng_init(dp.RNG.constrain(&clocks));
+    let (trng, rng) = rng_init(dp.RNG.constrain(&clocks));
 
-    serial_init(dp.GPIOA.split(), dp.USART1, &clocks)?;
-    keyboard_init(dp.GPIOD.split(), &mut dp.EXTI, &mut syscfg);
+    let tx = serial_init(dp.GPIOA.split(), dp.USART1, &dp.DMA2, &clocks)?;
+    let key_trigger = keyboard_init(dp.GPIOD.split(), &mut dp.EXTI, &mut syscfg);
 
     let gpiof = dp.GPIOF.split();
     let gpiob = dp.GPIOB.split();
-    let mut delay = dp.TIM1.delay(&clocks);
+    // only driven through BSRR from now on, see `set_led`
+    let _led = gpiof.pf10.into_push_pull_output();
 
-    let i2c_pins = set_i2c_bus(
-        (gpiob.pb6, gpiob.pb7), &mut delay
+    let panel = Panel::new(
+        dp.I2C1, (gpiob.pb6, gpiob.pb7), dp.TIM3.delay(&clocks), clocks
     );
-    free(|cs| {
-        set_global!(I2C1, i2c1_init(
-            i2c_pins, 
-            dp.I2C1, &clocks
-        ), cs);
-        set_global!(DELAY, delay, cs);
-        set_global!(CLOCK, clocks, cs);
-        set_global!(LED, gpiof.pf10.into_push_pull_output(), cs);
-        set_global!(EXTI, dp.EXTI, cs);
-        set_global!(FLASH, LockedFlash::new(dp.FLASH), cs);
-    });
-    
-    // enable interrupts
-    pac::NVIC::unpend(pac::interrupt::EXTI15_10);
-    pac::NVIC::unpend(pac::interrupt::USART1);
-    pac::NVIC::unpend(pac::interrupt::TIM2);
-    unsafe {
-        pac::NVIC::unmask(pac::interrupt::EXTI15_10);
-        pac::NVIC::unmask(pac::interrupt::USART1);
-        pac::NVIC::unmask(pac::interrupt::TIM2);
-    }
-
-    try_initialize_wallet()
+    let board = Board {
+        flash: LockedFlash::new(dp.FLASH),
+        tx,
+        trng,
+        delay: dp.TIM1.delay(&clocks),
+        iwdg,
+        clocks,
+        keys,
+        rx_tail: 0,
+        screen: None,
+        frame: [SEG7_BLANK; DIGIT_NUM]
+    };
 
-    // // Create a delay abstraction based on SysTick
-    // let mut delay = cp.SYST.delay(&clocks);
+    Ok(Parts { board, rng, panel, key_trigger, dog, rx_dma: dp.DMA2 })
 }
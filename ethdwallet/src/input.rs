@@ -1,13 +1,18 @@
-use stm32f4xx_hal::gpio::ExtiPin;
-
-use crate::{update_global, global::*, error::Error};
+use ethdwallet_protocol::{FRAME_TIMEOUT_MS, frame_crc};
 
 pub const FIXED_KEY_LEN: usize = 8;
 
+/// key codes of the keys at the right and left of 0
+pub const KEY_CONFIRM: u8 = 2;
+pub const KEY_CANCEL: u8 = 4;
+
 #[derive(Clone, Copy)]
 pub enum KeyInputState {
     Reading(usize),
-    Finished
+    Finished,
+    /// waiting for KEY_CONFIRM or KEY_CANCEL, digits are ignored
+    Confirming,
+    Decided(bool)
 }
 
 #[derive(Clone, Copy)]
@@ -25,72 +30,51 @@ impl KeyInputBuffer {
     }
 
     pub fn read(&mut self, byte: u8) {
-        match self.state {
-            KeyInputState::Reading(p) => {
-                self.buf[p] = byte;
-                // finished
-                if p + 1 < FIXED_KEY_LEN {
-                    self.state = KeyInputState::Reading(p + 1)
-                } else {
-                    self.state = KeyInputState::Finished
-                }
-            },
-            KeyInputState::Finished => {},
+        if let KeyInputState::Reading(p) = self.state {
+            self.buf[p] = byte;
+            // finished
+            if p + 1 < FIXED_KEY_LEN {
+                self.state = KeyInputState::Reading(p + 1)
+            } else {
+                self.state = KeyInputState::Finished
+            }
         }
     }
 
-    pub fn wait_for_key() -> [u8; 8] {
-        update_global!(|mut keyboard: Option<KEY_TRIGGER>, mut exti: Option<EXTI>| {
-            keyboard.enable_interrupt(&mut exti);
-        });
-
-        let mut passcode = [0; 8];
-        
-        loop {
-            cortex_m::asm::wfi();
-            if update_global!(|buf: Copy<KEY_BUFFER>| {
-                if let KeyInputState::Finished = buf.state {
-                    passcode = buf.buf;
-                    true
-                } else {
-                    false
-                }
-            }) {
-                break;
+    pub fn decide(&mut self, key: u8) {
+        if let KeyInputState::Confirming = self.state {
+            match key {
+                KEY_CONFIRM => self.state = KeyInputState::Decided(true),
+                KEY_CANCEL => self.state = KeyInputState::Decided(false),
+                _ => {}
             }
         }
-
-        update_global!(|
-            mut keyboard: Option<KEY_TRIGGER>, 
-            mut exti: Option<EXTI>,
-            mut buf: Copy<KEY_BUFFER>
-        | {
-            keyboard.disable_interrupt(&mut exti);
-            buf = KeyInputBuffer::new();
-        });
-
-        passcode
     }
 }
 
-pub const MSG_MAGIC: u8 = 0xff;
+pub use ethdwallet_protocol::{MSG_MAGIC, MAX_MSG_LEN};
 
 #[derive(Clone, Copy)]
 pub enum MsgBufferState {
     PendingStart,
+    PendingId,
     PendingLen(u8),
     Reading(u32),
+    PendingCrc(u8),
     Finished,
-    Error(Error)
+    /// the CRC does not match, the length is too long or the receiver ran into an error
+    Damaged
 }
 
-pub const MAX_MSG_LEN: usize = 1024;
-
-/// format of a message is MSG_MAGIC(1 byte) + MSG_LEN(4 byte) + MSG
+/// a request frame, MSG_MAGIC(1 byte) + ID(1 byte) + MSG_LEN(4 bytes) + MSG + CRC(4 bytes)
 #[derive(Clone, Copy)]
 pub struct MsgBuffer {
     pub buf: [u8; MAX_MSG_LEN],
     pub msg_len: u32,
+    pub id: u8,
+    pub crc: u32,
+    /// when the last byte arrived, see `System::millis`
+    pub last_byte: u32,
     pub state: MsgBufferState
 }
 
@@ -99,24 +83,87 @@ impl MsgBuffer {
         Self {
             buf: [0; MAX_MSG_LEN],
             msg_len: 0,
+            id: 0,
+            crc: 0,
+            last_byte: 0,
             state: MsgBufferState::PendingStart
         }
     }
 
-    pub fn read(&mut self, byte: u8) {
-        match self.state {
-            MsgBufferState::PendingStart => {
-                if byte == MSG_MAGIC {
-                    self.state = MsgBufferState::PendingLen(0)
+    /// the frame is finished or damaged, nothing more is read into the buffer
+    pub fn done(&self) -> bool {
+        matches!(self.state, MsgBufferState::Finished | MsgBufferState::Damaged)
+    }
+
+    /// `deadline` has passed and no frame is on the way
+    pub fn timed_out(&self, deadline: Option<u32>, now: u32) -> bool {
+        let idle = matches!(self.state, MsgBufferState::PendingStart)
+            || now.wrapping_sub(self.last_byte) > FRAME_TIMEOUT_MS;
+        idle && deadline.is_some_and(|deadline| now.wrapping_sub(deadline) as i32 >= 0)
+    }
+
+    /// read the bytes arrived by `now`, in milliseconds, until the frame is done,
+    /// returns how many bytes are taken, the rest belongs to the next frame
+    pub fn read(&mut self, bytes: &[u8], now: u32) -> usize {
+        if self.done() || bytes.is_empty() {
+            return 0
+        }
+        // the rest of the frame is lost, the host sends it again after a pause,
+        // so the next byte starts over
+        let started = !matches!(self.state, MsgBufferState::PendingStart);
+        if started && now.wrapping_sub(self.last_byte) > FRAME_TIMEOUT_MS {
+            *self = Self::new();
+        }
+        self.last_byte = now;
+
+        let mut taken = 0;
+        while taken < bytes.len() && !self.done() {
+            let rest = &bytes[taken..];
+            taken += match self.state {
+                MsgBufferState::PendingStart => {
+                    match rest.iter().position(|byte| *byte == MSG_MAGIC) {
+                        Some(p) => {
+                            self.state = MsgBufferState::PendingId;
+                            p + 1
+                        },
+                        None => rest.len()
+                    }
+                },
+                MsgBufferState::Reading(cur) => {
+                    let len = rest.len().min((self.msg_len - cur) as usize);
+                    let end = cur as usize + len;
+                    self.buf[cur as usize..end].copy_from_slice(&rest[..len]);
+
+                    if end == self.msg_len as usize {
+                        self.state = MsgBufferState::PendingCrc(0)
+                    } else {
+                        self.state = MsgBufferState::Reading(end as u32)
+                    }
+                    len
+                },
+                _ => {
+                    self.read_byte(rest[0]);
+                    1
                 }
+            };
+        }
+        taken
+    }
+
+    /// the header and the CRC are read a byte at a time
+    fn read_byte(&mut self, byte: u8) {
+        match self.state {
+            MsgBufferState::PendingId => {
+                self.id = byte;
+                self.state = MsgBufferState::PendingLen(0)
             },
             MsgBufferState::PendingLen(p) => {
                 self.msg_len |= (byte as u32) << ((p as u32) << 3);
                 if p == 3 {
-                    if self.msg_len == 0 { 
-                        self.state = MsgBufferState::Finished 
-                    } else if self.msg_len >= MAX_MSG_LEN as u32 {
-                        self.state = MsgBufferState::Error(Error::SerialDataCorrupted)
+                    if self.msg_len == 0 {
+                        self.state = MsgBufferState::PendingCrc(0)
+                    } else if self.msg_len > MAX_MSG_LEN as u32 {
+                        self.state = MsgBufferState::Damaged
                     } else {
                         self.state = MsgBufferState::Reading(0)
                     }
@@ -124,13 +171,14 @@ impl MsgBuffer {
                     self.state = MsgBufferState::PendingLen(p + 1)
                 }
             },
-            MsgBufferState::Reading(cur) => {
-                self.buf[cur as usize] = byte;
-
-                if cur + 1 == self.msg_len {
+            MsgBufferState::PendingCrc(p) => {
+                self.crc |= (byte as u32) << ((p as u32) << 3);
+                if p < 3 {
+                    self.state = MsgBufferState::PendingCrc(p + 1)
+                } else if self.crc == frame_crc(self.id, &self.buf[..self.msg_len as usize]) {
                     self.state = MsgBufferState::Finished
                 } else {
-                    self.state = MsgBufferState::Reading(cur + 1)
+                    self.state = MsgBufferState::Damaged
                 }
             },
             _ => {},
@@ -2,28 +2,43 @@
 #![feature(let_else)]
 #![allow(clippy::empty_loop)]
 #![no_main]
+// the keypad and the i2c bus are not emulated by QEMU
+#![cfg_attr(feature = "qemu", allow(dead_code))]
 #![feature(alloc_error_handler)]
 #![feature(core_intrinsics)]
 
 use core::alloc::Layout;
 
 use alloc_cortex_m::CortexMHeap;
-// Halt on panic
-use panic_halt as _; // panic handler
-
-use cortex_m_rt::entry;
-
+// the board keeps a crash report, see `crash`, QEMU halts on panic
+#[cfg(feature = "qemu")]
+use panic_halt as _;
 
 extern crate alloc;
 
 mod error;
 mod wallet;
 mod main_loop;
+#[cfg(not(feature = "qemu"))]
 mod init;
-mod global;
-mod interrupts;
+mod device;
+#[cfg(not(feature = "qemu"))]
+mod tasks;
+#[cfg(not(feature = "qemu"))]
+mod crash;
 mod input;
+#[cfg(not(feature = "qemu"))]
 mod i2c;
+mod display;
+mod tx;
+mod entropy;
+mod firmware;
+mod signing;
+mod audit;
+mod hal;
+// the backend of the board the firmware is built for
+#[cfg_attr(feature = "qemu", path = "qemu.rs")]
+mod board;
 
 #[global_allocator]
 static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
@@ -33,11 +48,21 @@ fn oom(_: Layout) -> ! {
     loop { }
 }
 
-#[entry]
+fn heap_init() {
+    use core::mem::MaybeUninit;
+    const HEAP_SIZE: usize = 1024;
+    static mut HEAP: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
+    unsafe { ALLOCATOR.init(HEAP.as_ptr() as usize, HEAP_SIZE) }
+}
+
+// the board runs the tasks of `tasks::app`, QEMU only has the protocol to run
+#[cfg(feature = "qemu")]
+#[cortex_m_rt::entry]
 fn main() -> ! {
-    match init::init() {
-        Err(_) => loop { },
-        Ok(_) => main_loop::main_loop()
-    }
+    heap_init();
+    let Ok(mut dev) = board::init() else {
+        loop { }
+    };
+    main_loop::run(&mut dev)
 }
 
@@ -1,168 +1,350 @@
-use core::sync::atomic::Ordering;
-
-use cortex_m::interrupt::free;
-use cortex_m::prelude::*;
-use fugit::TimerDurationU32;
-use stm32f4::stm32f407::USART1;
-use stm32f4xx_hal::{serial::Tx, block};
 use crate::error::Result;
 
-use crate::wallet::{wallet, PubKey};
+use ethdwallet_protocol::{
+    Instruction, Response, AuditOp, BookEntry, Diagnostics, Info, DuressPolicy, ErrorReport, WalletError, Sink, 
+    Answer, write_answer, INSTRUCTION_SET, PROTOCOL_VERSION,
+    DEFAULT_BAUD_RATE, BAUD_RATES, BAUD_CONFIRM_MS
+};
+use sha3::{Digest, Keccak256};
+
+use crate::wallet::{
+    wallet, lock, is_unlocked, HIDDEN_ZONE, DURESS_ZONE,
+    storage::SLOT_REPEAT,
+    initializer::{try_initialize_wallet, initialize_secondary_zone, wipe_real_zones}
+};
 use crate::{
-    global::*, 
-    update_global, 
-    wallet::{
-        Wallet,
-        safe_zone::{Signature, EthAddr},
-        ACCOUNT_NUM
-    }, 
-    input::{
-        MsgBufferState, 
-        MsgBuffer, KeyInputBuffer
-    }, 
-    error::{self, Error}
+    audit,
+    device::Device,
+    wallet::{Wallet, ACCOUNT_NUM}, 
+    input::{MsgBuffer, MsgBufferState, MAX_MSG_LEN}, 
+    error::{self, Error},
+    hal::{Transport, Hal},
+    display::{show_text, show_status, Status},
+    firmware::{self, RUNNING_SLOT, running_version, git_hash},
+    signing,
+    tx::decode_recipient
 };
 
-pub fn main_loop() -> ! {
+/// set up the wallet, then serve the host. if the setup fails its error is shown,
+/// and what needs no wallet is still served: GetInfo, the diagnostics,
+/// the crash report and firmware updates
+pub fn run<B: Hal>(dev: &mut Device<B>) -> ! {
+    if let Err(e) = try_initialize_wallet(dev) {
+        let _ = show_status(&mut dev.board, Status::Error(WalletError::from(e) as u8));
+    }
+    main_loop(dev)
+}
+
+pub fn main_loop<B: Hal>(dev: &mut Device<B>) -> ! {
+    // the wallet is ready, this image is kept. a failed write is tried at the next start
+    let _ = firmware::confirm_boot(dev);
+
+    // a new baud rate is kept once a valid frame arrives at it
+    let mut baud_deadline = None;
     loop {
-        cortex_m::asm::wfi();
-        watchdog_set_slow();
-        
-        let buf = update_global!(|buf: Copy<MSG_BUFFER>| {
-            buf
-        });
-
-        let result = match buf.state {
+        let Some(buf) = dev.board.receive(baud_deadline) else {
+            // the host could not follow, it looks for the wallet at the default rate
+            let _ = dev.board.set_baud_rate(DEFAULT_BAUD_RATE);
+            baud_deadline = None;
+            continue
+        };
+        let _result = match buf.state {
             MsgBufferState::Finished => {
-                dispatch(buf, wallet())
+                baud_deadline = None;
+                serve(dev, &buf)
             },
-            MsgBufferState::Error(e) => {
-                Err(e)
-            }
-            _ => continue
+            // the host sends the request again
+            _ => answer(&mut dev.board, buf.id, &Answer::Nak)
         };
 
-        let _result: Result<()> = update_global!(|
-            mut buf: Copy<MSG_BUFFER>, 
-            mut tx: Option<SERIAL_TX>
-        | {
-            match result  {
-                Ok(resp) => {
-                    // feed dog
-                    update_global!(|mut dog: Option<IWDG>| {
-                        dog.feed()
-                    });
-                    WATCHDOG.store(true, Ordering::SeqCst);
-                    block!(tx.write(0x00))?;
-                    resp.write_tx(&mut tx)?;
-                },
-                Err(e) => {
-                    block!(tx.write(0xff))?;
-                    block!(tx.write(e as u8))?;
-                },
-            }
+        // erase after the response, so the host cannot tell from the timing.
+        // tried again after the next request if the flash fails
+        if core::mem::take(&mut dev.pending_wipe) {
+            let wallet = wallet(dev);
+            dev.pending_wipe = wipe_real_zones(dev, &wallet).is_err();
+        }
 
-            tx.bflush()?;
-            // this will become the new global buffer
-            buf = MsgBuffer::new();
-            Ok(())
-        });
+        // let the bootloader check and boot the new image
+        if dev.pending_reset {
+            B::reset();
+        }
+
+        // the response to SetBaudRate is sent at the old rate
+        if let Some(baud) = dev.pending_baud.take() {
+            if dev.board.set_baud_rate(baud).is_ok() {
+                baud_deadline = Some(B::millis().wrapping_add(BAUD_CONFIRM_MS));
+            }
+        }
     }
 }
 
-/// defines an instruction
-#[repr(u8)]
-enum Instruction<'raw> {
-    /// [0, account_id, raw]
-    SignTransaction(u8, &'raw [u8]),
-    /// [1, account_id]
-    GetAddress(u8),
-    /// [2]
-    GetAddressList
+/// acknowledge the request, then reply to it.
+/// a request sent again, because the host lost the reply, is not run twice
+pub fn serve<B: Hal>(dev: &mut Device<B>, buf: &MsgBuffer) -> Result<()> {
+    answer(&mut dev.board, buf.id, &Answer::Ack)?;
+
+    let reply = match dev.last_reply {
+        Some((id, crc, reply)) if id == buf.id && crc == buf.crc => reply,
+        _ => {
+            let content = &buf.buf[..buf.msg_len as usize];
+            let wallet = wallet(dev);
+            let reply = dispatch(dev, content, &wallet).map_err(|e| report(dev, e, content));
+            if let Err(report) = reply {
+                let _ = show_status(&mut dev.board, Status::Error(report.code));
+            }
+            dev.last_reply = Some((buf.id, buf.crc, reply));
+            reply
+        }
+    };
+
+    if reply.is_ok() {
+        dev.board.feed_watchdog();
+    }
+    answer(&mut dev.board, buf.id, &Answer::Reply(reply))
 }
 
-#[repr(u8)]
-enum Response {
-    Signature(Signature),
-    Address((EthAddr, PubKey)),
-    AddressList([EthAddr; ACCOUNT_NUM])
+/// the error, with the part of the request at fault if there is one
+fn report<B: Hal>(dev: &Device<B>, e: Error, content: &[u8]) -> ErrorReport {
+    let arg = match (e, Instruction::decode(content)) {
+        (Error::InvalidInstruction, _) => content.first().map(|code| *code as u32),
+        (Error::AccountIdxOOB, Some(
+            Instruction::SignTransaction(idx, _) | Instruction::GetAddress(idx)
+            | Instruction::SetAllowlistOnly(idx, _) | Instruction::SignHash(idx, _)
+            | Instruction::Decrypt(idx, ..) | Instruction::GetEncryptionPubkey(idx)
+            | Instruction::SignBegin(idx, _)
+        )) => Some(idx as u32),
+        (Error::BookIdxOOB, Some(Instruction::RemoveBookEntry(idx))) => Some(idx as u32),
+        // where to go on from
+        (Error::SigningIncomplete, _) => signing::written(dev),
+        (Error::FirmwareIncomplete, _) => firmware::written(dev),
+        _ => None
+    };
+    ErrorReport::with_arg(e.into(), arg)
 }
 
-impl Response {
-    fn write_tx(&self, tx: &mut Tx<USART1>) -> Result<()> {
-        match self {
-            Response::Signature(Signature { 
-                r, s, v 
-            }) => {
-                block!(tx.write(0x00))?;
-                tx.bwrite_all(r)?;
-                tx.bwrite_all(s)?;
-                block!(tx.write(*v))?;
-            },
-            Response::Address((addr, pubkey)) => {
-                block!(tx.write(0x01))?;
-                tx.bwrite_all(addr)?;
-                tx.bwrite_all(pubkey)?;
-            },
-            Response::AddressList(list) => {
-                block!(tx.write(0x02))?;
-                list.into_iter().try_for_each(|addr| {
-                    tx.bwrite_all(addr)
-                })?;
-            },
-        }
-        Ok(())
-    }
+fn answer(board: &mut impl Transport, id: u8, answer: &Answer) -> Result<()> {
+    write_answer(&mut Serial(board), id, answer)?;
+    board.flush()
 }
 
-impl<'raw> TryFrom<&'raw [u8]> for Instruction<'raw> {
-    type Error = error::Error;
+/// the host, on the other side of the transport
+struct Serial<'a, T>(&'a mut T);
 
-    fn try_from(value: &'raw [u8]) -> core::result::Result<Self, Self::Error> {
-        Ok(match *value.get(0).ok_or(Error::InvalidInstruction)? {
-            0 if value.len() > 2 => {
-                Self::SignTransaction(value[1], &value[2..])
-            },
-            1 if value.len() == 2 => Self::GetAddress(value[1]),
-            2 if value.len() == 1 => Self::GetAddressList,
-            _ => return Err(Error::InvalidInstruction)
-        })
+impl<T: Transport> Sink for Serial<'_, T> {
+    type Error = Error;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.0.write(bytes)
     }
 }
 
-fn dispatch(buf: MsgBuffer, wallet: &Wallet) -> error::Result<Response> {
-    if !wallet.initialized {
-        return Err(Error::WalletNotInitialized)
+/// instructions which work without a usable wallet
+fn without_wallet(instr: &Instruction) -> bool {
+    matches!(instr, 
+        Instruction::GetDiagnostics | Instruction::GetFirmwareInfo | Instruction::FirmwareBegin(_) 
+        | Instruction::FirmwareChunk(..) | Instruction::FirmwareFinish | Instruction::GetInfo
+        | Instruction::SetBaudRate(_) | Instruction::ReadAuditLog(_) | Instruction::GetCrashReport
+    )
+}
+
+fn dispatch<B: Hal>(dev: &mut Device<B>, content: &[u8], wallet: &Wallet) -> error::Result<Response> {
+    let instr = Instruction::decode(content)
+        .ok_or(Error::InvalidInstruction)?;
+
+    if !wallet.initialized && !without_wallet(&instr) {
+        return Err(match dev.diagnostics.status.is_lost() {
+            true => Error::WalletCorrupted,
+            false => Error::WalletNotInitialized
+        })
     }
-    
-    let instr: Instruction = (&buf.buf[..buf.msg_len as usize]).try_into()?;
 
     Ok(match instr {
         Instruction::SignTransaction(idx, raw) => {
-            if free(|cs| {
-                let cipher = CIPHER.borrow(cs).take();
-                let is_none = cipher.is_none();
-                CIPHER.borrow(cs).set(cipher);
-                is_none
-            }) {
-                wallet.fill_cipher(KeyInputBuffer::wait_for_key())?;
-            }
-           
-            Response::Signature(
-                wallet.sign_raw(idx as usize, raw)?
-            )
+            let digest = Keccak256::digest(raw).into();
+            Response::Signature(audit::logged(dev, AuditOp::SignTransaction, idx, digest, |dev| {
+                wallet.unlock(dev)?;
+                wallet.review_recipient(dev, idx as usize, decode_recipient(raw))?;
+
+                wallet.sign_raw(dev, idx as usize, raw)
+            })?)
         },
         Instruction::GetAddress(idx) => {
+            wallet.unlock(dev)?;
+            Response::Address(wallet.account(dev, idx as usize)?)
+        },
+        Instruction::GetAddressList => {
+            wallet.unlock(dev)?;
+            Response::AddressList(wallet.addr_list(dev)?)
+        },
+        Instruction::AddBookEntry(label, addr) => {
+            wallet.update_settings(dev, |settings| {
+                settings.book.add(label, addr).map(|_| ())
+            })?;
+            Response::Done
+        },
+        Instruction::RemoveBookEntry(idx) => {
+            wallet.update_settings(dev, |settings| {
+                settings.book.remove(idx as usize)
+            })?;
+            Response::Done
+        },
+        Instruction::SetAllowlistOnly(idx, enabled) => {
             if idx as usize >= ACCOUNT_NUM {
                 return Err(Error::AccountIdxOOB)
             }
-            Response::Address((
-                wallet.addrs[idx as usize],
-                wallet.pubkeys[idx as usize]
-            ))
+            wallet.update_settings(dev, |settings| {
+                settings.allowlist_only[idx as usize] = enabled;
+                Ok(())
+            })?;
+            Response::Done
         },
-        Instruction::GetAddressList => {
-            Response::AddressList(wallet.addrs)
+        Instruction::GetAddressBook => {
+            wallet.unlock(dev)?;
+            Response::AddressBook(wallet.settings(dev)?.book.entries.map(|entry| BookEntry {
+                used: entry.used,
+                label: entry.label,
+                addr: entry.addr
+            }))
+        },
+        Instruction::SetupHiddenZone => {
+            wallet.update(dev, |dev, wallet| {
+                let passcode = dev.board.wait_for_key();
+                initialize_secondary_zone(
+                    dev, wallet, HIDDEN_ZONE, 
+                    passcode, 
+                    DuressPolicy::Decoy
+                )
+            })?;
+            // the cipher may belong to the replaced zone
+            lock(dev);
+            Response::Done
+        },
+        Instruction::SetupDuressZone(policy) => {
+            wallet.update(dev, |dev, wallet| {
+                let passcode = dev.board.wait_for_key();
+                initialize_secondary_zone(
+                    dev, wallet, DURESS_ZONE, 
+                    passcode, 
+                    policy
+                )
+            })?;
+            lock(dev);
+            Response::Done
+        },
+        Instruction::SetBlindSigning(enabled) => {
+            wallet.update_settings(dev, |settings| {
+                settings.blind_signing = enabled;
+                Ok(())
+            })?;
+            Response::Done
+        },
+        Instruction::SignHash(idx, hash) => {
+            Response::Signature(audit::logged(dev, AuditOp::SignHash, idx, hash, |dev| {
+                wallet.unlock(dev)?;
+                let settings = wallet.settings(dev)?;
+                if !settings.blind_signing {
+                    return Err(Error::BlindSigningDisabled)
+                }
+                // a hash has no recipient to find in the address book
+                if *settings.allowlist_only.get(idx as usize).ok_or(Error::AccountIdxOOB)? {
+                    return Err(Error::RecipientNotAllowed)
+                }
+
+                show_status(&mut dev.board, Status::BlindSign)?;
+                if !dev.board.wait_for_confirm() {
+                    return Err(Error::UserRejected)
+                }
+
+                wallet.sign_hash(dev, idx as usize, &hash)
+            })?)
+        },
+        Instruction::Decrypt(idx, scheme, payload) => {
+            wallet.unlock(dev)?;
+
+            let mut buf = [0; MAX_MSG_LEN];
+            buf[..payload.len()].copy_from_slice(payload);
+            let plaintext = wallet.decrypt(
+                dev, idx as usize, scheme, &mut buf[..payload.len()]
+            )?;
+            let len = plaintext.len();
+
+            show_text(&mut dev.board, b"dECrYPt")?;
+            if !dev.board.wait_for_confirm() {
+                return Err(Error::UserRejected)
+            }
+
+            buf.copy_within(plaintext, 0);
+            Response::Plaintext(buf, len)
+        },
+        Instruction::GetEncryptionPubkey(idx) => {
+            wallet.unlock(dev)?;
+            Response::EncryptionPubkey(wallet.encryption_pubkey(dev, idx as usize)?)
+        },
+        Instruction::GetDiagnostics => {
+            let diag = dev.diagnostics;
+            Response::Diagnostics(Diagnostics {
+                status: diag.status as u8,
+                slot: diag.slot,
+                seq: diag.seq,
+                version: diag.version,
+                valid: diag.valid,
+                agreeing: diag.agreeing,
+                copies: SLOT_REPEAT as u8
+            })
+        },
+        Instruction::GetFirmwareInfo => Response::FirmwareInfo(
+            RUNNING_SLOT as u8, running_version::<B>()
+        ),
+        Instruction::GetInfo => Response::Info(Info {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: running_version::<B>(),
+            git_hash: git_hash(),
+            account_num: ACCOUNT_NUM as u8,
+            max_msg_len: MAX_MSG_LEN as u32,
+            instructions: INSTRUCTION_SET,
+            initialized: wallet.initialized,
+            unlocked: is_unlocked(dev),
+            uid: B::unique_id()
+        }),
+        Instruction::SetBaudRate(baud) => {
+            if !BAUD_RATES.contains(&baud) {
+                return Err(Error::InvalidSerialConfig)
+            }
+            dev.pending_baud = Some(baud);
+            Response::Done
+        },
+        Instruction::ReadAuditLog(start) => {
+            let (entries, count) = audit::read::<B>(start);
+            Response::AuditLog(entries, count)
+        },
+        Instruction::GetCrashReport => Response::CrashReport(dev.board.take_crash_report()),
+        Instruction::SignBegin(idx, size) => {
+            signing::begin(dev, idx, size)?;
+            Response::Done
+        },
+        Instruction::SignChunk(offset, data) => {
+            signing::write_chunk(dev, offset, data)?;
+            Response::Done
+        },
+        Instruction::SignFinish => {
+            Response::Signature(signing::finish(dev, wallet)?)
+        },
+        Instruction::FirmwareBegin(header) => {
+            firmware::begin(dev, header)?;
+            Response::Done
+        },
+        Instruction::FirmwareChunk(offset, data) => {
+            firmware::write_chunk(dev, offset, data)?;
+            Response::Done
+        },
+        Instruction::FirmwareFinish => {
+            firmware::finish(dev)?;
+            Response::Done
+        },
+        Instruction::Unlock => {
+            let passcode = dev.board.wait_for_key();
+            wallet.fill_cipher(dev, passcode)?;
+            Response::Done
         },
     })
 }
@@ -0,0 +1,371 @@
+use core::{
+    num::NonZeroU32, ptr::{addr_of, addr_of_mut},
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering}
+};
+
+use cortex_m::peripheral::{SCB, syst::SystClkSource};
+use cortex_m_rt::exception;
+use cortex_m_semihosting::{hprint, hprintln, nr::open, syscall};
+use ethdwallet_protocol::CrashReport;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use stm32f4::stm32f407::{self, interrupt, USART1};
+
+use crate::{
+    device::Device,
+    error::{Error, Result},
+    hal::{WalletStorage, AuditStorage, FirmwareStorage, KeyInput, Display, Transport, EntropySource, System},
+    input::{MsgBuffer, FIXED_KEY_LEN},
+    display::{Screen, glyph, draw},
+    entropy::Trng,
+    firmware::HEADER_FIELDS_LEN,
+    wallet::storage::{Slot, SLOT_NUM},
+    audit::{LogSector, LOG_SECTOR_NUM}
+};
+
+/// backs the wallet sectors, QEMU loads it into flash when it starts
+/// (`-device loader,file=wallet.bin,addr=0x08080000,force-raw=on`)
+const WALLET_FILE: &str = "wallet.bin\0";
+/// where the audit log sectors (0x080C0000) are in the wallet file
+const LOG_OFFSET: usize = 0x4_0000;
+const LOG_SECTOR_SIZE: usize = 128 * 1024;
+/// the TRNG of the host stands in for the missing one
+const ENTROPY_FILE: &str = "/dev/urandom\0";
+
+/// milliseconds since the start, counted by SysTick
+static TICKS: AtomicU32 = AtomicU32::new(0);
+
+/// USART1 DMA is not emulated, the interrupt fills the ring instead
+const RX_RING_LEN: usize = 2048;
+static mut RX_RING: [u8; RX_RING_LEN] = [0; RX_RING_LEN];
+/// where the interrupt writes the next byte
+static RX_HEAD: AtomicUsize = AtomicUsize::new(0);
+
+/// QEMU maps flash as ROM, the sectors keep what was loaded at start.
+/// writes go to the wallet file and show up from the next start
+#[link_section = ".wallet_a"]
+#[no_mangle]
+pub static mut WALLET_A: Slot = Slot::erased();
+
+#[link_section = ".wallet_b"]
+#[no_mangle]
+pub static mut WALLET_B: Slot = Slot::erased();
+
+#[link_section = ".log_a"]
+#[no_mangle]
+pub static mut LOG_A: LogSector = LogSector::erased();
+
+#[link_section = ".log_b"]
+#[no_mangle]
+pub static mut LOG_B: LogSector = LogSector::erased();
+
+fn host_open(path: &str, mode: usize) -> Option<usize> {
+    match unsafe { syscall!(OPEN, path.as_ptr(), mode, path.len() - 1) } as isize {
+        -1 => None,
+        fd => Some(fd as usize)
+    }
+}
+
+fn host_read(fd: usize, buf: &mut [u8]) -> bool {
+    unsafe { syscall!(READ, fd, buf.as_mut_ptr(), buf.len()) == 0 }
+}
+
+fn host_write_at(fd: usize, pos: usize, data: &[u8]) -> Result<()> {
+    let written = unsafe {
+        syscall!(SEEK, fd, pos) == 0
+            && syscall!(WRITE, fd, data.as_ptr(), data.len()) == 0
+    };
+    match written {
+        true => Ok(()),
+        false => Err(Error::Flash)
+    }
+}
+
+/// random bytes read from the host through semihosting
+pub struct HostRandom(usize);
+
+impl RngCore for HostRandom {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.try_fill_bytes(dest).unwrap()
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> core::result::Result<(), rand::Error> {
+        match host_read(self.0, dest) {
+            true => Ok(()),
+            false => Err(NonZeroU32::new(rand::Error::CUSTOM_START).unwrap().into())
+        }
+    }
+}
+
+/// the parts of the board QEMU emulates, everything else goes through semihosting:
+/// the keypad reads lines from the console, the display is drawn on it,
+/// and the host talks to USART1 on a QEMU chardev (`-serial pty`)
+pub struct Board {
+    /// written here, the interrupt only reads it
+    serial: USART1,
+    trng: Trng<HostRandom>,
+    wallet_fd: usize,
+    /// where the next byte of RX_RING is read from
+    rx_tail: usize
+}
+
+impl WalletStorage for Board {
+    fn slots() -> [&'static Slot; SLOT_NUM] {
+        unsafe { [&*addr_of!(WALLET_A), &*addr_of!(WALLET_B)] }
+    }
+
+    fn erase(&mut self, slot: usize) -> Result<()> {
+        let erased = [0xff; 256];
+        (0..core::mem::size_of::<Slot>()).step_by(erased.len()).try_for_each(|offset| {
+            self.program(slot, offset, &erased)
+        })
+    }
+
+    fn program(&mut self, slot: usize, offset: usize, data: &[u8]) -> Result<()> {
+        host_write_at(self.wallet_fd, slot * core::mem::size_of::<Slot>() + offset, data)
+    }
+}
+
+impl AuditStorage for Board {
+    fn log_sectors() -> [&'static LogSector; LOG_SECTOR_NUM] {
+        unsafe { [&*addr_of!(LOG_A), &*addr_of!(LOG_B)] }
+    }
+
+    fn erase_log(&mut self, sector: usize) -> Result<()> {
+        let erased = [0xff; 256];
+        (0..LOG_SECTOR_SIZE).step_by(erased.len()).try_for_each(|offset| {
+            self.program_log(sector, offset, &erased)
+        })
+    }
+
+    fn program_log(&mut self, sector: usize, offset: usize, data: &[u8]) -> Result<()> {
+        host_write_at(self.wallet_fd, LOG_OFFSET + sector * LOG_SECTOR_SIZE + offset, data)
+    }
+}
+
+/// there is no bootloader to hand an image to, updates are accepted and dropped
+impl FirmwareStorage for Board {
+    fn image_header(_slot: usize) -> [u8; HEADER_FIELDS_LEN] {
+        [0xff; HEADER_FIELDS_LEN]
+    }
+
+    fn image_confirmed(_slot: usize) -> bool {
+        true
+    }
+
+    fn erase_image(&mut self, slot: usize) -> Result<()> {
+        hprintln!("firmware: erase slot {}", slot);
+        Ok(())
+    }
+
+    fn program_image(&mut self, _slot: usize, _offset: usize, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// read a line from the console, without the line break
+fn read_line(buf: &mut [u8]) -> &[u8] {
+    let mut len = 0;
+    loop {
+        match unsafe { syscall!(READC) } as u8 {
+            b'\n' => return &buf[..len],
+            b'\r' => {},
+            ch if len < buf.len() => {
+                buf[len] = ch;
+                len += 1;
+            },
+            _ => {}
+        }
+    }
+}
+
+impl KeyInput for Board {
+    fn wait_for_key(&mut self) -> [u8; FIXED_KEY_LEN] {
+        let mut buf = [0; 16];
+        loop {
+            hprint!("keypad ({} digits): ", FIXED_KEY_LEN);
+            let line = read_line(&mut buf);
+            if line.len() == FIXED_KEY_LEN && line.iter().all(|ch| ch.is_ascii_digit()) {
+                // the keypad reports the segments of the digit pressed
+                let mut key = [0; FIXED_KEY_LEN];
+                key.iter_mut().zip(line).for_each(|(key, ch)| *key = glyph(*ch));
+                return key
+            }
+        }
+    }
+
+    fn wait_for_confirm(&mut self) -> bool {
+        let mut buf = [0; 16];
+        loop {
+            hprint!("confirm? [y/n]: ");
+            match read_line(&mut buf) {
+                b"y" | b"Y" => return true,
+                b"n" | b"N" => return false,
+                _ => continue
+            }
+        }
+    }
+}
+
+impl Display for Board {
+    /// the console does not animate, a text longer than the display takes more lines
+    fn show(&mut self, screen: &Screen) -> Result<()> {
+        for line in screen.pages().flat_map(draw) {
+            hprintln!("{}", core::str::from_utf8(&line).unwrap());
+        }
+        Ok(())
+    }
+}
+
+impl Transport for Board {
+    fn receive(&mut self, deadline: Option<u32>) -> Option<MsgBuffer> {
+        let mut buf = MsgBuffer::new();
+        loop {
+            let head = RX_HEAD.load(Ordering::Acquire);
+            let tail = self.rx_tail;
+
+            if head != tail {
+                let end = if head > tail { head } else { RX_RING_LEN };
+                let bytes = unsafe { &(&*addr_of!(RX_RING))[tail..end] };
+                let taken = buf.read(bytes, Self::millis());
+                self.rx_tail = (tail + taken) % RX_RING_LEN;
+            }
+            if buf.done() {
+                return Some(buf)
+            }
+            if buf.timed_out(deadline, Self::millis()) {
+                return None
+            }
+
+            if head == tail {
+                cortex_m::asm::wfi();
+            }
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        bytes.iter().for_each(|byte| {
+            while self.serial.sr.read().txe().bit_is_clear() {}
+            self.serial.dr.write(|w| w.dr().bits(*byte as u16));
+        });
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        while self.serial.sr.read().tc().bit_is_clear() {}
+        Ok(())
+    }
+
+    /// the baud rate is up to the chardev
+    fn set_baud_rate(&mut self, _baud: u32) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl EntropySource for Board {
+    fn fill(&mut self, dest: &mut [u8]) -> Result<()> {
+        self.trng.fill(dest)
+    }
+}
+
+impl System for Board {
+    /// QEMU runs from the 16MHz HSI, RCC is not emulated
+    fn delay_us(&mut self, us: u32) {
+        cortex_m::asm::delay(us * 16);
+    }
+
+    fn millis() -> u32 {
+        TICKS.load(Ordering::Relaxed)
+    }
+
+    /// the system memory holding the id is not emulated
+    fn unique_id() -> [u8; 12] {
+        [0; 12]
+    }
+
+    /// QEMU does not emulate the IWDG
+    fn feed_watchdog(&mut self) {}
+
+    fn reset() -> ! {
+        SCB::sys_reset()
+    }
+
+    /// a panic halts QEMU, there is nothing to report after it
+    fn take_crash_report(&mut self) -> Option<CrashReport> {
+        None
+    }
+}
+
+#[allow(non_snake_case)]
+#[exception]
+fn SysTick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[allow(non_snake_case)]
+#[interrupt]
+fn USART1() {
+    // reading DR does not get in the way of `Board::write`
+    let serial = unsafe { &*USART1::ptr() };
+    while serial.sr.read().rxne().bit_is_set() {
+        let head = RX_HEAD.load(Ordering::Relaxed);
+        unsafe { (*addr_of_mut!(RX_RING))[head] = serial.dr.read().dr().bits() as u8 };
+        RX_HEAD.store((head + 1) % RX_RING_LEN, Ordering::Release);
+    }
+}
+
+pub fn init() -> Result<Device<Board>> {
+    let (Some(dp), Some(mut cp)) = (
+        stm32f407::Peripherals::take(),
+        cortex_m::peripheral::Peripherals::take()
+    ) else {
+        return Err(Error::HalInitError)
+    };
+
+    // a tick every millisecond of the 16MHz HSI
+    cp.SYST.set_clock_source(SystClkSource::Core);
+    cp.SYST.set_reload(16_000 - 1);
+    cp.SYST.clear_current();
+    cp.SYST.enable_counter();
+    cp.SYST.enable_interrupt();
+
+    // the baud rate is up to the chardev
+    dp.USART1.cr1.write(|w| w
+        .ue().enabled()
+        .te().enabled()
+        .re().enabled()
+        .rxneie().enabled()
+    );
+
+    let (Some(wallet), Some(random)) = (
+        host_open(WALLET_FILE, open::RW_BINARY),
+        host_open(ENTROPY_FILE, open::R_BINARY)
+    ) else {
+        return Err(Error::HalInitError)
+    };
+
+    // the same as the board, the RNG is seeded even if the TRNG fails
+    let mut trng = Trng::new(HostRandom(random));
+    let mut seed = [0; 32];
+    let _ = trng.startup().and_then(|_| trng.fill(&mut seed));
+
+    let board = Board { serial: dp.USART1, trng, wallet_fd: wallet, rx_tail: 0 };
+
+    unsafe {
+        stm32f407::NVIC::unmask(stm32f407::interrupt::USART1);
+    }
+
+    Ok(Device::new(board, ChaCha20Rng::from_seed(seed)))
+}
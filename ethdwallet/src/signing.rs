@@ -0,0 +1,86 @@
+// signing of transactions longer than a request: the chunks go through keccak256
+// on the way in, only the head holding the recipient is kept for the review
+use ethdwallet_protocol::{AuditOp, Signature};
+use sha3::{Digest, Keccak256};
+
+use crate::{
+    audit,
+    device::Device,
+    error::{Error, Result},
+    hal::Hal,
+    tx::{decode_recipient_head, TX_HEAD_LEN},
+    wallet::{Wallet, ACCOUNT_NUM}
+};
+
+pub struct SignSession {
+    account: u8,
+    size: u32,
+    written: u32,
+    hasher: Keccak256,
+    head: [u8; TX_HEAD_LEN]
+}
+
+impl SignSession {
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<()> {
+        if offset != self.written || self.written as usize + data.len() > self.size as usize {
+            return Err(Error::SigningIncomplete)
+        }
+
+        let start = offset as usize;
+        if start < TX_HEAD_LEN {
+            let len = data.len().min(TX_HEAD_LEN - start);
+            self.head[start..start + len].copy_from_slice(&data[..len]);
+        }
+        self.hasher.update(data);
+        self.written += data.len() as u32;
+        Ok(())
+    }
+}
+
+/// start a session, the last one is dropped if it was not finished
+pub fn begin<B: Hal>(dev: &mut Device<B>, account: u8, size: u32) -> Result<()> {
+    if account as usize >= ACCOUNT_NUM {
+        return Err(Error::AccountIdxOOB)
+    }
+
+    dev.sign_session = Some(SignSession {
+        account,
+        size,
+        written: 0,
+        hasher: Keccak256::new(),
+        head: [0; TX_HEAD_LEN]
+    });
+    Ok(())
+}
+
+/// chunks are hashed in order, `offset` is counted from the start of the transaction
+pub fn write_chunk<B: Hal>(dev: &mut Device<B>, offset: u32, data: &[u8]) -> Result<()> {
+    match &mut dev.sign_session {
+        Some(session) => session.write(offset, data),
+        None => Err(Error::SigningIncomplete)
+    }
+}
+
+/// bytes hashed by the open session
+pub fn written<B: Hal>(dev: &Device<B>) -> Option<u32> {
+    dev.sign_session.as_ref().map(|session| session.written)
+}
+
+/// ask for the passcode and review the recipient as `SignTransaction` does,
+/// then sign the digest. the session ends either way
+pub fn finish<B: Hal>(dev: &mut Device<B>, wallet: &Wallet) -> Result<Signature> {
+    let session = dev.sign_session.take();
+    let Some(session) = session.filter(|session| session.written == session.size) else {
+        return Err(Error::SigningIncomplete)
+    };
+
+    let idx = session.account as usize;
+    let digest = session.hasher.finalize().into();
+    audit::logged(dev, AuditOp::SignTransaction, session.account, digest, |dev| {
+        let head = &session.head[..TX_HEAD_LEN.min(session.size as usize)];
+        wallet.unlock(dev)?;
+        wallet.review_recipient(dev, idx, decode_recipient_head(head))?;
+
+        wallet.sign_digest(dev, idx, &digest)
+    })
+}
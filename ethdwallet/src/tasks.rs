@@ -0,0 +1,231 @@
+// the tasks of the board, scheduled by RTIC by priority:
+// 3 the interrupts, which only take a flag or spawn a task,
+// 2 the ZLG7290, the keypad and the display, sharing the bus,
+// 1 the serial task, which runs the protocol and everything it calls
+// with the `Device` it owns, preempted by the others
+use core::sync::atomic::Ordering;
+
+use crate::{
+    board::*,
+    error::Result,
+    i2c::Panel,
+    display::{glyph, DIGIT_NUM, SEG7_PLACEHOLDER},
+    input::{KeyInputBuffer, KeyInputState, FIXED_KEY_LEN}
+};
+
+#[rtic::app(device = stm32f4::stm32f407, peripherals = true, dispatchers = [SPI1, SPI2])]
+mod app {
+    use core::sync::atomic::Ordering;
+
+    use ethdwallet_protocol::CrashCause;
+    use fugit::TimerDurationU32;
+    use heapless::spsc::{Queue, Producer};
+    use stm32f4::stm32f407::{USART1, DMA2, TIM2};
+    use stm32f4xx_hal::{gpio::{Input, Pin, ExtiPin}, timer::{CounterUs, Event}};
+
+    use crate::{
+        board::*,
+        device::Device,
+        display::DIGIT_NUM,
+        i2c::Panel,
+        input::{KeyInputBuffer, KeyInputState},
+        main_loop::run
+    };
+
+    #[shared]
+    struct Shared {
+        panel: Panel,
+        /// the keys read so far, None when nobody waits for them
+        keys: Option<KeyInputBuffer>
+    }
+
+    #[local]
+    struct Local {
+        device: Option<Device<Board>>,
+        finished: Producer<'static, KeyInputBuffer, KEY_QUEUE_LEN>,
+        key_trigger: Pin<'D', 13, Input>,
+        dog: CounterUs<TIM2>,
+        rx_dma: DMA2
+    }
+
+    #[init(local = [queue: Queue<KeyInputBuffer, KEY_QUEUE_LEN> = Queue::new()])]
+    fn init(mut cx: init::Context) -> (Shared, Local, init::Monotonics) {
+        crate::heap_init();
+        let (finished, keys) = cx.local.queue.split();
+        let parts = match crate::init::init(cx.device, &mut cx.core.SYST, keys) {
+            Ok(parts) => parts,
+            Err(e) => panic!("init failed: {:?}", e)
+        };
+
+        serial::spawn().ok();
+        (
+            Shared { panel: parts.panel, keys: None },
+            Local {
+                device: Some(Device::new(parts.board, parts.rng)),
+                finished,
+                key_trigger: parts.key_trigger,
+                dog: parts.dog,
+                rx_dma: parts.rx_dma
+            },
+            init::Monotonics()
+        )
+    }
+
+    /// the protocol, it never returns
+    #[task(priority = 1, local = [device])]
+    fn serial(cx: serial::Context) {
+        run(cx.local.device.as_mut().unwrap())
+    }
+
+    /// read keys from `state` on, see `Board::wait_until`
+    #[task(priority = 2, shared = [keys])]
+    fn listen(mut cx: listen::Context, state: KeyInputState) {
+        cx.shared.keys.lock(|keys| {
+            *keys = Some(KeyInputBuffer { state, ..KeyInputBuffer::new() });
+        });
+    }
+
+    /// read the key pressed and echo it, what is finished goes to the serial task
+    #[task(priority = 2, shared = [panel, keys], local = [finished])]
+    fn keypad(cx: keypad::Context) {
+        let finished = cx.local.finished;
+        (cx.shared.panel, cx.shared.keys).lock(|panel, keys| {
+            let mut code = [0x00];
+            let Some(key) = keys else {
+                // drop the key, nobody waits for it
+                let _ = panel.write_read(&[0x01], &mut code);
+                return
+            };
+            if super::read_key(panel, key, &mut code).is_err() {
+                return
+            }
+
+            if matches!(key.state, KeyInputState::Finished | KeyInputState::Decided(_)) {
+                let _ = finished.enqueue(*key);
+                *keys = None;
+            }
+        });
+    }
+
+    #[task(priority = 2, shared = [panel], capacity = 4)]
+    fn show(mut cx: show::Context, segs: [u8; DIGIT_NUM]) {
+        cx.shared.panel.lock(|panel| {
+            let _ = super::show_segs(panel, segs);
+        });
+    }
+
+    #[task(binds = EXTI15_10, priority = 3, local = [key_trigger])]
+    fn key_pressed(cx: key_pressed::Context) {
+        cx.local.key_trigger.clear_interrupt_pending_bit();
+        super::toggle_led();
+        keypad::spawn().ok();
+    }
+
+    /// the bytes are taken by DMA, the line going idle after a frame only wakes the serial task
+    #[task(binds = USART1, priority = 3)]
+    fn usart1(_: usart1::Context) {
+        let usart = unsafe { &*USART1::ptr() };
+        let sr = usart.sr.read();
+        if sr.fe().bit_is_set() || sr.nf().bit_is_set() || sr.ore().bit_is_set() {
+            RX_ERROR.store(true, Ordering::Relaxed);
+        }
+        // reading SR then DR clears IDLE and the errors
+        let _ = usart.dr.read();
+    }
+
+    /// half of RX_RING or all of it is written, wakes the serial task
+    #[task(binds = DMA2_STREAM2, priority = 3, local = [rx_dma])]
+    fn rx_dma(cx: rx_dma::Context) {
+        cx.local.rx_dma.lifcr.write(|w| w.chtif2().set_bit().ctcif2().set_bit());
+    }
+
+    /// the RTC wakeup timer, only STOP waits for it, see `Board::stop`
+    #[task(binds = RTC_WKUP, priority = 3)]
+    fn rtc_wakeup(_: rtc_wakeup::Context) {
+        clear_rtc_wakeup();
+    }
+
+    #[task(binds = SysTick, priority = 3)]
+    fn tick(_: tick::Context) {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// the device is expected to show signs of life every minute,
+    /// it is given 100ms more before it is reset
+    #[task(binds = TIM2, priority = 3, local = [dog, rapid: bool = false])]
+    fn watchdog(cx: watchdog::Context) {
+        let dog = cx.local.dog;
+        dog.clear_interrupt(Event::Update);
+
+        let alive = WATCHDOG.swap(false, Ordering::SeqCst);
+        let rapid = match (alive, *cx.local.rapid) {
+            (true, false) => return,
+            (true, true) => false,
+            (false, true) => crate::crash::reset(CrashCause::SoftWatchdog),
+            (false, false) => true
+        };
+
+        let period = match rapid {
+            true => TimerDurationU32::millis(100),
+            false => TimerDurationU32::minutes(1)
+        };
+        if dog.start(period).is_err() {
+            crate::crash::reset(CrashCause::SoftWatchdog)
+        }
+        *cx.local.rapid = rapid;
+    }
+}
+
+/// read the key code from the ZLG7290 into `key`, a digit is echoed where it is typed,
+/// the digits before it are masked
+fn read_key(panel: &mut Panel, key: &mut KeyInputBuffer, code: &mut [u8; 1]) -> Result<()> {
+    panel.write_read(&[0x01], code)?;
+    if let Some(num) = to_segled_value(code[0]) {
+        let idx = match key.state {
+            KeyInputState::Reading(p) => p,
+            _ => return Ok(())
+        };
+        key.read(num);
+
+        panel.write(&[idx as u8 + ZLG7290_DPRAM, num])?;
+        if idx > 0 {
+            panel.write(&[idx as u8 + ZLG7290_DPRAM - 1, SEG7_PLACEHOLDER])?
+        }
+
+        if idx == FIXED_KEY_LEN - 1 {
+            panel.write(&[ZLG7290_DPRAM + 7, SEG7_PLACEHOLDER])?;
+        }
+    } else {
+        key.decide(code[0]);
+    }
+    Ok(())
+}
+
+fn show_segs(panel: &mut Panel, segs: [u8; DIGIT_NUM]) -> Result<()> {
+    segs.iter().enumerate().try_for_each(|(idx, seg)| {
+        panel.write(&[ZLG7290_DPRAM + idx as u8, *seg])
+    })
+}
+
+/// a key has been pressed
+fn toggle_led() {
+    let state = LED_STATE.fetch_xor(true, Ordering::Relaxed);
+    set_led(state);
+}
+
+/// map a key code of ZLG7290 to the segment value of its digit
+fn to_segled_value(val: u8) -> Option<u8> {
+    Some(match val {
+        28 => glyph(b'1'),
+        27 => glyph(b'2'),
+        26 => glyph(b'3'),
+        20 => glyph(b'4'),
+        19 => glyph(b'5'),
+        18 => glyph(b'6'),
+        12 => glyph(b'7'),
+        11 => glyph(b'8'),
+        10 => glyph(b'9'),
+        3  => glyph(b'0'),
+        _ => return None,
+    })
+}
@@ -0,0 +1,83 @@
+use core::mem::size_of;
+
+use crate::wallet::safe_zone::EthAddr;
+
+/// split the header of the first rlp item from `raw`, returns (is_list, payload length, rest).
+/// the payload starts the rest, it may be cut off
+fn split_header(raw: &[u8]) -> Option<(bool, usize, &[u8])> {
+    let (&prefix, rest) = raw.split_first()?;
+
+    let (is_list, len_of_len, len) = match prefix {
+        // a single byte is its own payload
+        0x00..=0x7f => return Some((false, 1, raw)),
+        0x80..=0xb7 => (false, 0, (prefix - 0x80) as usize),
+        0xb8..=0xbf => {
+            let len_of_len = (prefix - 0xb7) as usize;
+            (false, len_of_len, be_usize(rest.get(..len_of_len)?)?)
+        },
+        0xc0..=0xf7 => (true, 0, (prefix - 0xc0) as usize),
+        0xf8..=0xff => {
+            let len_of_len = (prefix - 0xf7) as usize;
+            (true, len_of_len, be_usize(rest.get(..len_of_len)?)?)
+        }
+    };
+
+    Some((is_list, len, &rest[len_of_len..]))
+}
+
+/// split the first rlp item from `raw`, returns (is_list, payload, rest)
+fn split_item(raw: &[u8]) -> Option<(bool, &[u8], &[u8])> {
+    let (is_list, len, rest) = split_header(raw)?;
+    Some((is_list, rest.get(..len)?, &rest[len..]))
+}
+
+fn be_usize(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() > size_of::<usize>() {
+        return None
+    }
+    Some(bytes.iter().fold(0, |acc, byte| (acc << 8) | *byte as usize))
+}
+
+/// the longest head of a transaction holding `to`: the type, the list header,
+/// then up to 5 fields of 256-bit integers before it
+pub const TX_HEAD_LEN: usize = 1 + 9 + 5 * 33 + 21;
+
+/// decode the `to` field of an unsigned transaction.
+///
+/// legacy (EIP-155) and typed (EIP-2930, EIP-1559) transactions are supported,
+/// returns None for contract creations and anything that is not a transaction.
+pub fn decode_recipient(raw: &[u8]) -> Option<EthAddr> {
+    find_recipient(raw, false)
+}
+
+/// decode the `to` field from the first `TX_HEAD_LEN` bytes of a transaction,
+/// the same as `decode_recipient` without the rest
+pub fn decode_recipient_head(head: &[u8]) -> Option<EthAddr> {
+    find_recipient(head, true)
+}
+
+fn find_recipient(raw: &[u8], is_head: bool) -> Option<EthAddr> {
+    let (to_idx, payload) = match *raw.first()? {
+        0x01 => (4, &raw[1..]),
+        0x02 => (5, &raw[1..]),
+        _ => (3, raw)
+    };
+
+    let (true, len, fields) = split_header(payload)? else {
+        return None
+    };
+    let mut fields = match is_head {
+        true => &fields[..len.min(fields.len())],
+        false => fields.get(..len)?
+    };
+
+    for _ in 0..to_idx {
+        let (_, _, rest) = split_item(fields)?;
+        fields = rest;
+    }
+
+    let (false, to, _) = split_item(fields)? else {
+        return None
+    };
+    to.try_into().ok()
+}
@@ -1,160 +1,262 @@
-use core::{cmp::Ordering, intrinsics::size_of, ptr::addr_of, slice};
+use core::ops::Range;
 
-use chacha20::cipher::StreamCipher;
-use cortex_m::{interrupt::free, prelude::*};
+use chacha20::{cipher::StreamCipher, ChaCha20};
 use rand::Rng;
 
 use crate::{
     input::FIXED_KEY_LEN, 
     error::{Error, Result}, 
-    update_global, 
-    global::{CIPHER, RNG, DELAY}, set_global
+    device::Device,
+    hal::Hal,
+    display::{show_text, show_hex, show_status, Status}
 };
 
-use self::{safe_zone::{SafeZone, EthAddr, ZKPLAIN, Signature}, utils::get_cipher, initializer::write_wallet};
+use self::{
+    safe_zone::{SafeZone, Settings, EthAddr, ZKPLAIN, Signature, DuressPolicy}, 
+    ecies::Scheme,
+    utils::{get_cipher, ct_eq}, 
+    storage::write_wallet,
+    recovery::recover
+};
 
+pub mod book;
+pub mod ecies;
+pub mod format;
 pub mod initializer;
+pub mod recovery;
 pub mod safe_zone;
+pub mod storage;
 pub mod utils;
 
-pub type PubKey = [u8; 64];
-
-pub(super) const ACCOUNT_NUM: usize = 32;
+pub use ethdwallet_protocol::{PubKey, ACCOUNT_NUM};
 /// length of OTP secret, which is randomly generated when initializing
 pub(super) const OTP_SECRET_LEN: usize = 64;
 
-pub const WALLET_SIZE: usize = size_of::<Wallet>();
-pub const WALLET_REPEAT: usize = 128 * 1024 / size_of::<Wallet>();
-
-/// This static variable is in the flash memory, so its persistent
-/// The initial value will be changed after the wallet is initialized
-#[link_section = ".wallet"]
-#[no_mangle]
-pub static mut WALLET: [Wallet; WALLET_REPEAT] = [Wallet { 
-    initialized: false, 
-    zone: SafeZone {
-        zkmagic: ZKPLAIN,
-        keys: [[0; 32]; ACCOUNT_NUM],
-        otp_secret: [0; 64],
-    },
-    chacha_iv: [0; 12], 
-    addrs: [[0; 20]; ACCOUNT_NUM],
-    pubkeys: [[0; 64]; ACCOUNT_NUM],
-    crc: 2004029866,
-}; WALLET_REPEAT];
-
-pub fn wallet() -> &'static Wallet {
-    let mut crcs = [0; WALLET_REPEAT];
-
-    unsafe {
-        let mut valid_count = 0;
-
-        for i in 0..WALLET_REPEAT {
-            let mut wallet = WALLET[i];
-            let crc = wallet.crc;
-            wallet.crc = 0;
-            let wallet_slice = slice::from_raw_parts(
-                addr_of!(wallet) as *const u8, 
-                WALLET_SIZE
-            );
-
-            if crc32fast::hash(wallet_slice) == crc {
-                crcs[i] = crc;
-                valid_count += 1;  
-            }
-            wallet.crc = crc;
-        };
-
-        if valid_count == 0 {
-            panic!("cannot recover wallet data.")
-        }
-
-        let mut counter = [0; 5];
-
-        for i in 0..5 {
-            counter[i] = crcs.iter().filter(|crc| 
-                (**crc != 0) && (**crc == crcs[i])
-            ).count();
-        }
-        
-        let (correct, count) = counter.iter().enumerate()
-            .max_by(|a, b| 
-                a.1.cmp(b.1) 
-            ).unwrap();
-        
-        if *count == valid_count {
-            return &WALLET[correct]
-        }
+/// every wallet carries the same number of zones, an unused zone is filled with 
+/// random bytes so it cannot be told apart from a zone in use
+pub const ZONE_NUM: usize = 3;
+/// the zone set up with the wallet, it wins over the other zones
+/// when a passcode opens more than one
+pub const MAIN_ZONE: usize = 0;
+/// the zone unlocked by the secondary passcode
+pub const HIDDEN_ZONE: usize = 1;
+/// the decoy zone unlocked by the duress passcode
+pub const DURESS_ZONE: usize = 2;
 
-        let wallet = WALLET[correct];
-
-        write_wallet(wallet);
-        &WALLET[0]
+/// the wallet in use, recovered from flash on the first call
+pub fn wallet<B: Hal>(dev: &mut Device<B>) -> Wallet {
+    match dev.wallet {
+        Some(wallet) => wallet,
+        None => recover(dev)
     }
 }
 
-pub const WALLET_SECTOR: u8 = 8;
-pub const SECTIOR_BASE: usize = 0x0800_0000;
-
-#[repr(align(16))]
 #[derive(Clone, Copy)]
 pub struct Wallet {
     pub initialized: bool,
-    pub zone: SafeZone,
-    /// the iv of chacha, randomly generated.
+    pub zones: [SafeZone; ZONE_NUM],
+    /// the iv of chacha for each zone, randomly generated.
     /// this field is not encrypted 
-    pub chacha_iv: [u8; 12],
+    pub chacha_ivs: [[u8; 12]; ZONE_NUM],
+    /// plaintext accounts of the main zone
     pub addrs: [EthAddr; ACCOUNT_NUM],
     pub pubkeys: [PubKey; ACCOUNT_NUM],
-    pub crc: u32
+    /// the settings of a wallet written before each zone kept its own,
+    /// in plaintext until the main zone seals them at the next start
+    pub unsealed: Option<Settings>
 }
 
 impl Wallet {
     pub const fn new() -> Self {
         Self { 
             initialized: false, 
-            zone: SafeZone {
-                zkmagic: ZKPLAIN,
-                keys: [[0; 32]; ACCOUNT_NUM],
-                otp_secret: [0; 64],
-            },
-            chacha_iv: [0; 12], 
+            zones: [SafeZone::new(); ZONE_NUM],
+            chacha_ivs: [[0; 12]; ZONE_NUM], 
             addrs: [[0; 20]; ACCOUNT_NUM],
             pubkeys: [[0; 64]; ACCOUNT_NUM],
-            crc: 0,
+            unsealed: None
+        }
+    }
+
+    pub fn sign_raw<B: Hal>(&self, dev: &mut Device<B>, idx: usize, raw: &[u8]) -> Result<Signature> {
+        random_delay(dev);
+        let (zone, cipher) = dev.unlocked()?;
+        self.zones[zone].sign_raw(idx, raw, cipher)
+    }
+
+    pub fn sign_hash<B: Hal>(&self, dev: &mut Device<B>, idx: usize, hash: &[u8; 32]) -> Result<Signature> {
+        if !self.settings(dev)?.blind_signing {
+            return Err(Error::BlindSigningDisabled)
         }
+        self.sign_digest(dev, idx, hash)
+    }
+
+    /// sign the keccak256 digest of a transaction reviewed like `sign_raw`
+    pub fn sign_digest<B: Hal>(&self, dev: &mut Device<B>, idx: usize, hash: &[u8; 32]) -> Result<Signature> {
+        random_delay(dev);
+        let (zone, cipher) = dev.unlocked()?;
+        self.zones[zone].sign_hash(idx, hash, cipher)
+    }
+
+    /// decrypt a message sent to an account of the unlocked zone in place,
+    /// returns where the plaintext is in the payload
+    pub fn decrypt<B: Hal>(
+        &self, dev: &mut Device<B>, idx: usize, scheme: Scheme, payload: &mut [u8]
+    ) -> Result<Range<usize>> {
+        let (zone, cipher) = dev.unlocked()?;
+        self.zones[zone].decrypt(idx, scheme, payload, cipher)
+    }
+
+    pub fn encryption_pubkey<B: Hal>(&self, dev: &mut Device<B>, idx: usize) -> Result<[u8; 32]> {
+        let (zone, cipher) = dev.unlocked()?;
+        self.zones[zone].encryption_pubkey(idx, cipher)
     }
 
-    pub fn sign_raw(&self, idx: usize, raw: &[u8]) -> Result<Signature> {
-        update_global!(|
-            mut cipher: Option<CIPHER>, 
-            mut rng: Option<RNG>,
-            mut delay: Option<DELAY>
-        | {
-            let delay_time: u32 = rng.gen();
-            delay.delay_us(delay_time % 10000);
-            self.zone.sign_raw(idx, raw, &mut cipher)
-        })
+    /// find the zone encrypted by this passcode, the first one if several are.
+    /// a zone set up later with the passcode of another zone is never opened,
+    /// so setting up a zone does not tell which passcodes are in use.
+    /// every zone is tried without exiting early, so the time taken 
+    /// does not tell which zone (if any) is opened
+    pub fn match_zone(&self, passcode: [u8; FIXED_KEY_LEN]) -> Option<(usize, ChaCha20)> {
+        let mut matched = None;
+
+        for (idx, (zone, iv)) in self.zones.iter().zip(&self.chacha_ivs).enumerate() {
+            let mut cipher = get_cipher(passcode, iv);
+            let mut zkmagic = zone.zkmagic;
+            cipher.apply_keystream(&mut zkmagic);
+
+            if ct_eq(&zkmagic, &ZKPLAIN) && matched.is_none() {
+                matched = Some((idx, cipher));
+            }
+        }
+
+        matched
     }
 
     /// verify if passcode is correct
-    /// fill the cipher in wallet
-    pub fn fill_cipher(&self, passcode: [u8; FIXED_KEY_LEN]) -> Result<()> {
-        let mut cipher = get_cipher(passcode, &self.chacha_iv);
-        let mut zkmagic = self.zone.zkmagic;
-        cipher.apply_keystream(&mut zkmagic);
-
-        let cipher = match zkmagic.cmp(&ZKPLAIN) {
-            Ordering::Equal => cipher,
-            _ => return Err(Error::WrongPassword)
-        };
+    /// fill the cipher and the active zone in wallet.
+    /// 
+    /// the duress passcode succeeds like any other passcode, 
+    /// the wipe (if configured) is deferred until the response is sent
+    pub fn fill_cipher<B: Hal>(&self, dev: &mut Device<B>, passcode: [u8; FIXED_KEY_LEN]) -> Result<()> {
+        let (zone, mut cipher) = self.match_zone(passcode)
+            .ok_or(Error::WrongPassword)?;
+
+        // every zone has a policy, decrypt it anyway to take the same time
+        let policy = self.zones[zone].policy(&mut cipher);
+        if zone == DURESS_ZONE && policy == DuressPolicy::Wipe {
+            dev.pending_wipe = true;
+        }
 
-        free(|cs| {
-            set_global!(CIPHER, cipher, cs);
-        });
-        
+        dev.cipher = Some((zone, cipher));
         Ok(())
     }
+
+    /// ask for the passcode if the cipher is not filled yet
+    pub fn unlock<B: Hal>(&self, dev: &mut Device<B>) -> Result<()> {
+        if !is_unlocked(dev) {
+            let passcode = dev.board.wait_for_key();
+            self.fill_cipher(dev, passcode)?;
+        }
+        Ok(())
+    }
+
+    /// addresses of the unlocked zone. none are shown while locked,
+    /// as they would differ from those of a duress unlock
+    pub fn addr_list<B: Hal>(&self, dev: &mut Device<B>) -> Result<[EthAddr; ACCOUNT_NUM]> {
+        let (zone, cipher) = dev.unlocked()?;
+        Ok(self.zones[zone].addrs(cipher))
+    }
+
+    /// address and public key of an account in the unlocked zone
+    pub fn account<B: Hal>(&self, dev: &mut Device<B>, idx: usize) -> Result<(EthAddr, PubKey)> {
+        if idx >= ACCOUNT_NUM {
+            return Err(Error::AccountIdxOOB)
+        }
+
+        let (zone, cipher) = dev.unlocked()?;
+        self.zones[zone].account(idx, cipher)
+    }
+
+    /// the address book and the policies of the unlocked zone
+    pub fn settings<B: Hal>(&self, dev: &mut Device<B>) -> Result<Settings> {
+        let (zone, cipher) = dev.unlocked()?;
+        Ok(self.zones[zone].settings(cipher))
+    }
+
+    /// check the recipient of a transaction against the address book of the unlocked zone,
+    /// then show its label (or the whole address scrolling if unknown) on the display.
+    /// `to` is None for contract creations and anything that is not a transaction
+    pub fn review_recipient<B: Hal>(&self, dev: &mut Device<B>, idx: usize, to: Option<EthAddr>) -> Result<()> {
+        let settings = self.settings(dev)?;
+        let allowlist_only = *settings.allowlist_only.get(idx)
+            .ok_or(Error::AccountIdxOOB)?;
+
+        let Some(to) = to else {
+            if allowlist_only {
+                return Err(Error::InvalidTransaction)
+            }
+            return Ok(())
+        };
+
+        match settings.book.find(&to) {
+            Some(entry) => show_text(&mut dev.board, &entry.label),
+            None if allowlist_only => Err(Error::RecipientNotAllowed),
+            None => show_hex(&mut dev.board, &to)
+        }
+    }
+
+    /// ask for the passcode, then persist the changes made by `f` to flash.
+    /// the duress zone is told the passcode is wrong, it cannot change
+    /// anything applying to the real zones
+    pub fn update<B: Hal, F>(&self, dev: &mut Device<B>, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Device<B>, &mut Wallet) -> Result<()>
+    {
+        let passcode = dev.board.wait_for_key();
+        self.fill_cipher(dev, passcode)?;
+        if dev.unlocked()?.0 == DURESS_ZONE {
+            return Err(Error::WrongPassword)
+        }
+
+        let mut wallet = *self;
+        f(dev, &mut wallet)?;
+        write_wallet(dev, &wallet)
+    }
+
+    /// ask for the passcode, then persist the changes made by `f` to the settings
+    /// of the zone it opens. the settings of the other zones are left as they are
+    pub fn update_settings<B: Hal, F>(&self, dev: &mut Device<B>, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Settings) -> Result<()>
+    {
+        let passcode = dev.board.wait_for_key();
+        self.fill_cipher(dev, passcode)?;
+
+        let mut wallet = *self;
+        let (zone, cipher) = dev.unlocked()?;
+        let mut settings = wallet.zones[zone].settings(cipher);
+        f(&mut settings)?;
+        wallet.zones[zone].set_settings(&settings, cipher);
+        write_wallet(dev, &wallet)
+    }
+}
+
+/// wait for up to 10ms before signing, so the time taken varies
+fn random_delay<B: Hal>(dev: &mut Device<B>) {
+    let delay_time: u32 = dev.rng.gen();
+    dev.board.delay_us(delay_time % 10000);
+}
+
+/// the cipher is filled
+pub fn is_unlocked<B: Hal>(dev: &Device<B>) -> bool {
+    dev.cipher.is_some()
+}
+
+/// drop the cipher, the passcode is required again for the next signing
+pub fn lock<B: Hal>(dev: &mut Device<B>) {
+    dev.cipher = None;
+    let _ = show_status(&mut dev.board, Status::Locked);
 }
 
 
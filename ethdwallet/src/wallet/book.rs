@@ -0,0 +1,63 @@
+use crate::error::{Error, Result};
+
+use super::safe_zone::EthAddr;
+
+pub use ethdwallet_protocol::{BOOK_SIZE, LABEL_LEN};
+
+#[derive(Clone, Copy)]
+pub struct BookEntry {
+    pub used: bool,
+    pub label: [u8; LABEL_LEN],
+    pub addr: EthAddr
+}
+
+impl BookEntry {
+    pub const fn empty() -> Self {
+        Self {
+            used: false,
+            label: [0; LABEL_LEN],
+            addr: [0; 20]
+        }
+    }
+}
+
+/// known recipients, kept encrypted in the settings of a zone
+#[derive(Clone, Copy)]
+pub struct AddressBook {
+    pub entries: [BookEntry; BOOK_SIZE]
+}
+
+impl AddressBook {
+    pub const fn new() -> Self {
+        Self { entries: [BookEntry::empty(); BOOK_SIZE] }
+    }
+
+    /// add a recipient to the first free slot, returns the index of the slot
+    pub fn add(&mut self, label: [u8; LABEL_LEN], addr: EthAddr) -> Result<u8> {
+        if self.find(&addr).is_some() {
+            return Err(Error::BookEntryExists)
+        }
+
+        let (idx, entry) = self.entries.iter_mut()
+            .enumerate()
+            .find(|(_, entry)| !entry.used)
+            .ok_or(Error::BookFull)?;
+
+        *entry = BookEntry { used: true, label, addr };
+        Ok(idx as u8)
+    }
+
+    pub fn remove(&mut self, idx: usize) -> Result<()> {
+        match self.entries.get_mut(idx) {
+            Some(entry) if entry.used => {
+                *entry = BookEntry::empty();
+                Ok(())
+            },
+            _ => Err(Error::BookIdxOOB)
+        }
+    }
+
+    pub fn find(&self, addr: &EthAddr) -> Option<&BookEntry> {
+        self.entries.iter().find(|entry| entry.used && entry.addr == *addr)
+    }
+}
@@ -0,0 +1,103 @@
+use core::ops::Range;
+
+use aes::Aes128;
+use crypto_box::{
+    SalsaBox,
+    aead::{AeadInPlace, generic_array::GenericArray}
+};
+use ctr::{Ctr128BE, cipher::{KeyIvInit, StreamCipher}};
+use hmac::{Hmac, Mac};
+use k256::{
+    PublicKey, SecretKey,
+    ecdh::diffie_hellman,
+    sha2::{Sha256, Digest}
+};
+
+use crate::error::{Error, Result};
+
+use super::safe_zone::PrivKey;
+
+pub use ethdwallet_protocol::Scheme;
+
+/// 0x04 + x + y
+const SECP_PUBKEY_LEN: usize = 65;
+const AES_IV_LEN: usize = 16;
+const HMAC_LEN: usize = 32;
+
+const X25519_PUBKEY_LEN: usize = 32;
+const XSALSA_NONCE_LEN: usize = 24;
+const POLY1305_TAG_LEN: usize = 16;
+
+/// decrypt the payload in place, returns where the plaintext is in the payload
+pub fn decrypt(scheme: Scheme, key: &PrivKey, payload: &mut [u8]) -> Result<Range<usize>> {
+    match scheme {
+        Scheme::Secp256k1 => decrypt_secp256k1(key, payload),
+        Scheme::X25519XSalsa20Poly1305 => decrypt_x25519(key, payload)
+    }
+}
+
+/// the public key others use to encrypt for `key` with X25519XSalsa20Poly1305
+pub fn x25519_pubkey(key: &PrivKey) -> [u8; X25519_PUBKEY_LEN] {
+    crypto_box::SecretKey::from(*key).public_key().to_bytes()
+}
+
+fn decrypt_secp256k1(key: &PrivKey, payload: &mut [u8]) -> Result<Range<usize>> {
+    if payload.len() < SECP_PUBKEY_LEN + AES_IV_LEN + HMAC_LEN {
+        return Err(Error::InvalidCiphertext)
+    }
+
+    let (ephemeral, rest) = payload.split_at_mut(SECP_PUBKEY_LEN);
+    let tag_start = rest.len() - HMAC_LEN;
+    let (body, tag) = rest.split_at_mut(tag_start);
+
+    let secret = SecretKey::from_be_bytes(key)?;
+    let ephemeral = PublicKey::from_sec1_bytes(ephemeral)
+        .map_err(|_| Error::InvalidCiphertext)?;
+    let shared = diffie_hellman(secret.to_nonzero_scalar(), ephemeral.as_affine());
+
+    // NIST SP 800-56 concatenation KDF, one round of SHA-256 gives both keys
+    let derived = Sha256::new()
+        .chain_update(1u32.to_be_bytes())
+        .chain_update(shared.raw_secret_bytes())
+        .finalize();
+    let (enc_key, mac_key) = derived.split_at(16);
+    let mac_key = Sha256::digest(mac_key);
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&mac_key)
+        .map_err(|_| Error::CryptoError)?;
+    mac.update(body);
+    mac.verify_slice(tag)
+        .map_err(|_| Error::InvalidCiphertext)?;
+
+    let (iv, ciphertext) = body.split_at_mut(AES_IV_LEN);
+    Ctr128BE::<Aes128>::new(
+        GenericArray::from_slice(enc_key), 
+        GenericArray::from_slice(iv)
+    ).apply_keystream(ciphertext);
+
+    Ok(SECP_PUBKEY_LEN + AES_IV_LEN..tag_start + SECP_PUBKEY_LEN)
+}
+
+fn decrypt_x25519(key: &PrivKey, payload: &mut [u8]) -> Result<Range<usize>> {
+    if payload.len() < X25519_PUBKEY_LEN + XSALSA_NONCE_LEN + POLY1305_TAG_LEN {
+        return Err(Error::InvalidCiphertext)
+    }
+
+    let len = payload.len();
+    let (ephemeral, rest) = payload.split_at_mut(X25519_PUBKEY_LEN);
+    let (nonce, rest) = rest.split_at_mut(XSALSA_NONCE_LEN);
+    let (tag, ciphertext) = rest.split_at_mut(POLY1305_TAG_LEN);
+
+    let ephemeral = crypto_box::PublicKey::from_slice(ephemeral)
+        .map_err(|_| Error::InvalidCiphertext)?;
+    SalsaBox::new(&ephemeral, &crypto_box::SecretKey::from(*key))
+        .decrypt_in_place_detached(
+            GenericArray::from_slice(nonce), 
+            b"", 
+            ciphertext, 
+            GenericArray::from_slice(tag)
+        )
+        .map_err(|_| Error::InvalidCiphertext)?;
+
+    Ok(X25519_PUBKEY_LEN + XSALSA_NONCE_LEN + POLY1305_TAG_LEN..len)
+}
@@ -0,0 +1,319 @@
+// the on-flash format of the wallet.
+//
+// a record is a header (magic, version, payload length), the payload,
+// then the CRC32 of everything before it. every field is written explicitly
+// in little endian, so the format does not depend on the layout rustc picks.
+//
+// when the payload changes, bump `FORMAT_VERSION` and keep a reader for
+// the old version in `decode`, filling new fields with their defaults.
+// `wallet()` writes migrated records back in the current version.
+//
+// the first firmware had no header, see `LegacyWallet`.
+
+use core::mem::{offset_of, size_of};
+
+use crate::error::{Error, Result};
+
+use super::{
+    Wallet, ZONE_NUM, ACCOUNT_NUM, MAIN_ZONE, OTP_SECRET_LEN, PubKey,
+    safe_zone::{SafeZone, Settings, EthAddr, PrivKey},
+    book::{AddressBook, BookEntry}
+};
+
+pub const MAGIC: [u8; 4] = *b"ETHW";
+pub const FORMAT_VERSION: u16 = 2;
+/// the first version keeping the settings in each zone, 
+/// the versions before shared them between the zones in plaintext
+pub const ZONE_SETTINGS_VERSION: u16 = 2;
+/// magic, version and payload length
+pub const HEADER_LEN: usize = 4 + 2 + 4;
+pub const CRC_LEN: usize = 4;
+/// the version given to the copies written by the first firmware
+pub const LEGACY_VERSION: u16 = 0;
+pub const LEGACY_SIZE: usize = size_of::<LegacyWallet>();
+
+/// the wallet of the first firmware, copied to flash as it is in memory.
+/// it is never built, only its layout is used to find the fields,
+/// which is the one rustc picked when the first firmware was built with this toolchain
+#[repr(align(16))]
+#[allow(dead_code)]
+pub struct LegacyWallet {
+    pub initialized: bool,
+    pub zone: LegacyZone,
+    pub chacha_iv: [u8; 12],
+    pub addrs: [EthAddr; ACCOUNT_NUM],
+    pub pubkeys: [PubKey; ACCOUNT_NUM],
+    /// taken over the copy with this field zeroed
+    pub crc: u32
+}
+
+/// a zone without the encrypted copies of the accounts and the policy,
+/// they are added at the first unlock, see `SafeZone::is_sealed`
+pub struct LegacyZone {
+    pub zkmagic: [u8; 32],
+    pub keys: [PrivKey; ACCOUNT_NUM],
+    pub otp_secret: [u8; OTP_SECRET_LEN]
+}
+
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// panics if the buffer is too small, records have a fixed capacity
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.bytes(&[val])
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8)
+    }
+}
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let bytes = self.buf.get(self.pos..self.pos + N)
+            .ok_or(Error::WalletCorrupted)?;
+        self.pos += N;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    /// fill every element of `arr` with `N` bytes
+    pub fn fill<const N: usize>(&mut self, arr: &mut [[u8; N]]) -> Result<()> {
+        arr.iter_mut().try_for_each(|item| {
+            *item = self.bytes()?;
+            Ok(())
+        })
+    }
+}
+
+/// a type stored in the wallet record
+pub trait Persist: Sized {
+    fn write(&self, w: &mut Writer);
+    fn read(r: &mut Reader) -> Result<Self>;
+}
+
+impl Persist for SafeZone {
+    fn write(&self, w: &mut Writer) {
+        w.bytes(&self.zkmagic);
+        self.keys.iter().for_each(|key| w.bytes(key));
+        w.bytes(&self.otp_secret);
+        self.addrs.iter().for_each(|addr| w.bytes(addr));
+        self.pubkeys.iter().for_each(|pubkey| w.bytes(pubkey));
+        w.u8(self.policy);
+        w.bytes(&self.settings);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        let mut zone = read_zone_v1(r)?;
+        zone.settings = r.bytes()?;
+        Ok(zone)
+    }
+}
+
+/// a zone of version 1, which had no settings
+fn read_zone_v1(r: &mut Reader) -> Result<SafeZone> {
+    let mut zone = SafeZone::new();
+    zone.zkmagic = r.bytes()?;
+    r.fill(&mut zone.keys)?;
+    zone.otp_secret = r.bytes()?;
+    r.fill(&mut zone.addrs)?;
+    r.fill(&mut zone.pubkeys)?;
+    zone.policy = r.u8()?;
+    Ok(zone)
+}
+
+impl Persist for AddressBook {
+    fn write(&self, w: &mut Writer) {
+        self.entries.iter().for_each(|entry| {
+            w.bool(entry.used);
+            w.bytes(&entry.label);
+            w.bytes(&entry.addr);
+        })
+    }
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        let mut book = AddressBook::new();
+        book.entries.iter_mut().try_for_each(|entry| {
+            *entry = BookEntry {
+                used: r.bool()?,
+                label: r.bytes()?,
+                addr: r.bytes()?
+            };
+            Ok::<(), Error>(())
+        })?;
+        Ok(book)
+    }
+}
+
+impl Persist for Settings {
+    fn write(&self, w: &mut Writer) {
+        self.book.write(w);
+        self.allowlist_only.iter().for_each(|only| w.bool(*only));
+        w.bool(self.blind_signing);
+    }
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        let mut settings = Settings::new();
+        settings.book = AddressBook::read(r)?;
+        for idx in 0..ACCOUNT_NUM {
+            settings.allowlist_only[idx] = r.bool()?;
+        }
+        settings.blind_signing = r.bool()?;
+        Ok(settings)
+    }
+}
+
+impl Persist for Wallet {
+    fn write(&self, w: &mut Writer) {
+        w.bool(self.initialized);
+        self.zones.iter().for_each(|zone| zone.write(w));
+        self.chacha_ivs.iter().for_each(|iv| w.bytes(iv));
+        self.addrs.iter().for_each(|addr| w.bytes(addr));
+        self.pubkeys.iter().for_each(|pubkey| w.bytes(pubkey));
+        w.bool(self.unsealed.is_some());
+        if let Some(settings) = &self.unsealed {
+            settings.write(w);
+        }
+    }
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        let mut wallet = Wallet::new();
+        wallet.initialized = r.bool()?;
+        for idx in 0..ZONE_NUM {
+            wallet.zones[idx] = SafeZone::read(r)?;
+        }
+        r.fill(&mut wallet.chacha_ivs)?;
+        r.fill(&mut wallet.addrs)?;
+        r.fill(&mut wallet.pubkeys)?;
+        if r.bool()? {
+            wallet.unsealed = Some(Settings::read(r)?);
+        }
+        Ok(wallet)
+    }
+}
+
+/// the settings of version 1 were shared by every zone, they are kept 
+/// for the main zone to seal. the zones are left without settings
+fn read_v1(r: &mut Reader) -> Result<Wallet> {
+    let mut wallet = Wallet::new();
+    wallet.initialized = r.bool()?;
+    for idx in 0..ZONE_NUM {
+        wallet.zones[idx] = read_zone_v1(r)?;
+    }
+    r.fill(&mut wallet.chacha_ivs)?;
+    r.fill(&mut wallet.addrs)?;
+    r.fill(&mut wallet.pubkeys)?;
+    wallet.unsealed = Some(Settings::read(r)?);
+    Ok(wallet)
+}
+
+/// write the wallet as a record of the current version, returns its length
+pub fn encode(wallet: &Wallet, record: &mut [u8]) -> usize {
+    let (header, rest) = record.split_at_mut(HEADER_LEN);
+
+    let mut w = Writer::new(rest);
+    wallet.write(&mut w);
+    let len = w.pos;
+
+    let mut w = Writer::new(header);
+    w.bytes(&MAGIC);
+    w.bytes(&FORMAT_VERSION.to_le_bytes());
+    w.bytes(&(len as u32).to_le_bytes());
+
+    let crc = crc32fast::hash(&record[..HEADER_LEN + len]);
+    record[HEADER_LEN + len..][..CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+    HEADER_LEN + len + CRC_LEN
+}
+
+/// check the header and the CRC of a record, returns its version and payload
+pub fn verify(record: &[u8]) -> Option<(u16, &[u8])> {
+    let mut r = Reader::new(record);
+    let (magic, version, len) = (
+        r.bytes::<4>().ok()?,
+        u16::from_le_bytes(r.bytes().ok()?),
+        u32::from_le_bytes(r.bytes().ok()?) as usize
+    );
+
+    let end = HEADER_LEN.checked_add(len)?;
+    let crc = record.get(end..end.checked_add(CRC_LEN)?)?;
+    let crc = u32::from_le_bytes(crc.try_into().unwrap());
+    if magic != MAGIC || crc32fast::hash(&record[..end]) != crc {
+        return None
+    }
+
+    Some((version, &record[HEADER_LEN..end]))
+}
+
+/// check the CRC of a copy written by the first firmware, returns its payload
+pub fn verify_legacy(copy: &[u8]) -> Option<&[u8]> {
+    let payload = copy.get(..LEGACY_SIZE)?;
+    let at = offset_of!(LegacyWallet, crc);
+    let crc = u32::from_le_bytes(payload[at..at + CRC_LEN].try_into().unwrap());
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&payload[..at]);
+    hasher.update(&[0; CRC_LEN]);
+    hasher.update(&payload[at + CRC_LEN..]);
+    (hasher.finalize() == crc).then_some(payload)
+}
+
+/// the only zone of the first firmware becomes the main zone, which is sealed with
+/// the default settings at the next start. the other zones are left for the caller to fill
+fn read_legacy(payload: &[u8]) -> Result<Wallet> {
+    let payload = payload.get(..LEGACY_SIZE).ok_or(Error::WalletCorrupted)?;
+    let field = |offset: usize| Reader::new(&payload[offset..]);
+
+    let mut wallet = Wallet::new();
+    wallet.initialized = field(offset_of!(LegacyWallet, initialized)).bool()?;
+    wallet.chacha_ivs[MAIN_ZONE] = field(offset_of!(LegacyWallet, chacha_iv)).bytes()?;
+    field(offset_of!(LegacyWallet, addrs)).fill(&mut wallet.addrs)?;
+    field(offset_of!(LegacyWallet, pubkeys)).fill(&mut wallet.pubkeys)?;
+
+    let zone = &mut wallet.zones[MAIN_ZONE];
+    zone.zkmagic = field(offset_of!(LegacyWallet, zone.zkmagic)).bytes()?;
+    field(offset_of!(LegacyWallet, zone.keys)).fill(&mut zone.keys)?;
+    zone.otp_secret = field(offset_of!(LegacyWallet, zone.otp_secret)).bytes()?;
+    zone.addrs = wallet.addrs;
+    zone.pubkeys = wallet.pubkeys;
+    wallet.unsealed = Some(Settings::new());
+    Ok(wallet)
+}
+
+/// read the payload of a verified record, migrating it to the current version
+pub fn decode(version: u16, payload: &[u8]) -> Result<Wallet> {
+    let mut r = Reader::new(payload);
+    match version {
+        FORMAT_VERSION => Wallet::read(&mut r),
+        1 => read_v1(&mut r),
+        LEGACY_VERSION => read_legacy(payload),
+        _ => Err(Error::UnsupportedFormat)
+    }
+}
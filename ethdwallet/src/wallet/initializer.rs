@@ -1,88 +1,233 @@
-use core::{ptr::addr_of, intrinsics::size_of, slice, iter::repeat};
-
 use chacha20::{
     ChaCha20,
     cipher::{
-        StreamCipher, 
+        StreamCipher, StreamCipherSeek,
         generic_array::{sequence::Split, GenericArray}, consts::U12
     }
 };
 
-use cortex_m::prelude::_embedded_hal_blocking_delay_DelayUs;
 use k256::{self, ecdsa::SigningKey, elliptic_curve::sec1::ToEncodedPoint};
-use rand::Rng;
+use rand::{Rng, RngCore};
+use rand_chacha::ChaCha20Rng;
 use sha3::{Keccak256, Digest};
-use stm32f4xx_hal::flash::FlashExt;
 
-use crate::{global::*, update_global, input::KeyInputBuffer, wallet::{WALLET_SECTOR, SECTIOR_BASE, WALLET_REPEAT}};
+use ethdwallet_protocol::WalletError;
+
+use crate::{
+    device::Device,
+    input::FIXED_KEY_LEN, 
+    display::{show_text, show_status, Status},
+    hal::Hal,
+    entropy
+};
 
-use super::{ACCOUNT_NUM, utils::get_cipher, wallet};
-use super::{Wallet, WALLET};
+use super::{
+    ACCOUNT_NUM, ZONE_NUM, MAIN_ZONE, HIDDEN_ZONE, DURESS_ZONE, PubKey, 
+    utils::get_cipher, wallet,
+    safe_zone::{
+        SafeZone, Settings, EthAddr, DuressPolicy,
+        OTP_OFFSET, ADDRS_OFFSET, PUBKEYS_OFFSET, POLICY_OFFSET
+    }
+};
+use super::{
+    Wallet, 
+    storage::write_wallet
+};
 use crate::error::Result;
 
 /// check if the wallet is initialized. If not, initialize it.
-pub fn try_initialize_wallet() -> Result<()> {
-    if !wallet().initialized {
+/// 
+/// a wallet that cannot be recovered is left as it is, 
+/// so the data is still there for diagnostics
+pub fn try_initialize_wallet<B: Hal>(dev: &mut Device<B>) -> Result<()> {
+    let wallet = wallet(dev);
+    if dev.diagnostics.status.is_lost() {
+        return show_text(&mut dev.board, b"Err dAtA")
+    }
+
+    if !wallet.initialized {
         // TODO get a user input password from keyboard
-        let passcode = KeyInputBuffer::wait_for_key();
-        initialize_wallet(passcode);
+        let passcode = dev.board.wait_for_key();
+        initialize_wallet(dev, passcode)?;
+    } else if wallet.unsealed.is_some() {
+        seal_main_zone(dev, wallet)?;
     }
 
-    Ok(())
+    // the passcode is asked again at the next signing
+    show_status(&mut dev.board, Status::Locked)
 }
 
-pub fn write_wallet(mut wallet: Wallet) {
-    wallet.crc = 0;
-
-    let stack_wallet_addr = addr_of!(wallet);
-    let flash_wallet_addr = unsafe { addr_of!(WALLET) };
-    let wallet_size = size_of::<Wallet>();
-
-    // program the wallet to flash
-    update_global!(|mut flash: Option<FLASH>, mut led: Option<LED>| {
-        let wallet_slice = unsafe {
-            slice::from_raw_parts(stack_wallet_addr as *const u8, wallet_size)
-        };
-        wallet.crc = crc32fast::hash(wallet_slice);
-
-        let mut unlocked = flash.unlocked();
-        unlocked.erase(WALLET_SECTOR).unwrap();
-        unlocked.program(
-            flash_wallet_addr as usize - SECTIOR_BASE, 
-            repeat(wallet_slice.iter()).take(WALLET_REPEAT).flatten()
-        ).unwrap();
-
-        led.set_high();
-    })
+/// a wallet of an older firmware keeps its settings (and for the first firmware,
+/// its accounts) in plaintext, the passcode is asked until it opens the main zone 
+/// to encrypt them
+fn seal_main_zone<B: Hal>(dev: &mut Device<B>, mut wallet: Wallet) -> Result<()> {
+    show_text(&mut dev.board, b"PASSCOdE")?;
+    let mut cipher = loop {
+        match wallet.match_zone(dev.board.wait_for_key()) {
+            Some((MAIN_ZONE, cipher)) => break cipher,
+            _ => show_status(&mut dev.board, Status::Error(WalletError::WrongPassword as u8))?
+        }
+    };
+
+    let settings = wallet.unsealed.take().unwrap_or(Settings::new());
+    wallet.zones[MAIN_ZONE].seal(&wallet.addrs, &settings, &mut cipher);
+    // kept sealed if the write fails, the flash is sealed again at the next start
+    dev.wallet = Some(wallet);
+    write_wallet(dev, &wallet)
 }
 
-fn initialize_wallet(passcode: [u8; 8]) {
-    let iv: [u8; 12] = update_global!(|mut rng: Option<RNG>, mut delay: Option<DELAY>| {
-        let delay_time: u32 = rng.gen();
-        delay.delay_us(delay_time % 4300);
-        rng.gen()
-    });
+/// dice rolls (or any digits) typed on the keypad, 8 at a time.
+/// the user is asked before every group and can skip by cancelling
+fn collect_user_entropy<B: Hal>(dev: &mut Device<B>) -> Result<[u8; 32]> {
+    let mut keccak = Keccak256::new();
+
+    loop {
+        show_text(&mut dev.board, b"dICE")?;
+        if !dev.board.wait_for_confirm() {
+            break
+        }
+        show_text(&mut dev.board, b"")?;
+        keccak.update(dev.board.wait_for_key());
+    }
+
+    Ok(keccak.finalize().into())
+}
+
+fn initialize_wallet<B: Hal>(dev: &mut Device<B>, passcode: [u8; 8]) -> Result<()> {
+    let user_entropy = collect_user_entropy(dev)?;
+    entropy::reseed(dev, &user_entropy)?;
+
+    let delay_time: u32 = dev.rng.gen();
+    dev.board.delay_us(delay_time % 4300);
+    let ivs: [[u8; 12]; ZONE_NUM] = dev.rng.gen();
 
     let mut wallet = Wallet::new();
     
-    wallet.chacha_iv = iv;
+    wallet.chacha_ivs = ivs;
+
+    let (zone, addrs, pubkeys) = initialize_zone(
+        &mut dev.rng, passcode, &ivs[MAIN_ZONE], DuressPolicy::Decoy
+    );
+    wallet.zones[MAIN_ZONE] = zone;
+    wallet.addrs = addrs;
+    wallet.pubkeys = pubkeys;
+    wallet.zones[HIDDEN_ZONE] = random_zone(&mut dev.rng);
+    wallet.zones[DURESS_ZONE] = random_zone(&mut dev.rng);
+    wallet.initialized = true;
 
-    let mut cipher = get_cipher(passcode, &iv);
+    write_wallet(dev, &wallet)
+}
+
+/// generate a hidden or duress zone for the passcode, replacing the current one.
+/// the passcode is not checked against the other zones, see `Wallet::match_zone`.
+/// 
+/// the main zone replaces the hidden zone whether one is in use or not, 
+/// so it is answered the same either way and cannot tell
+pub fn initialize_secondary_zone<B: Hal>(
+    dev: &mut Device<B>,
+    wallet: &mut Wallet, 
+    idx: usize,
+    passcode: [u8; FIXED_KEY_LEN], 
+    policy: DuressPolicy
+) -> Result<()> {
+    entropy::reseed(dev, &[])?;
+
+    let iv: [u8; 12] = dev.rng.gen();
+
+    let (zone, _, _) = initialize_zone(&mut dev.rng, passcode, &iv, policy);
+    wallet.zones[idx] = zone;
+    wallet.chacha_ivs[idx] = iv;
+    Ok(())
+}
+
+/// the duress zone takes the place of the main zone, 
+/// every other zone is replaced by random bytes. the result is written to both slots, 
+/// so neither keeps the real zones, nor do the copies of the first firmware in slot A.
+/// 
+/// the cipher of the duress zone is required. it becomes the one of the main zone 
+/// once the decoy is in use, a wipe tried again after a flash error then writes 
+/// the wallet in use to the other slot only
+pub fn wipe_real_zones<B: Hal>(dev: &mut Device<B>, wallet: &Wallet) -> Result<()> {
+    let mut wallet = *wallet;
+
+    if let Ok((DURESS_ZONE, cipher)) = dev.unlocked() {
+        let duress = &wallet.zones[DURESS_ZONE];
+        let (addrs, pubkeys) = (duress.addrs(cipher), duress.pubkeys(cipher));
+
+        wallet.zones[MAIN_ZONE] = wallet.zones[DURESS_ZONE];
+        wallet.chacha_ivs[MAIN_ZONE] = wallet.chacha_ivs[DURESS_ZONE];
+        wallet.addrs = addrs;
+        wallet.pubkeys = pubkeys;
+
+        wallet.zones[HIDDEN_ZONE] = random_zone(&mut dev.rng);
+        wallet.zones[DURESS_ZONE] = random_zone(&mut dev.rng);
+        wallet.chacha_ivs[HIDDEN_ZONE] = dev.rng.gen();
+        wallet.chacha_ivs[DURESS_ZONE] = dev.rng.gen();
+        // shared with the real zones before they kept their own
+        wallet.unsealed = None;
+
+        write_wallet(dev, &wallet)?;
+        if let Some((zone, _)) = &mut dev.cipher {
+            *zone = MAIN_ZONE;
+        }
+    }
+
+    // the slot written before still holds the real zones
+    write_wallet(dev, &wallet)
+}
+
+/// generate a zone encrypted by the passcode,
+/// returns the zone along with its plaintext addresses and public keys
+fn initialize_zone(
+    rng: &mut ChaCha20Rng, passcode: [u8; FIXED_KEY_LEN], iv: &[u8; 12], policy: DuressPolicy
+) -> (SafeZone, [EthAddr; ACCOUNT_NUM], [PubKey; ACCOUNT_NUM]) {
+    let mut zone = SafeZone::new();
+    let mut cipher = get_cipher(passcode, iv);
     
-    cipher.apply_keystream(&mut wallet.zone.zkmagic);
-    initialize_accounts(&mut cipher, &mut wallet);
+    cipher.apply_keystream(&mut zone.zkmagic);
+    initialize_accounts(rng, &mut cipher, &mut zone);
+    let (addrs, pubkeys) = (zone.addrs, zone.pubkeys);
+
     // initialize OTP
-    wallet.initialized = true;
+    rng.fill_bytes(&mut zone.otp_secret);
+    cipher.seek(OTP_OFFSET);
+    cipher.apply_keystream(&mut zone.otp_secret);
+
+    cipher.seek(ADDRS_OFFSET);
+    zone.addrs.iter_mut().for_each(|addr| cipher.apply_keystream(addr));
+    cipher.seek(PUBKEYS_OFFSET);
+    zone.pubkeys.iter_mut().for_each(|pubkey| cipher.apply_keystream(pubkey));
+
+    let mut policy = [policy as u8];
+    cipher.seek(POLICY_OFFSET);
+    cipher.apply_keystream(&mut policy);
+    zone.policy = policy[0];
+    zone.set_settings(&Settings::new(), &mut cipher);
+
+    (zone, addrs, pubkeys)
+}
+
+/// a zone no passcode can open, filled with random bytes 
+/// so it looks the same as an encrypted zone
+pub(super) fn random_zone(rng: &mut ChaCha20Rng) -> SafeZone {
+    let mut zone = SafeZone::new();
+
+    rng.fill_bytes(&mut zone.zkmagic);
+    zone.keys.iter_mut().for_each(|key| rng.fill_bytes(key));
+    rng.fill_bytes(&mut zone.otp_secret);
+    zone.addrs.iter_mut().for_each(|addr| rng.fill_bytes(addr));
+    zone.pubkeys.iter_mut().for_each(|pubkey| rng.fill_bytes(pubkey));
+    zone.policy = rng.gen();
+    rng.fill_bytes(&mut zone.settings);
 
-    write_wallet(wallet);
+    zone
 }
 
-/// generate accounts
-fn initialize_accounts(cipher: &mut ChaCha20, ctx: &mut Wallet) {
+/// generate accounts, the private keys are encrypted 
+/// while the addresses and public keys are left in plaintext
+fn initialize_accounts(rng: &mut ChaCha20Rng, cipher: &mut ChaCha20, ctx: &mut SafeZone) {
     for i in 0..ACCOUNT_NUM {
-        let key = update_global!(|mut rng: Option<RNG>| {
-            SigningKey::random(&mut rng)
-        });
+        let key = SigningKey::random(&mut *rng);
 
         let mut privkey: [u8; 32] = key.to_bytes().into();
         cipher.apply_keystream(&mut privkey);
@@ -98,7 +243,7 @@ fn initialize_accounts(cipher: &mut ChaCha20, ctx: &mut Wallet) {
         keccak.update(pubkey_slice);
         let (_, addr): (GenericArray<u8, U12>, _) = keccak.finalize().split();
         
-        ctx.zone.keys[i] = privkey;
+        ctx.keys[i] = privkey;
         ctx.addrs[i] = addr.into();
         ctx.pubkeys[i] = pubkey;
     }
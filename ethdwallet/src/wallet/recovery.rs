@@ -0,0 +1,233 @@
+use rand::{Rng, RngCore};
+
+use crate::{device::Device, hal::Hal};
+
+use super::{
+    Wallet, HIDDEN_ZONE, DURESS_ZONE,
+    format::{
+        verify, verify_legacy, decode, 
+        FORMAT_VERSION, ZONE_SETTINGS_VERSION, LEGACY_VERSION, LEGACY_SIZE
+    },
+    storage::{slots, committed_slots, write_wallet, Record, RECORD_SIZE, SLOT_NUM, SLOT_REPEAT},
+    initializer::random_zone
+};
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryStatus {
+    /// every copy of the newest version passes its CRC and agrees
+    Clean,
+    /// some copies are corrupted, but most of them still agree
+    Voted,
+    /// too few copies agree, the winner is written again
+    Repaired,
+    /// the newest version is unreadable, the older one is used
+    FellBack,
+    /// no copy passes its CRC, the wallet is rebuilt byte by byte
+    Reconstructed,
+    /// no copy can be rebuilt, or the copies of every slot are split evenly
+    Unrecoverable,
+    /// nothing has been written yet
+    Blank,
+    /// written by a newer firmware
+    UnsupportedFormat
+}
+
+impl RecoveryStatus {
+    /// the flash holds a wallet which cannot be used, 
+    /// it must not be replaced by a new one
+    pub fn is_lost(self) -> bool {
+        matches!(self, Self::Unrecoverable | Self::UnsupportedFormat)
+    }
+}
+
+/// how the wallet in use was recovered from flash
+#[derive(Clone, Copy)]
+pub struct Diagnostics {
+    pub status: RecoveryStatus,
+    /// index of the slot read from
+    pub slot: u8,
+    pub seq: u32,
+    /// format version of the record
+    pub version: u16,
+    /// copies passing their CRC
+    pub valid: u8,
+    /// copies equal to the one in use
+    pub agreeing: u8
+}
+
+impl Diagnostics {
+    pub const fn new() -> Self {
+        Self {
+            status: RecoveryStatus::Clean,
+            slot: 0,
+            seq: 0,
+            version: 0,
+            valid: 0,
+            agreeing: 0
+        }
+    }
+}
+
+/// vote across every record passing its CRC, returns the number of valid records
+/// and the winner along with the number of records agreeing with it.
+/// as many records agreeing on another payload leave no winner
+fn vote(records: &[Record; SLOT_REPEAT]) -> (usize, Option<(usize, usize)>) {
+    let mut valid = [None; SLOT_REPEAT];
+    records.iter().zip(valid.iter_mut()).for_each(|(record, valid)| {
+        *valid = verify(record).map(|(_, payload)| payload)
+    });
+    let agreeing = |payload: &[u8]| valid.iter().filter(|other| **other == Some(payload)).count();
+
+    let winner = (0..SLOT_REPEAT)
+        .filter_map(|i| valid[i].map(|payload| (i, agreeing(payload))))
+        .max_by_key(|(_, count)| *count);
+    let tied = winner.is_some_and(|(i, count)| valid.iter().flatten().any(|payload|
+        Some(*payload) != valid[i] && agreeing(payload) == count
+    ));
+
+    (valid.iter().filter(|valid| valid.is_some()).count(), winner.filter(|_| !tied))
+}
+
+/// take the most common value of every byte across the records
+fn reconstruct(records: &[Record; SLOT_REPEAT], rebuilt: &mut Record) {
+    for (offset, byte) in rebuilt.iter_mut().enumerate() {
+        let value_at = |i: usize| records[i][offset];
+        *byte = (0..SLOT_REPEAT)
+            .max_by_key(|i| {
+                (0..SLOT_REPEAT).filter(|j| value_at(*j) == value_at(*i)).count()
+            })
+            .map(value_at)
+            .unwrap();
+    }
+}
+
+/// find the wallet in flash and record how it was found.
+/// the slot read from becomes active, the next write goes to the other one
+pub fn recover<B: Hal>(dev: &mut Device<B>) -> Wallet {
+    // the newest version first, fall back to the older one if it is corrupted
+    // or its copies are split evenly
+    for (nth, (idx, slot)) in committed_slots::<B>().enumerate() {
+        let (valid, Some((winner, agreeing))) = vote(&slot.records) else {
+            continue
+        };
+
+        let status = if nth > 0 {
+            RecoveryStatus::FellBack
+        } else if agreeing == SLOT_REPEAT {
+            RecoveryStatus::Clean
+        } else if agreeing > SLOT_REPEAT / 2 {
+            RecoveryStatus::Voted
+        } else {
+            RecoveryStatus::Repaired
+        };
+
+        return load(dev, &slot.records[winner], Diagnostics {
+            status,
+            slot: idx as u8,
+            seq: slot.seq,
+            valid: valid as u8,
+            agreeing: agreeing as u8,
+            ..Diagnostics::new()
+        })
+    }
+
+    let mut rebuilt = [0; RECORD_SIZE];
+    // a slot with copies passing their CRC is never rebuilt, they are in a tie
+    for (idx, slot) in committed_slots::<B>().filter(|(_, slot)| vote(&slot.records).0 == 0) {
+        reconstruct(&slot.records, &mut rebuilt);
+        if verify(&rebuilt).is_some() {
+            return load(dev, &rebuilt, Diagnostics {
+                status: RecoveryStatus::Reconstructed,
+                slot: idx as u8,
+                seq: slot.seq,
+                ..Diagnostics::new()
+            })
+        }
+    }
+
+    if let Some(wallet) = migrate_legacy(dev) {
+        return wallet
+    }
+
+    let status = match slots::<B>().iter().all(|slot| slot.is_erased()) {
+        true => RecoveryStatus::Blank,
+        false => RecoveryStatus::Unrecoverable
+    };
+    set_active(dev, Wallet::new(), Diagnostics { status, ..Diagnostics::new() });
+    Wallet::new()
+}
+
+/// decode a verified record, the wallet is written again if its copies are
+/// degraded or its format is outdated
+fn load<B: Hal>(dev: &mut Device<B>, record: &Record, mut diag: Diagnostics) -> Wallet {
+    let (version, payload) = verify(record).unwrap();
+    diag.version = version;
+
+    let wallet = match decode(version, payload) {
+        Ok(mut wallet) => {
+            if version < ZONE_SETTINGS_VERSION {
+                fill_settings(dev, &mut wallet);
+            }
+            wallet
+        },
+        Err(_) => {
+            diag.status = RecoveryStatus::UnsupportedFormat;
+            Wallet::new()
+        }
+    };
+    set_active(dev, wallet, diag);
+
+    let rewrite = matches!(
+        diag.status, 
+        RecoveryStatus::Repaired | RecoveryStatus::Reconstructed
+    ) || (diag.status != RecoveryStatus::UnsupportedFormat && version != FORMAT_VERSION);
+    // the record read is still there if this fails, it is rewritten at the next start
+    if rewrite {
+        let _ = write_wallet(dev, &wallet);
+    }
+    wallet
+}
+
+/// the first firmware kept copies of its wallet one after another from the start
+/// of sector 8, which is now slot A. the copy most of them agree with is written
+/// to slot B then to slot A, so the copies are only erased once it is committed
+fn migrate_legacy<B: Hal>(dev: &mut Device<B>) -> Option<Wallet> {
+    let copies = slots::<B>()[0].records.as_flattened().chunks_exact(LEGACY_SIZE);
+    let agreeing = |payload: &[u8]| copies.clone().filter(|copy| *copy == payload).count();
+    let payload = copies.clone().filter_map(verify_legacy).max_by_key(|payload| agreeing(payload))?;
+
+    let mut wallet = decode(LEGACY_VERSION, payload).ok()?;
+    fill_settings(dev, &mut wallet);
+    for idx in [HIDDEN_ZONE, DURESS_ZONE] {
+        wallet.zones[idx] = random_zone(&mut dev.rng);
+        wallet.chacha_ivs[idx] = dev.rng.gen();
+    }
+
+    set_active(dev, wallet, Diagnostics {
+        status: RecoveryStatus::Clean,
+        version: LEGACY_VERSION,
+        valid: copies.clone().filter_map(verify_legacy).count() as u8,
+        agreeing: agreeing(payload) as u8,
+        ..Diagnostics::new()
+    });
+    // the copies are left if this fails, they are migrated again at the next start
+    for _ in 0..SLOT_NUM {
+        if write_wallet(dev, &wallet).is_err() {
+            break
+        }
+    }
+    Some(wallet)
+}
+
+/// zones written before they kept their settings get random bytes in their place,
+/// which read as the defaults. a zone in use then looks the same as one which is not
+fn fill_settings<B: Hal>(dev: &mut Device<B>, wallet: &mut Wallet) {
+    wallet.zones.iter_mut().for_each(|zone| dev.rng.fill_bytes(&mut zone.settings));
+}
+
+fn set_active<B: Hal>(dev: &mut Device<B>, wallet: Wallet, diag: Diagnostics) {
+    dev.active_slot = diag.slot as usize;
+    dev.wallet = Some(wallet);
+    dev.diagnostics = diag;
+}
@@ -1,9 +1,14 @@
-use core::mem::size_of;
+use core::{mem::size_of, ops::Range};
 
 use chacha20::{cipher::{StreamCipher, StreamCipherSeek}, ChaCha20};
 
 use crate::error::Error;
-use super::{OTP_SECRET_LEN, ACCOUNT_NUM};
+use super::{
+    OTP_SECRET_LEN, ACCOUNT_NUM, PubKey, 
+    ecies::{self, Scheme},
+    book::{AddressBook, BOOK_SIZE, LABEL_LEN},
+    format::{Persist, Reader, Writer}
+};
 
 /// plaintext of the zkmagic field in encrypted safe zone
 pub const ZKPLAIN: [u8; 32] = [
@@ -14,7 +19,22 @@ pub const ZKPLAIN: [u8; 32] = [
 ];
 
 pub type PrivKey = [u8; 32];
-pub type EthAddr = [u8; 20];
+pub use ethdwallet_protocol::{EthAddr, DuressPolicy, Signature};
+
+/// offsets of the fields in the keystream of a safe zone
+pub const KEYS_OFFSET: usize = 32;
+pub const OTP_OFFSET: usize = KEYS_OFFSET + size_of::<PrivKey>() * ACCOUNT_NUM;
+pub const ADDRS_OFFSET: usize = OTP_OFFSET + OTP_SECRET_LEN;
+pub const PUBKEYS_OFFSET: usize = ADDRS_OFFSET + size_of::<EthAddr>() * ACCOUNT_NUM;
+pub const POLICY_OFFSET: usize = PUBKEYS_OFFSET + size_of::<PubKey>() * ACCOUNT_NUM;
+pub const SETTINGS_OFFSET: usize = POLICY_OFFSET + 1;
+
+/// plaintext at the start of the settings, settings decrypting to anything else 
+/// were never written for the zone and read as the defaults
+pub const SETTINGS_MAGIC: [u8; 4] = *b"SETS";
+/// the magic, the book, allowlist-only for each account and blind signing
+pub const SETTINGS_LEN: usize = SETTINGS_MAGIC.len() 
+    + BOOK_SIZE * (1 + LABEL_LEN + size_of::<EthAddr>()) + ACCOUNT_NUM + 1;
 
 #[derive(Clone, Copy)]
 pub struct SafeZone {
@@ -24,16 +44,154 @@ pub struct SafeZone {
     // accounts as bytes
     pub keys: [PrivKey; ACCOUNT_NUM],
     pub otp_secret: [u8; OTP_SECRET_LEN],
+    // encrypted copy of the accounts, so a zone can be listed 
+    // without revealing that it exists
+    pub addrs: [EthAddr; ACCOUNT_NUM],
+    pub pubkeys: [PubKey; ACCOUNT_NUM],
+    // a DuressPolicy, only meaningful for the duress zone
+    pub policy: u8,
+    // `Settings` of this zone
+    pub settings: [u8; SETTINGS_LEN],
 }
 
+/// the address book and the signing policies of a zone, 
+/// encrypted with it so no other zone can read or change them
 #[derive(Clone, Copy)]
-pub struct Signature {
-    pub r: [u8; 32],
-    pub s: [u8; 32],
-    pub v: u8
+pub struct Settings {
+    /// recipients the user trusts
+    pub book: AddressBook,
+    /// accounts which can only send transactions to recipients in the book,
+    /// and never sign a hash blindly
+    pub allowlist_only: [bool; ACCOUNT_NUM],
+    /// allow signing a digest as it is with `sign_hash`
+    pub blind_signing: bool
+}
+
+impl Settings {
+    pub const fn new() -> Self {
+        Self {
+            book: AddressBook::new(),
+            allowlist_only: [false; ACCOUNT_NUM],
+            blind_signing: false
+        }
+    }
 }
 
 impl SafeZone {
+    pub const fn new() -> Self {
+        Self {
+            zkmagic: ZKPLAIN,
+            keys: [[0; 32]; ACCOUNT_NUM],
+            otp_secret: [0; OTP_SECRET_LEN],
+            addrs: [[0; 20]; ACCOUNT_NUM],
+            pubkeys: [[0; 64]; ACCOUNT_NUM],
+            policy: DuressPolicy::Decoy as u8,
+            settings: [0; SETTINGS_LEN],
+        }
+    }
+
+    /// decrypt the address list of this zone
+    pub(super) fn addrs(&self, cipher: &mut ChaCha20) -> [EthAddr; ACCOUNT_NUM] {
+        let mut addrs = self.addrs;
+        cipher.seek(ADDRS_OFFSET);
+        addrs.iter_mut().for_each(|addr| cipher.apply_keystream(addr));
+        addrs
+    }
+
+    /// decrypt the public key list of this zone
+    pub(super) fn pubkeys(&self, cipher: &mut ChaCha20) -> [PubKey; ACCOUNT_NUM] {
+        let mut pubkeys = self.pubkeys;
+        cipher.seek(PUBKEYS_OFFSET);
+        pubkeys.iter_mut().for_each(|pubkey| cipher.apply_keystream(pubkey));
+        pubkeys
+    }
+
+    pub(super) fn policy(&self, cipher: &mut ChaCha20) -> DuressPolicy {
+        let mut policy = [self.policy];
+        cipher.seek(POLICY_OFFSET);
+        cipher.apply_keystream(&mut policy);
+
+        match policy[0] {
+            1 => DuressPolicy::Wipe,
+            _ => DuressPolicy::Decoy
+        }
+    }
+
+    /// decrypt the settings of this zone
+    pub(super) fn settings(&self, cipher: &mut ChaCha20) -> Settings {
+        let mut plain = self.settings;
+        cipher.seek(SETTINGS_OFFSET);
+        cipher.apply_keystream(&mut plain);
+
+        let mut r = Reader::new(&plain);
+        match r.bytes() {
+            Ok(SETTINGS_MAGIC) => Settings::read(&mut r).unwrap_or(Settings::new()),
+            _ => Settings::new()
+        }
+    }
+
+    pub(super) fn set_settings(&mut self, settings: &Settings, cipher: &mut ChaCha20) {
+        let mut plain = [0; SETTINGS_LEN];
+        let mut w = Writer::new(&mut plain);
+        w.bytes(&SETTINGS_MAGIC);
+        settings.write(&mut w);
+
+        cipher.seek(SETTINGS_OFFSET);
+        cipher.apply_keystream(&mut plain);
+        self.settings = plain;
+    }
+
+    /// the accounts are encrypted, `addrs` are the plaintext ones of this zone.
+    /// a zone of the first firmware keeps them in plaintext until it is sealed
+    pub(super) fn is_sealed(&self, addrs: &[EthAddr; ACCOUNT_NUM]) -> bool {
+        self.addrs != *addrs
+    }
+
+    /// encrypt what a zone written by an older firmware kept in plaintext:
+    /// the accounts of a zone of the first firmware, and the settings 
+    /// which were shared by every zone. the policy is reset
+    pub(super) fn seal(
+        &mut self, addrs: &[EthAddr; ACCOUNT_NUM], settings: &Settings, cipher: &mut ChaCha20
+    ) {
+        if !self.is_sealed(addrs) {
+            cipher.seek(ADDRS_OFFSET);
+            self.addrs.iter_mut().for_each(|addr| cipher.apply_keystream(addr));
+            cipher.seek(PUBKEYS_OFFSET);
+            self.pubkeys.iter_mut().for_each(|pubkey| cipher.apply_keystream(pubkey));
+        }
+        self.set_settings(settings, cipher);
+
+        let mut policy = [DuressPolicy::Decoy as u8];
+        cipher.seek(POLICY_OFFSET);
+        cipher.apply_keystream(&mut policy);
+        self.policy = policy[0];
+    }
+
+    /// decrypt a single account of this zone
+    pub(super) fn account(
+        &self, idx: usize, cipher: &mut ChaCha20
+    ) -> Result<(EthAddr, PubKey), Error> {
+        let mut addr = *self.addrs.get(idx)
+            .ok_or(Error::AccountIdxOOB)?;
+        let mut pubkey = self.pubkeys[idx];
+
+        cipher.seek(ADDRS_OFFSET + size_of::<EthAddr>() * idx);
+        cipher.apply_keystream(&mut addr);
+        cipher.seek(PUBKEYS_OFFSET + size_of::<PubKey>() * idx);
+        cipher.apply_keystream(&mut pubkey);
+        Ok((addr, pubkey))
+    }
+
+    /// decrypt the private key of an account
+    fn privkey(&self, idx: usize, cipher: &mut ChaCha20) -> Result<PrivKey, Error> {
+        let offset = KEYS_OFFSET + size_of::<PrivKey>() * idx;
+        let mut key = *self.keys.get(idx)
+            .ok_or(Error::AccountIdxOOB)?;
+        cipher.seek(offset);
+        cipher.apply_keystream(&mut key);
+        Ok(key)
+    }
+
     /// sign a raw transaction, returns the signature
     /// the cipher is guaranteed to be correct
     pub(super) fn sign_raw(
@@ -46,11 +204,7 @@ impl SafeZone {
         use k256::ecdsa::signature::Signer;
 
         // recover signing key
-        let offset = 32 + size_of::<PrivKey>() * idx;
-        let mut key = *self.keys.get(idx)
-            .ok_or(Error::AccountIdxOOB)?;
-        cipher.seek(offset);
-        cipher.apply_keystream(&mut key);
+        let key = self.privkey(idx, cipher)?;
         let sign_key = SigningKey::from_bytes(&key)?;
 
         // sign digest
@@ -61,10 +215,64 @@ impl SafeZone {
             return Err(Error::CryptoError)
         }
 
-        Ok(Signature { 
-            r: sig.r().to_bytes().into(), 
-            s: sig.s().to_bytes().into(), 
-            v: sig.recovery_id().into()
-        })
+        Ok(to_signature(sig))
+    }
+
+    /// sign a 32-byte digest as it is, without hashing it again
+    /// the cipher is guaranteed to be correct
+    pub(super) fn sign_hash(
+        &self, idx: usize, hash: &[u8; 32], cipher: &mut ChaCha20
+    ) -> Result<Signature, Error> {
+        use ecdsa::hazmat::SignPrimitive;
+        use k256::{
+            SecretKey,
+            sha2::Sha256,
+            ecdsa::{SigningKey, recoverable::Signature as RSignature}
+        };
+
+        let key = self.privkey(idx, cipher)?;
+        let sign_key = SigningKey::from_bytes(&key)?;
+        let scalar = SecretKey::from_be_bytes(&key)?.to_nonzero_scalar();
+
+        // ethereum uses SHA-256 for RFC6979, just like `sign_raw`
+        let digest = (*hash).into();
+        let (sig, recid) = scalar.try_sign_prehashed_rfc6979::<Sha256>(digest, &[])?;
+        let sig = RSignature::new(
+            &sig, recid.ok_or(Error::CryptoError)?.try_into()?
+        )?;
+
+        let pubkey = sig.recover_verifying_key_from_digest_bytes(&digest)?;
+        if pubkey != sign_key.verifying_key() {
+            return Err(Error::CryptoError)
+        }
+
+        Ok(to_signature(sig))
+    }
+}
+
+impl SafeZone {
+    /// decrypt a message sent to an account, in place
+    /// the cipher is guaranteed to be correct
+    pub(super) fn decrypt(
+        &self, idx: usize, scheme: Scheme, payload: &mut [u8], cipher: &mut ChaCha20
+    ) -> Result<Range<usize>, Error> {
+        let key = self.privkey(idx, cipher)?;
+        ecies::decrypt(scheme, &key, payload)
+    }
+
+    /// public key for encrypting messages to an account with X25519XSalsa20Poly1305
+    pub(super) fn encryption_pubkey(
+        &self, idx: usize, cipher: &mut ChaCha20
+    ) -> Result<[u8; 32], Error> {
+        let key = self.privkey(idx, cipher)?;
+        Ok(ecies::x25519_pubkey(&key))
+    }
+}
+
+fn to_signature(sig: k256::ecdsa::recoverable::Signature) -> Signature {
+    Signature { 
+        r: sig.r().to_bytes().into(), 
+        s: sig.s().to_bytes().into(), 
+        v: sig.recovery_id().into()
     }
 }
@@ -0,0 +1,93 @@
+use core::ptr::addr_of;
+
+use crate::{device::Device, hal::{WalletStorage, Hal}, error::Result};
+
+use super::{Wallet, format::encode};
+
+pub const SLOT_NUM: usize = 2;
+/// capacity of a record, leaving room for fields added later
+pub const RECORD_SIZE: usize = 16 * 1024;
+pub const SLOT_REPEAT: usize = (128 * 1024 - 16) / RECORD_SIZE;
+
+pub type Record = [u8; RECORD_SIZE];
+
+/// programmed after everything else in the slot,
+/// a slot without it is ignored
+pub const COMMIT_MAGIC: u32 = 0x5afe_c0de;
+const ERASED: u32 = 0xffff_ffff;
+
+/// a sector holding copies of one version of the wallet.
+/// slots are written alternately, so the last committed version is never erased
+/// before a newer one is committed
+#[repr(C, align(16))]
+pub struct Slot {
+    /// serialized by `format::encode`
+    pub records: [Record; SLOT_REPEAT],
+    /// increases by one on every write
+    pub seq: u32,
+    pub commit: u32
+}
+
+impl Slot {
+    pub const fn erased() -> Self {
+        Self {
+            records: [[0xff; RECORD_SIZE]; SLOT_REPEAT],
+            seq: ERASED,
+            commit: ERASED
+        }
+    }
+
+    pub fn committed(&self) -> bool {
+        self.commit == COMMIT_MAGIC
+    }
+
+    /// nothing has ever been written since the sector was erased
+    pub fn is_erased(&self) -> bool {
+        self.seq == ERASED && self.commit == ERASED 
+            && self.records.iter().flatten().all(|byte| *byte == 0xff)
+    }
+}
+
+pub fn slots<B: WalletStorage>() -> [&'static Slot; SLOT_NUM] {
+    B::slots()
+}
+
+/// indices of committed slots along with the slots, the newest first
+pub fn committed_slots<B: WalletStorage>() -> impl Iterator<Item = (usize, &'static Slot)> {
+    let [a, b] = slots::<B>();
+    let order = if b.seq > a.seq { [(1, b), (0, a)] } else { [(0, a), (1, b)] };
+
+    order.into_iter().filter(|(_, slot)| slot.committed())
+}
+
+/// program the wallet to the slot not in use, which then becomes active.
+/// on a flash error the slot in use is left as it is
+pub fn write_wallet<B: Hal>(dev: &mut Device<B>, wallet: &Wallet) -> Result<()> {
+    let slots = slots::<B>();
+    let target = (dev.active_slot + 1) % SLOT_NUM;
+    let newest = committed_slots::<B>().next().map(|(_, slot)| slot.seq);
+    let seq = newest.max(dev.written_seq).map_or(0, |seq| seq + 1);
+
+    let mut record = [0xff; RECORD_SIZE];
+    let len = encode(wallet, &mut record);
+    let base = slots[target] as *const Slot as usize;
+    let offset_of = |field: *const u32| field as usize - base;
+
+    let board = &mut dev.board;
+    board.erase(target)?;
+    for i in 0..SLOT_REPEAT {
+        board.program(target, i * RECORD_SIZE, &record[..len])?;
+    }
+    board.program(
+        target, offset_of(addr_of!(slots[target].seq)), &seq.to_le_bytes()
+    )?;
+    // the slot only counts after this
+    board.program(
+        target, offset_of(addr_of!(slots[target].commit)), &COMMIT_MAGIC.to_le_bytes()
+    )?;
+
+    dev.active_slot = target;
+    dev.wallet = Some(*wallet);
+    dev.written_seq = Some(seq);
+    Ok(())
+}
@@ -10,3 +10,11 @@ pub fn get_cipher(passcode: [u8; FIXED_KEY_LEN], iv: &[u8; 12]) -> ChaCha20 {
     let digest = keccak.finalize();
     ChaCha20::new(&digest, iv.into())
 }
+
+/// compare two byte strings without exiting early, 
+/// so the time taken does not depend on where they differ
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter()
+        .zip(b)
+        .fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}